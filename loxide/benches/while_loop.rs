@@ -0,0 +1,126 @@
+#![feature(test)]
+
+extern crate test;
+
+use loxide::{interpret, VM};
+use test::Bencher;
+
+/// A 200 item list literal, pushing it around should cost the same as a number
+fn large_list_src() -> String {
+    let items = (0..200).map(|i| i.to_string()).collect::<Vec<_>>();
+    format!(
+        r#"
+var big = [{}];
+fun pass(list) {{ return list; }}
+var i = 0;
+while (i < 100000) {{
+    var alias = big;
+    pass(alias);
+    i = i + 1;
+}}"#,
+        items.join(", ")
+    )
+}
+
+const SRC: &str = r#"
+var i = 0;
+while (i < 100000) {
+    i = i + 1;
+}"#;
+
+#[bench]
+fn tight_while_loop(b: &mut Bencher) {
+    b.iter(|| {
+        let mut vm = VM::new();
+        interpret(&mut vm, SRC).unwrap();
+    });
+}
+
+const GLOBALS_SRC: &str = r#"
+var a = 0;
+var b = 0;
+var c = 0;
+var i = 0;
+while (i < 100000) {
+    a = a + i;
+    b = b + a;
+    c = c + b - a;
+    i = i + 1;
+}"#;
+
+#[bench]
+fn global_heavy_while_loop(b: &mut Bencher) {
+    b.iter(|| {
+        let mut vm = VM::new();
+        interpret(&mut vm, GLOBALS_SRC).unwrap();
+    });
+}
+
+const METHOD_SRC: &str = r#"
+class Counter {
+    init() { this.count = 0; }
+    bump(by) { this.count = this.count + by; }
+}
+var c = Counter();
+var i = 0;
+while (i < 100000) {
+    c.bump(1);
+    i = i + 1;
+}"#;
+
+#[bench]
+fn hot_method_call_loop(b: &mut Bencher) {
+    b.iter(|| {
+        let mut vm = VM::new();
+        interpret(&mut vm, METHOD_SRC).unwrap();
+    });
+}
+
+#[bench]
+fn push_large_list_loop(b: &mut Bencher) {
+    let src = large_list_src();
+    b.iter(|| {
+        let mut vm = VM::new();
+        interpret(&mut vm, &src).unwrap();
+    });
+}
+
+/// The same loop over locals, `low` keeps them in slots 1-3 which get the
+/// one byte `GetLocal1`..`GetLocal3` while `high` pushes them past slot 3
+/// onto the generic `GetLocal`
+fn locals_src(padding: &str) -> String {
+    format!(
+        r#"
+fun run() {{
+    {padding}
+    var i = 0;
+    var a = 0;
+    var b = 0;
+    while (i < 100000) {{
+        a = a + i;
+        b = b + a - i;
+        i = i + 1;
+    }}
+    return b;
+}}
+run();"#
+    )
+}
+
+#[bench]
+fn low_slot_locals_loop(b: &mut Bencher) {
+    let src = locals_src("");
+    b.iter(|| {
+        let mut vm = VM::new();
+        interpret(&mut vm, &src).unwrap();
+    });
+}
+
+#[bench]
+fn high_slot_locals_loop(b: &mut Bencher) {
+    let src = locals_src("var p1; var p2; var p3;");
+    b.iter(|| {
+        let mut vm = VM::new();
+        interpret(&mut vm, &src).unwrap();
+    });
+}
@@ -1,102 +1,247 @@
-use std::ops::Deref;
+use std::{fmt::Write, ops::Deref, ptr::null_mut};
 
 use crate::{
     compile::Upvalue,
+    mem::Mem,
+    obj::{ObjFunction, ObjKind},
     value::{Value, ValueArray},
 };
 
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
-pub enum Opcode {
-    Return = 0,
+/// Magic bytes at the start of a serialized chunk
+const MAGIC: &[u8; 4] = b"LOXC";
+/// Bump whenever the serialized layout or the opcode numbering changes
+pub const BYTECODE_VERSION: u8 = 2;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
+
+/// Declares every opcode once: its byte, its canonical clox-style name and
+/// its operand, so decoding, naming and disassembling can't disagree
+macro_rules! opcodes {
+    ($($byte:literal => $op:ident, $name:literal, $operand:ident;)*) => {
+        #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd)]
+        pub enum Opcode {
+            $($op = $byte,)*
+        }
+
+        impl Opcode {
+            #[inline]
+            pub fn from_u8(val: u8) -> Option<Self> {
+                match val {
+                    $($byte => Some(Opcode::$op),)*
+                    _ => None,
+                }
+            }
+
+            /// The canonical clox-style name, e.g. `OP_CONSTANT`
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$op => $name,)*
+                }
+            }
+
+            /// The operand bytes the VM reads after this opcode
+            pub fn operand(self) -> Operand {
+                match self {
+                    $(Opcode::$op => Operand::$operand,)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    0 => Return,        "OP_RETURN",        None;
+    1 => Constant,      "OP_CONSTANT",      Constant;
+    2 => Negate,        "OP_NEGATE",        None;
+    3 => Add,           "OP_ADD",           None;
+    4 => Subtract,      "OP_SUBTRACT",      None;
+    5 => Multiply,      "OP_MULTIPLY",      None;
+    6 => Divide,        "OP_DIVIDE",        None;
+    7 => Nil,           "OP_NIL",           None;
+    8 => True,          "OP_TRUE",          None;
+    9 => False,         "OP_FALSE",         None;
+    10 => Not,          "OP_NOT",           None;
+    11 => Equal,        "OP_EQUAL",         None;
+    12 => Greater,      "OP_GREATER",       None;
+    13 => Less,         "OP_LESS",          None;
+    14 => Print,        "OP_PRINT",         None;
+    15 => Pop,          "OP_POP",           None;
+    16 => DefineGlobal, "OP_DEFINE_GLOBAL", Constant;
+    17 => GetGlobal,    "OP_GET_GLOBAL",    Constant;
+    18 => SetGlobal,    "OP_SET_GLOBAL",    Constant;
+    19 => GetLocal,     "OP_GET_LOCAL",     Byte;
+    20 => SetLocal,     "OP_SET_LOCAL",     Byte;
+    21 => JumpIfFalse,  "OP_JUMP_IF_FALSE", Jump;
+    22 => Jump,         "OP_JUMP",          Jump;
+    23 => Loop,         "OP_LOOP",          Jump;
+    24 => Call,         "OP_CALL",          Byte;
+    25 => Closure,      "OP_CLOSURE",       Closure;
+    26 => GetUpvalue,   "OP_GET_UPVALUE",   Byte;
+    27 => SetUpvalue,   "OP_SET_UPVALUE",   Byte;
+    28 => CloseUpvalue, "OP_CLOSE_UPVALUE", None;
+    29 => Class,        "OP_CLASS",         Constant;
+    30 => GetProperty,  "OP_GET_PROPERTY",  Constant;
+    31 => SetProperty,  "OP_SET_PROPERTY",  Constant;
+    32 => Method,       "OP_METHOD",        Constant;
+    33 => Invoke,       "OP_INVOKE",        Invoke;
+    34 => Inherit,      "OP_INHERIT",       None;
+    35 => GetSuper,     "OP_GET_SUPER",     Constant;
+    36 => SuperInvoke,  "OP_SUPER_INVOKE",  Invoke;
+    37 => ConstantLong, "OP_CONSTANT_LONG", ConstantLong;
+    38 => Modulo,       "OP_MODULO",        None;
+    39 => PopN,         "OP_POP_N",         Byte;
+    40 => Assert,       "OP_ASSERT",        Constant;
+    41 => BuildList,    "OP_BUILD_LIST",    Byte;
+    42 => IndexGet,     "OP_INDEX_GET",     None;
+    43 => IndexSet,     "OP_INDEX_SET",     None;
+    44 => BuildMap,     "OP_BUILD_MAP",     Byte;
+    45 => Dup,          "OP_DUP",           None;
+    46 => BitAnd,       "OP_BIT_AND",       None;
+    47 => BitOr,        "OP_BIT_OR",        None;
+    48 => BitXor,       "OP_BIT_XOR",       None;
+    49 => ShiftLeft,    "OP_SHIFT_LEFT",    None;
+    50 => ShiftRight,   "OP_SHIFT_RIGHT",   None;
+    51 => Power,        "OP_POWER",         None;
+    52 => TailCall,     "OP_TAIL_CALL",     Byte;
+    53 => GetLocal0,    "OP_GET_LOCAL_0",   None;
+    54 => GetLocal1,    "OP_GET_LOCAL_1",   None;
+    55 => GetLocal2,    "OP_GET_LOCAL_2",   None;
+    56 => GetLocal3,    "OP_GET_LOCAL_3",   None;
+    57 => Iter,         "OP_ITER",          None;
+    58 => Len,          "OP_LEN",           None;
+    59 => Unpack,       "OP_UNPACK",        Byte;
+    60 => Write,        "OP_WRITE",         None;
+}
+
+impl std::fmt::Display for Opcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A byte that isn't any opcode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnknownOpcode(pub u8);
+
+impl std::fmt::Display for UnknownOpcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Unknown opcode {}.", self.0)
+    }
+}
+
+impl TryFrom<u8> for Opcode {
+    type Error = UnknownOpcode;
+
+    fn try_from(byte: u8) -> Result<Self, Self::Error> {
+        Self::from_u8(byte).ok_or(UnknownOpcode(byte))
+    }
+}
+
+/// What follows an opcode in the code
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    None,
+    /// A one byte index into the constant pool
     Constant,
-    Negate,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Nil,
-    True,
-    False,
-    Not,
-    Equal,
-    Greater,
-    Less,
-    Print,
-    Pop,
-    DefineGlobal,
-    GetGlobal,
-    SetGlobal,
-    GetLocal,
-    SetLocal,
-    JumpIfFalse,
+    /// A three byte (big endian) index into the constant pool
+    ConstantLong,
+    /// A local slot, upvalue index or count
+    Byte,
+    /// A two byte (big endian) jump distance
     Jump,
-    Loop,
-    Call,
+    /// A constant index for the function, then an `is_local` and index byte
+    /// for each of its upvalues
     Closure,
-    GetUpvalue,
-    SetUpvalue,
-    CloseUpvalue,
-    Class,
-    GetProperty,
-    SetProperty,
-    Method,
+    /// A constant index for the method name and an argument count
     Invoke,
-    Inherit,
-    GetSuper,
-    SuperInvoke,
 }
 
-impl Opcode {
-    #[inline]
-    pub fn from_u8(val: u8) -> Option<Self> {
-        use Opcode::*;
-        match val {
-            0 => Some(Return),
-            1 => Some(Constant),
-            2 => Some(Negate),
-            3 => Some(Add),
-            4 => Some(Subtract),
-            5 => Some(Multiply),
-            6 => Some(Divide),
-            7 => Some(Nil),
-            8 => Some(True),
-            9 => Some(False),
-            10 => Some(Not),
-            11 => Some(Equal),
-            12 => Some(Greater),
-            13 => Some(Less),
-            14 => Some(Print),
-            15 => Some(Pop),
-            16 => Some(DefineGlobal),
-            17 => Some(GetGlobal),
-            18 => Some(SetGlobal),
-            19 => Some(GetLocal),
-            20 => Some(SetLocal),
-            21 => Some(JumpIfFalse),
-            22 => Some(Jump),
-            23 => Some(Loop),
-            24 => Some(Call),
-            25 => Some(Closure),
-            26 => Some(GetUpvalue),
-            27 => Some(SetUpvalue),
-            28 => Some(CloseUpvalue),
-            29 => Some(Class),
-            30 => Some(GetProperty),
-            31 => Some(SetProperty),
-            32 => Some(Method),
-            33 => Some(Invoke),
-            34 => Some(Inherit),
-            35 => Some(GetSuper),
-            36 => Some(SuperInvoke),
-            _ => None,
-        }
+/// An instruction was written with a helper for a different kind of operand
+/// than its opcode takes, nothing was written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OperandMismatch {
+    pub op: Opcode,
+    pub expected: Operand,
+}
+
+impl std::fmt::Display for OperandMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} takes a {:?} operand.", self.op, self.expected)
+    }
+}
+
+/// The largest constant index `Opcode::ConstantLong` can load
+pub const MAX_CONSTANT_INDEX: usize = 0xFF_FFFF;
+
+/// The constant pool has no index left that an instruction could load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyConstants;
+
+impl std::fmt::Display for TooManyConstants {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Too many constants in one chunk.")
     }
 }
 
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: ValueArray,
-    pub lines: Vec<u32>,
+    /// Run-length encoded line numbers, each entry is a line and how many
+    /// consecutive bytes of `code` belong to it
+    pub lines: Vec<LineRun>,
+    /// File names given by `#line` directives, each applies to the code from
+    /// its `start` up to the next one. Empty when the source had none
+    pub files: Vec<FileRun>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct LineRun {
+    pub line: u32,
+    pub count: u32,
+}
+
+/// Looks up lines for offsets that only ever increase, like a listing's,
+/// without walking the runs from the start each time
+struct LineCursor<'a> {
+    runs: std::slice::Iter<'a, LineRun>,
+    line: u32,
+    /// End of the current run
+    end: usize,
+}
+
+impl<'a> LineCursor<'a> {
+    fn new(lines: &'a [LineRun]) -> Self {
+        Self {
+            runs: lines.iter(),
+            line: 0,
+            end: 0,
+        }
+    }
+
+    fn line_at(&mut self, offset: usize) -> u32 {
+        while offset >= self.end {
+            let run = match self.runs.next() {
+                Some(run) => run,
+                None => panic!(
+                    "Offset {offset} is out of bounds of chunk with len {}",
+                    self.end
+                ),
+            };
+            self.line = run.line;
+            self.end += run.count as usize;
+        }
+        self.line
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileRun {
+    pub start: u32,
+    pub name: String,
 }
 
 impl Chunk {
@@ -105,6 +250,7 @@ impl Chunk {
             code: vec![],
             constants: vec![],
             lines: vec![],
+            files: vec![],
         }
     }
 
@@ -116,114 +262,634 @@ impl Chunk {
     }
 
     pub fn iter_debug(&self) -> ChunkIterDebug {
-        ChunkIterDebug(ChunkIter {
-            chunk: self,
-            offset: 0,
-        })
+        ChunkIterDebug(
+            ChunkIter {
+                chunk: self,
+                offset: 0,
+            },
+            LineCursor::new(&self.lines),
+        )
     }
 
     pub fn write(&mut self, op: u8, line: u32) {
         self.code.push(op);
-        self.lines.push(line);
+
+        match self.lines.last_mut() {
+            Some(run) if run.line == line => run.count += 1,
+            _ => self.lines.push(LineRun { line, count: 1 }),
+        }
+    }
+
+    /// Drop the code from `len` onwards along with its line info
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+
+        let mut covered = 0;
+        let mut runs = 0;
+        for run in self.lines.iter_mut() {
+            if covered >= len {
+                break;
+            }
+            run.count = run.count.min((len - covered) as u32);
+            covered += run.count as usize;
+            runs += 1;
+        }
+        self.lines.truncate(runs);
+        self.files.retain(|run| (run.start as usize) < len);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    /// Code written from now on was compiled from the file `name`
+    pub fn set_file(&mut self, name: &str) {
+        let start = self.code.len() as u32;
+        match self.files.last_mut() {
+            Some(run) if run.name == name => (),
+            Some(run) if run.start == start => run.name = name.to_string(),
+            _ => self.files.push(FileRun {
+                start,
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    /// File the byte at `offset` was compiled from, if a `#line` directive
+    /// named one
+    pub fn file_at(&self, offset: usize) -> Option<&str> {
+        self.files
+            .iter()
+            .rev()
+            .find(|run| run.start as usize <= offset)
+            .map(|run| run.name.as_str())
+    }
+
+    /// Line of the source code the byte at `offset` was compiled from
+    pub fn line_at(&self, offset: usize) -> u32 {
+        let mut end = 0;
+        for run in self.lines.iter() {
+            end += run.count as usize;
+            if offset < end {
+                return run.line;
+            }
+        }
+
+        panic!("Offset {offset} is out of bounds of chunk with len {end}")
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
         let index = self.constants.len();
         self.constants.push(value);
-        index.try_into().unwrap()
+        index
+    }
+
+    /// Add `value` to the constant pool and emit the instruction to load it,
+    /// using `Opcode::ConstantLong` (24-bit operand) when the index doesn't fit in a byte.
+    /// Errors without writing anything once the pool is too big for that too
+    pub fn write_constant(&mut self, value: Value, line: u32) -> Result<(), TooManyConstants> {
+        if self.constants.len() > MAX_CONSTANT_INDEX {
+            return Err(TooManyConstants);
+        }
+
+        let index = self.add_constant(value);
+        if index <= u8::MAX as usize {
+            self.write(Opcode::Constant as u8, line);
+            self.write(index as u8, line);
+        } else {
+            self.write(Opcode::ConstantLong as u8, line);
+            self.write((index >> 16) as u8, line);
+            self.write((index >> 8) as u8, line);
+            self.write(index as u8, line);
+        }
+        Ok(())
+    }
+
+    /// Writes an instruction without operands
+    pub fn write_op(&mut self, op: Opcode, line: u32) -> Result<(), OperandMismatch> {
+        Self::expect_operand(op, &[Operand::None])?;
+        self.write(op as u8, line);
+        Ok(())
+    }
+
+    /// Writes an instruction with a one byte operand, a slot or count or an
+    /// index into the constant pool
+    pub fn write_byte_op(
+        &mut self,
+        op: Opcode,
+        operand: u8,
+        line: u32,
+    ) -> Result<(), OperandMismatch> {
+        Self::expect_operand(op, &[Operand::Byte, Operand::Constant])?;
+        self.write(op as u8, line);
+        self.write(operand, line);
+        Ok(())
+    }
+
+    /// Writes a jump, `distance` is counted from the end of the instruction
+    pub fn write_jump(
+        &mut self,
+        op: Opcode,
+        distance: u16,
+        line: u32,
+    ) -> Result<(), OperandMismatch> {
+        Self::expect_operand(op, &[Operand::Jump])?;
+        self.write(op as u8, line);
+        self.write((distance >> 8) as u8, line);
+        self.write(distance as u8, line);
+        Ok(())
+    }
+
+    /// Writes an `Invoke` or `SuperInvoke` of the method named by the
+    /// constant at `name_idx`
+    pub fn write_invoke(
+        &mut self,
+        op: Opcode,
+        name_idx: u8,
+        arg_count: u8,
+        line: u32,
+    ) -> Result<(), OperandMismatch> {
+        Self::expect_operand(op, &[Operand::Invoke])?;
+        self.write(op as u8, line);
+        self.write(name_idx, line);
+        self.write(arg_count, line);
+        Ok(())
+    }
+
+    fn expect_operand(op: Opcode, allowed: &[Operand]) -> Result<(), OperandMismatch> {
+        let expected = op.operand();
+        if allowed.contains(&expected) {
+            Ok(())
+        } else {
+            Err(OperandMismatch { op, expected })
+        }
+    }
+
+    /// Disassemble the whole chunk into a human-readable listing, one
+    /// instruction per line prefixed with its offset and source line
+    pub fn disassemble(&self, name: &str) -> String {
+        let mut out = format!("== {name} ==\n");
+        let mut lines = LineCursor::new(&self.lines);
+        let mut offset = 0;
+        while offset < self.code.len() {
+            out.push_str(&self.format_instruction(&mut offset, &mut lines));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Format the instruction at `offset` as a single listing line (without
+    /// the trailing newline) and increment offset to the start of the next one
+    pub fn disassemble_instruction_string(&self, offset: &mut usize) -> String {
+        self.format_instruction(offset, &mut LineCursor::new(&self.lines))
+    }
+
+    fn format_instruction(&self, offset: &mut usize, lines: &mut LineCursor) -> String {
+        let start = *offset;
+        let prev_line = (start > 0).then(|| lines.line_at(start - 1));
+        let line = lines.line_at(start);
+
+        let mut out = format!("{start:04} ");
+        if prev_line == Some(line) {
+            out.push_str("   | ");
+        } else {
+            let _ = write!(out, "{line:4} ");
+        }
+
+        let name = match Opcode::from_u8(self.code[start]) {
+            Some(op) => op.name().to_string(),
+            None => format!("Unknown({})", self.code[start]),
+        };
+
+        let _ = match self.disassemble_instruction(offset) {
+            Some(Instruction::Simple(_)) => write!(out, "{name}"),
+            // Not a whole instruction, list the byte on its own
+            None => {
+                *offset += 1;
+                write!(out, "{name}")
+            }
+            Some(Instruction::Constant(_, value)) => write!(out, "{name:<16} {value:?}"),
+            Some(Instruction::Byte(_, byte)) => write!(out, "{name:<16} {byte}"),
+            Some(Instruction::Jump(op, jump)) => {
+                let target = if op == Opcode::Loop {
+                    *offset - jump as usize
+                } else {
+                    *offset + jump as usize
+                };
+                write!(out, "{name:<16} {start} -> {target}")
+            }
+            Some(Instruction::Closure { function, upvalues }) => {
+                let _ = write!(out, "{name:<16} {function:?}");
+                for upvalue in upvalues {
+                    let _ = write!(
+                        out,
+                        " ({} {})",
+                        if upvalue.is_local { "local" } else { "upvalue" },
+                        upvalue.index
+                    );
+                }
+                Ok(())
+            }
+            Some(Instruction::Invoke { method, arg_count }) => {
+                write!(out, "{name:<16} ({arg_count} args) {method:?}")
+            }
+        };
+
+        out
     }
 
     /// Dissamble instruction and increment offset to the start of
-    /// the next one
+    /// the next one. `None` if there's no whole instruction at `offset`: an
+    /// unknown opcode, missing operand bytes or a constant that isn't in the
+    /// pool, `offset` is left alone then
     pub fn disassemble_instruction(&self, offset: &mut usize) -> Option<Instruction> {
-        let instr = self.code[*offset];
-        let op = Opcode::from_u8(instr);
-        match op {
-            Some(
-                Opcode::CloseUpvalue
-                | Opcode::Pop
-                | Opcode::Print
-                | Opcode::Equal
-                | Opcode::Greater
-                | Opcode::Less
-                | Opcode::Not
-                | Opcode::True
-                | Opcode::False
-                | Opcode::Nil
-                | Opcode::Add
-                | Opcode::Subtract
-                | Opcode::Multiply
-                | Opcode::Divide
-                | Opcode::Negate
-                | Opcode::Return
-                | Opcode::Inherit,
-            ) => {
+        let start = *offset;
+        let byte = |index: usize| self.code.get(start + index).copied();
+        let constant = |index: usize| self.constants.get(index).copied();
+        let op = Opcode::from_u8(byte(0)?)?;
+        match op.operand() {
+            Operand::None => {
                 *offset += 1;
-                Some(Instruction::Simple(op.unwrap()))
-            }
-            Some(
-                Opcode::Method
-                | Opcode::GetProperty
-                | Opcode::SetProperty
-                | Opcode::Class
-                | Opcode::Constant
-                | Opcode::DefineGlobal
-                | Opcode::GetGlobal
-                | Opcode::SetGlobal
-                | Opcode::GetSuper,
-            ) => {
-                let constant_idx = self.code[*offset + 1];
-                let constant = self.constants[constant_idx as usize];
+                Some(Instruction::Simple(op))
+            }
+            Operand::Constant => {
+                let constant = constant(byte(1)? as usize)?;
                 *offset += 2;
-                Some(Instruction::Constant(op.unwrap(), constant))
-            }
-            Some(
-                Opcode::GetUpvalue
-                | Opcode::SetUpvalue
-                | Opcode::GetLocal
-                | Opcode::SetLocal
-                | Opcode::Call,
-            ) => {
-                let slot = self.code[*offset + 1];
+                Some(Instruction::Constant(op, constant))
+            }
+            Operand::ConstantLong => {
+                let constant_idx =
+                    ((byte(1)? as usize) << 16) | ((byte(2)? as usize) << 8) | (byte(3)? as usize);
+                let constant = constant(constant_idx)?;
+                *offset += 4;
+                Some(Instruction::Constant(op, constant))
+            }
+            Operand::Byte => {
+                let slot = byte(1)?;
                 *offset += 2;
-                Some(Instruction::Byte(op.unwrap(), slot))
+                Some(Instruction::Byte(op, slot))
             }
-            Some(Opcode::Jump | Opcode::JumpIfFalse | Opcode::Loop) => {
-                let byte1 = self.code[*offset + 1];
-                let byte2 = self.code[*offset + 2];
+            Operand::Jump => {
+                let byte1 = byte(1)?;
+                let byte2 = byte(2)?;
                 *offset += 3;
                 let val = ((byte1 as u16) << 8) | (byte2 as u16);
-                Some(Instruction::Jump(op.unwrap(), val))
+                Some(Instruction::Jump(op, val))
             }
-            Some(Opcode::Closure) => {
-                *offset += 1;
-                let constant_idx = self.code[*offset];
-                *offset += 1;
-                let value = self.constants[constant_idx as usize];
+            Operand::Closure => {
+                let value = constant(byte(1)? as usize)?;
                 let mut upvalues = vec![];
 
-                let function = value.as_fn().unwrap();
-                for _i in 0..function.upvalue_count as usize {
-                    let is_local = self.code[*offset] == 1;
-                    *offset += 1;
-                    let index = self.code[*offset];
-                    *offset += 1;
+                let function = value.as_fn()?;
+                for i in 0..function.upvalue_count as usize {
+                    let is_local = byte(2 + 2 * i)? == 1;
+                    let index = byte(3 + 2 * i)?;
                     upvalues.push(Upvalue { index, is_local })
                 }
+                *offset += 2 + 2 * upvalues.len();
 
                 Some(Instruction::Closure {
                     function: value,
                     upvalues,
                 })
             }
-            Some(Opcode::Invoke | Opcode::SuperInvoke) => {
-                let method = self.code[*offset + 1];
-                let arg_count = self.code[*offset + 2];
-                let method = self.constants[method as usize];
+            Operand::Invoke => {
+                let method = constant(byte(1)? as usize)?;
+                let arg_count = byte(2)?;
                 *offset += 3;
                 Some(Instruction::Invoke { method, arg_count })
             }
-            otherwise => panic!("Invalid opcode {otherwise:?}"),
+        }
+    }
+}
+
+// Serialized layout (all integers little endian):
+//
+// header:   MAGIC, BYTECODE_VERSION (u8)
+// chunk:    code len (u32), code, line run count (u32), (line u32, count u32)*,
+//           file run count (u32), (start u32, name string)*,
+//           constant count (u32), constant*
+// constant: tag (u8) followed by
+//           bool: u8
+//           number: f64
+//           string: len (u32), utf8 bytes
+//           function: arity (u8), upvalue count (u8), has name (u8),
+//                     [name string], chunk
+impl Chunk {
+    /// Serialize the chunk, including the chunks of any functions in its
+    /// constant pool, so it can be loaded again with [`Chunk::from_bytes`]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(BYTECODE_VERSION);
+        self.write_bytes(&mut bytes);
+        bytes
+    }
+
+    /// Load a chunk serialized by [`Chunk::to_bytes`], string and function
+    /// constants are allocated in `mem`.
+    ///
+    /// Each chunk's code is checked as it's loaded so the VM can run it
+    /// without reading outside it: opcodes, operand bytes, constant indices,
+    /// jump targets and the final `Return`. What the code does to the stack
+    /// isn't checked, so only load bytecode written by `to_bytes`
+    pub fn from_bytes(bytes: &[u8], mem: &mut Mem) -> Result<Chunk, DeserializeError> {
+        let mut reader = ByteReader { bytes, offset: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(DeserializeError::BadMagic);
+        }
+
+        let version = reader.u8()?;
+        if version != BYTECODE_VERSION {
+            return Err(DeserializeError::VersionMismatch {
+                expected: BYTECODE_VERSION,
+                found: version,
+            });
+        }
+
+        let chunk = reader.chunk(mem)?;
+        if reader.offset != bytes.len() {
+            return Err(DeserializeError::TrailingBytes);
+        }
+
+        Ok(chunk)
+    }
+
+    /// Checks that the code is a sequence of whole instructions (known
+    /// opcodes with all their operand bytes and constants that are in the
+    /// pool), that every jump lands on one of them and that the last is a
+    /// `Return`
+    fn verify(&self) -> Result<(), DeserializeError> {
+        let mut starts = vec![false; self.code.len()];
+        let mut jumps = vec![];
+        let mut last = None;
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let start = offset;
+            starts[start] = true;
+            match self.disassemble_instruction(&mut offset) {
+                Some(Instruction::Jump(op, distance)) => {
+                    let target = if op == Opcode::Loop {
+                        offset.checked_sub(distance as usize)
+                    } else {
+                        Some(offset + distance as usize)
+                    };
+                    jumps.push((start, target));
+                }
+                Some(_) => (),
+                None if Opcode::from_u8(self.code[start]).is_none() => {
+                    return Err(DeserializeError::InvalidOpcode {
+                        offset: start,
+                        byte: self.code[start],
+                    })
+                }
+                None => return Err(DeserializeError::InvalidOperand { offset: start }),
+            }
+            last = Opcode::from_u8(self.code[start]);
+        }
+
+        for (offset, target) in jumps {
+            if !target.map_or(false, |target| starts.get(target) == Some(&true)) {
+                return Err(DeserializeError::InvalidJump { offset });
+            }
+        }
+
+        if last != Some(Opcode::Return) {
+            return Err(DeserializeError::MissingReturn);
+        }
+
+        Ok(())
+    }
+
+    fn write_bytes(&self, bytes: &mut Vec<u8>) {
+        write_u32(bytes, self.code.len() as u32);
+        bytes.extend_from_slice(&self.code);
+
+        write_u32(bytes, self.lines.len() as u32);
+        for run in self.lines.iter() {
+            write_u32(bytes, run.line);
+            write_u32(bytes, run.count);
+        }
+
+        write_u32(bytes, self.files.len() as u32);
+        for run in self.files.iter() {
+            write_u32(bytes, run.start);
+            write_str(bytes, &run.name);
+        }
+
+        write_u32(bytes, self.constants.len() as u32);
+        for constant in self.constants.iter() {
+            write_constant(bytes, *constant);
+        }
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, val: u32) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_str(bytes: &mut Vec<u8>, string: &str) {
+    write_u32(bytes, string.len() as u32);
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+fn write_constant(bytes: &mut Vec<u8>, value: Value) {
+    match value {
+        Value::Nil => bytes.push(TAG_NIL),
+        Value::Bool(b) => {
+            bytes.push(TAG_BOOL);
+            bytes.push(b as u8);
+        }
+        Value::Number(num) => {
+            bytes.push(TAG_NUMBER);
+            bytes.extend_from_slice(&num.to_le_bytes());
+        }
+        Value::Obj(obj) => match obj.kind {
+            ObjKind::Str => {
+                bytes.push(TAG_STRING);
+                write_str(bytes, value.as_str().unwrap());
+            }
+            ObjKind::Fn => {
+                let function = value.as_fn().unwrap();
+                bytes.push(TAG_FUNCTION);
+                bytes.push(function.arity);
+                bytes.push(function.upvalue_count);
+                match unsafe { function.name.as_ref() } {
+                    Some(name) => {
+                        bytes.push(1);
+                        write_str(bytes, name.as_str());
+                    }
+                    None => bytes.push(0),
+                }
+                function.chunk.write_bytes(bytes);
+            }
+            // The compiler only puts strings and functions in the constant pool
+            kind => panic!("Can't serialize {kind:?} constant"),
+        },
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeserializeError {
+    BadMagic,
+    VersionMismatch {
+        expected: u8,
+        found: u8,
+    },
+    UnexpectedEof,
+    TrailingBytes,
+    InvalidConstantTag(u8),
+    InvalidUtf8,
+    /// The line runs don't cover exactly the chunk's code, or a file run
+    /// starts past its end
+    InvalidLines,
+    /// The byte at `offset`, where an instruction should start, isn't an
+    /// opcode
+    InvalidOpcode {
+        offset: usize,
+        byte: u8,
+    },
+    /// The instruction at `offset` is cut short by the end of the code or
+    /// loads a constant that isn't in the pool
+    InvalidOperand {
+        offset: usize,
+    },
+    /// The jump at `offset` doesn't land on an instruction
+    InvalidJump {
+        offset: usize,
+    },
+    /// The code doesn't end with a `Return`, running it would go past the end
+    MissingReturn,
+}
+
+impl std::fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeserializeError::BadMagic => write!(f, "Not a loxide bytecode file."),
+            DeserializeError::VersionMismatch { expected, found } => write!(
+                f,
+                "Bytecode version {found} is not supported, expected version {expected}."
+            ),
+            DeserializeError::UnexpectedEof => write!(f, "Unexpected end of bytecode."),
+            DeserializeError::TrailingBytes => write!(f, "Unexpected bytes after chunk."),
+            DeserializeError::InvalidConstantTag(tag) => {
+                write!(f, "Invalid constant tag {tag}.")
+            }
+            DeserializeError::InvalidUtf8 => write!(f, "String constant is not valid UTF-8."),
+            DeserializeError::InvalidLines => {
+                write!(f, "Line table doesn't match the chunk's code.")
+            }
+            DeserializeError::InvalidOpcode { offset, byte } => {
+                write!(f, "Invalid opcode {byte} at offset {offset}.")
+            }
+            DeserializeError::InvalidOperand { offset } => {
+                write!(f, "Invalid operand for the instruction at offset {offset}.")
+            }
+            DeserializeError::InvalidJump { offset } => {
+                write!(
+                    f,
+                    "The jump at offset {offset} doesn't land on an instruction."
+                )
+            }
+            DeserializeError::MissingReturn => write!(f, "Code doesn't end with a return."),
+        }
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DeserializeError> {
+        let end = self.offset + len;
+        let slice = self
+            .bytes
+            .get(self.offset..end)
+            .ok_or(DeserializeError::UnexpectedEof)?;
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, DeserializeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, DeserializeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, DeserializeError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str, DeserializeError> {
+        let len = self.u32()? as usize;
+        std::str::from_utf8(self.take(len)?).map_err(|_| DeserializeError::InvalidUtf8)
+    }
+
+    fn chunk(&mut self, mem: &mut Mem) -> Result<Chunk, DeserializeError> {
+        let mut chunk = Chunk::new();
+
+        let code_len = self.u32()? as usize;
+        chunk.code = self.take(code_len)?.to_vec();
+
+        let run_count = self.u32()?;
+        let mut covered = 0;
+        for _ in 0..run_count {
+            let line = self.u32()?;
+            let count = self.u32()?;
+            covered += count as usize;
+            chunk.lines.push(LineRun { line, count });
+        }
+        if covered != code_len {
+            return Err(DeserializeError::InvalidLines);
+        }
+
+        let file_count = self.u32()?;
+        for _ in 0..file_count {
+            let start = self.u32()?;
+            let name = self.str()?.to_string();
+            if start as usize >= code_len {
+                return Err(DeserializeError::InvalidLines);
+            }
+            chunk.files.push(FileRun { start, name });
+        }
+
+        let constant_count = self.u32()?;
+        for _ in 0..constant_count {
+            let constant = self.constant(mem)?;
+            chunk.constants.push(constant);
+        }
+
+        chunk.verify()?;
+        Ok(chunk)
+    }
+
+    fn constant(&mut self, mem: &mut Mem) -> Result<Value, DeserializeError> {
+        match self.u8()? {
+            TAG_NIL => Ok(Value::Nil),
+            TAG_BOOL => Ok(Value::Bool(self.u8()? != 0)),
+            TAG_NUMBER => Ok(Value::Number(self.f64()?)),
+            TAG_STRING => {
+                let string = self.str()?;
+                Ok(Value::Obj(mem.copy_string(string).cast()))
+            }
+            TAG_FUNCTION => {
+                let arity = self.u8()?;
+                let upvalue_count = self.u8()?;
+                let name = match self.u8()? {
+                    0 => null_mut(),
+                    _ => {
+                        let name = self.str()?;
+                        mem.copy_string(name).as_ptr()
+                    }
+                };
+
+                let mut function = mem.alloc_obj(ObjFunction::new(name));
+                function.arity = arity;
+                function.upvalue_count = upvalue_count;
+                function.chunk = self.chunk(mem)?;
+                Ok(Value::Obj(function.cast()))
+            }
+            tag => Err(DeserializeError::InvalidConstantTag(tag)),
         }
     }
 }
@@ -313,7 +979,7 @@ impl<'a> Iterator for ChunkIter<'a> {
     }
 }
 
-pub struct ChunkIterDebug<'a>(ChunkIter<'a>);
+pub struct ChunkIterDebug<'a>(ChunkIter<'a>, LineCursor<'a>);
 
 impl<'a> Iterator for ChunkIterDebug<'a> {
     type Item = InstructionDebug;
@@ -323,9 +989,304 @@ impl<'a> Iterator for ChunkIterDebug<'a> {
             return None;
         }
 
-        let line = self.0.chunk.lines[self.0.offset];
+        let line = self.1.line_at(self.0.offset);
         let inner = self.0.disassemble_instruction();
 
         inner.map(|inner| InstructionDebug { inner, line })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::{
+            Chunk, DeserializeError, Instruction, LineRun, Opcode, Operand, OperandMismatch,
+            UnknownOpcode, BYTECODE_VERSION,
+        },
+        compile::{compile, Parser},
+        interpret,
+        mem::Mem,
+        obj::ObjFunction,
+        value::Value,
+        vm::VM,
+    };
+
+    #[test]
+    fn chunk_write_helpers() {
+        let mut mem = Mem::new();
+        let mut chunk = Chunk::new();
+        let name = chunk.add_constant(Value::Obj(mem.copy_string("x").cast())) as u8;
+        chunk.write_constant(Value::Number(1.0), 1);
+        chunk.write_byte_op(Opcode::DefineGlobal, name, 1).unwrap();
+        chunk.write_byte_op(Opcode::GetGlobal, name, 2).unwrap();
+        chunk.write_jump(Opcode::JumpIfFalse, 4, 2).unwrap();
+        chunk.write_op(Opcode::Pop, 2).unwrap();
+        chunk.write_invoke(Opcode::Invoke, name, 0, 2).unwrap();
+        chunk.write_byte_op(Opcode::PopN, 2, 3).unwrap();
+        chunk.write_op(Opcode::Return, 3).unwrap();
+
+        assert_eq!(
+            chunk.disassemble("helpers"),
+            r#"== helpers ==
+0000    1 OP_CONSTANT      Number(1.0)
+0002    | OP_DEFINE_GLOBAL "x"
+0004    2 OP_GET_GLOBAL    "x"
+0006    | OP_JUMP_IF_FALSE 6 -> 13
+0009    | OP_POP
+0010    | OP_INVOKE        (0 args) "x"
+0013    3 OP_POP_N         2
+0015    | OP_RETURN
+"#
+        );
+
+        let len = chunk.len();
+        assert_eq!(
+            chunk.write_op(Opcode::GetLocal, 1),
+            Err(OperandMismatch {
+                op: Opcode::GetLocal,
+                expected: Operand::Byte
+            })
+        );
+        assert_eq!(
+            chunk
+                .write_byte_op(Opcode::Jump, 0, 1)
+                .unwrap_err()
+                .to_string(),
+            "Jump takes a Jump operand."
+        );
+        assert!(chunk.write_jump(Opcode::Call, 0, 1).is_err());
+        assert!(chunk.write_invoke(Opcode::Closure, 0, 0, 1).is_err());
+        assert_eq!(chunk.len(), len);
+    }
+
+    #[test]
+    fn opcode_names_and_bytes() {
+        let ops: Vec<Opcode> = (0..=u8::MAX)
+            .filter_map(|byte| Opcode::try_from(byte).ok())
+            .collect();
+        // numbered from 0 without gaps
+        assert!(ops.iter().enumerate().all(|(i, op)| *op as usize == i));
+        for op in &ops {
+            assert_eq!(Opcode::try_from(*op as u8), Ok(*op));
+            assert_eq!(op.to_string(), op.name());
+            assert!(op.name().starts_with("OP_"), "{op:?}");
+        }
+        let mut names: Vec<&str> = ops.iter().map(|op| op.name()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), ops.len());
+
+        assert_eq!(Opcode::Constant.to_string(), "OP_CONSTANT");
+        assert_eq!(Opcode::JumpIfFalse.name(), "OP_JUMP_IF_FALSE");
+        assert_eq!(Opcode::GetLocal2.name(), "OP_GET_LOCAL_2");
+        assert_eq!(Opcode::try_from(200), Err(UnknownOpcode(200)));
+        assert_eq!(UnknownOpcode(200).to_string(), "Unknown opcode 200.");
+    }
+
+    #[test]
+    fn chunk_bytes_round_trip() {
+        let src = r#"
+        fun makeAdder(n) {
+            fun add(x) {
+                return x + n;
+            }
+            return add;
+        }
+        class Point {
+            init(x) {
+                this.x = x;
+            }
+        }
+        var sum = makeAdder(2)(40);
+        var greeting = "hello" + " " + "world";
+        var px = Point(7).x;
+        var big = 0;
+        while (big < 3) big = big + 1;"#;
+
+        let mut vm = VM::new();
+        let bytes = {
+            let mut parser = Parser::new(src, &mut vm.mem);
+            assert!(parser.compile());
+            parser.compiler.function.chunk.to_bytes()
+        };
+        interpret(&mut vm, src).unwrap();
+
+        let mut loaded_vm = VM::new();
+        let chunk = Chunk::from_bytes(&bytes, &mut loaded_vm.mem).unwrap();
+        assert_eq!(chunk.to_bytes(), bytes);
+
+        let mut function = loaded_vm
+            .mem
+            .alloc_obj(ObjFunction::new(std::ptr::null_mut()));
+        function.chunk = chunk;
+        loaded_vm.init(function);
+        loaded_vm.run().unwrap();
+
+        for name in ["sum", "greeting", "px", "big"] {
+            let name_str = vm.get_string(name).as_non_null_ptr();
+            let expected = vm.mem.globals.get(name_str);
+            let name_str = loaded_vm.get_string(name).as_non_null_ptr();
+            let loaded = loaded_vm.mem.globals.get(name_str);
+            // Strings live in different heaps so compare what they print as
+            assert_eq!(
+                loaded.map(|value| value.to_string()),
+                expected.map(|value| value.to_string()),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn chunk_bytes_errors() {
+        let mut mem = Mem::new();
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.0), 1).unwrap();
+        chunk.write(Opcode::Return as u8, 1);
+        let bytes = chunk.to_bytes();
+
+        assert!(Chunk::from_bytes(&bytes, &mut mem).is_ok());
+        assert_eq!(
+            Chunk::from_bytes(b"nope", &mut mem).err(),
+            Some(DeserializeError::BadMagic)
+        );
+        assert_eq!(
+            Chunk::from_bytes(&bytes[..bytes.len() - 1], &mut mem).err(),
+            Some(DeserializeError::UnexpectedEof)
+        );
+
+        let mut newer = bytes.clone();
+        newer[4] = BYTECODE_VERSION + 1;
+        let err = Chunk::from_bytes(&newer, &mut mem).err().unwrap();
+        assert_eq!(
+            err,
+            DeserializeError::VersionMismatch {
+                expected: BYTECODE_VERSION,
+                found: BYTECODE_VERSION + 1
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Bytecode version {} is not supported, expected version {BYTECODE_VERSION}.",
+                BYTECODE_VERSION + 1
+            )
+        );
+    }
+
+    #[test]
+    fn chunk_bytes_verify_code() {
+        fn load(code: &[u8], constants: &[Value]) -> Option<DeserializeError> {
+            let mut chunk = Chunk::new();
+            for &byte in code {
+                chunk.write(byte, 1);
+            }
+            chunk.constants.extend_from_slice(constants);
+            Chunk::from_bytes(&chunk.to_bytes(), &mut Mem::new()).err()
+        }
+        use Opcode::*;
+
+        assert_eq!(load(&[Nil as u8, Pop as u8, Return as u8], &[]), None);
+        assert_eq!(
+            load(&[255, Return as u8], &[]),
+            Some(DeserializeError::InvalidOpcode {
+                offset: 0,
+                byte: 255
+            })
+        );
+        // the operand is cut off by the end of the code
+        assert_eq!(
+            load(&[Return as u8, Constant as u8], &[]),
+            Some(DeserializeError::InvalidOperand { offset: 1 })
+        );
+        assert_eq!(
+            load(&[Constant as u8, 1, Return as u8], &[Value::Nil]),
+            Some(DeserializeError::InvalidOperand { offset: 0 })
+        );
+        // past the end, into an operand and before the start
+        for code in [
+            [Jump as u8, 0, 5, Return as u8].as_slice(),
+            &[Jump as u8, 0, 1, Constant as u8, 0, Return as u8],
+            &[Loop as u8, 0, 4, Return as u8],
+        ] {
+            assert_eq!(
+                load(code, &[Value::Nil]),
+                Some(DeserializeError::InvalidJump { offset: 0 })
+            );
+        }
+        let err = load(&[Nil as u8], &[]).unwrap();
+        assert_eq!(err, DeserializeError::MissingReturn);
+        assert_eq!(err.to_string(), "Code doesn't end with a return.");
+    }
+
+    #[test]
+    fn line_runs() {
+        let src = r#"var a = 1;
+
+var b = a +
+  2;
+print b;"#;
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+
+        let chunk = &parser.compiler.function.chunk;
+        assert_eq!(
+            chunk.lines,
+            vec![
+                LineRun { line: 1, count: 4 },
+                LineRun { line: 3, count: 2 },
+                LineRun { line: 4, count: 5 },
+                LineRun { line: 5, count: 5 },
+            ]
+        );
+
+        let expected = [1, 1, 1, 1, 3, 3, 4, 4, 4, 4, 4, 5, 5, 5, 5, 5];
+        assert_eq!(chunk.len(), expected.len());
+        for (offset, line) in expected.into_iter().enumerate() {
+            assert_eq!(chunk.line_at(offset), line);
+        }
+
+        let listing = chunk.disassemble("code");
+        let listed: Vec<&str> = listing.lines().skip(1).map(|line| &line[5..9]).collect();
+        assert_eq!(
+            listed,
+            ["   1", "   |", "   3", "   4", "   |", "   |", "   5", "   |", "   |", "   |"]
+        );
+    }
+
+    #[test]
+    fn constant_long() {
+        let mut chunk = Chunk::new();
+        for i in 0..300 {
+            chunk.write_constant(Value::Number(i as f64), 1).unwrap();
+        }
+
+        let mut count = 0;
+        for (i, instr) in chunk.iter().enumerate() {
+            match instr {
+                Instruction::Constant(op, value) => {
+                    let expected_op = if i <= u8::MAX as usize {
+                        Opcode::Constant
+                    } else {
+                        Opcode::ConstantLong
+                    };
+                    assert_eq!(op, expected_op);
+                    assert_eq!(value, Value::Number(i as f64));
+                }
+                other => panic!("Expected constant instruction, got {other:?}"),
+            }
+            count += 1;
+        }
+        assert_eq!(count, 300);
+
+        let sum = (0..300)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(" + ");
+        let src = format!("var sum = {sum};");
+        let mut vm = VM::new();
+        interpret(&mut vm, &src).unwrap();
+        let sum = vm.get_string("sum").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(sum), Some(Value::Number(44850.0)));
+    }
+}
@@ -4,10 +4,12 @@ use std::{
 };
 
 use crate::{
-    chunk::{Chunk, Opcode},
+    chunk::{Chunk, Instruction, Opcode},
     mem::{Gc, Mem},
     obj::ObjFunction,
+    scanner::{Scanner, Token, TokenKind},
     value::Value,
+    vm::U8_COUNT,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -27,13 +29,22 @@ pub struct ParseRule<'a, 'src> {
 pub enum Precedence {
     None = 0,
     Assignment,
+    Conditional,
     Or,
     And,
     Equality,
     Comparison,
+    /// Unlike C the bitwise operators bind tighter than comparisons (like
+    /// Rust and Python), so `x & 1 == 0` is `(x & 1) == 0`
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
+    /// Above unary so `-2 ** 2` is `-(2 ** 2)`
+    Power,
     Call,
     Primary,
 }
@@ -44,15 +55,21 @@ impl Precedence {
         match val {
             0 => Some(None),
             1 => Some(Assignment),
-            2 => Some(Or),
-            3 => Some(And),
-            4 => Some(Equality),
-            5 => Some(Comparison),
-            6 => Some(Term),
-            7 => Some(Factor),
-            8 => Some(Unary),
-            9 => Some(Call),
-            10 => Some(Primary),
+            2 => Some(Conditional),
+            3 => Some(Or),
+            4 => Some(And),
+            5 => Some(Equality),
+            6 => Some(Comparison),
+            7 => Some(BitOr),
+            8 => Some(BitXor),
+            9 => Some(BitAnd),
+            10 => Some(Shift),
+            11 => Some(Term),
+            12 => Some(Factor),
+            13 => Some(Unary),
+            14 => Some(Power),
+            15 => Some(Call),
+            16 => Some(Primary),
             _ => Option::None,
         }
     }
@@ -92,10 +109,11 @@ macro_rules! parse_rule {
     };
 }
 
-pub struct Local<'src> {
+pub(crate) struct Local<'src> {
     name: Token<'src>,
     depth: Option<u32>,
     is_captured: bool,
+    is_const: bool,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -114,9 +132,9 @@ pub enum FunctionKindT<T> {
     Initializer,
 }
 
-pub struct Locals<'src> {
-    stack: [MaybeUninit<Local<'src>>; u8::MAX as usize],
-    count: u8,
+pub(crate) struct Locals<'src> {
+    stack: [MaybeUninit<Local<'src>>; U8_COUNT],
+    count: usize,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -142,6 +160,26 @@ impl ClassCompiler {
     }
 }
 
+/// Where the latest number constant load was emitted, used for constant folding
+#[derive(Copy, Clone, Debug)]
+struct NumberLoad {
+    start: usize,
+    end: usize,
+    constant_idx: usize,
+    value: f64,
+}
+
+/// The innermost loop being compiled, for `break` and `continue`
+struct Loop<'src> {
+    /// Where `continue` jumps to, the condition or the increment clause
+    start: usize,
+    /// Scope depth outside the loop body, locals deeper than this are
+    /// discarded before jumping
+    scope_depth: usize,
+    /// `break` jumps to patch once the end of the loop is known
+    break_jumps: Vec<(u32, Token<'src>)>,
+}
+
 pub struct Compiler<'src> {
     pub function: Gc<ObjFunction>,
     enclosing: Option<Box<Compiler<'src>>>,
@@ -150,6 +188,17 @@ pub struct Compiler<'src> {
     locals: Locals<'src>,
     scope_depth: usize,
     upvalues: [MaybeUninit<Upvalue>; u8::MAX as usize],
+    loops: Vec<Loop<'src>>,
+    last_number: Option<NumberLoad>,
+    /// Offset of the latest `Call`, a `return` right after it becomes a tail call
+    last_call: Option<usize>,
+    /// Offsets of `Call`s ending the then branch of a `?:`, each followed by
+    /// the jump over the else branch. They're tail calls too when that jump
+    /// lands on the `Return`
+    branch_calls: Vec<usize>,
+    /// Offset of the latest jump target, code before it must not be folded away
+    fold_barrier: usize,
+    limits: CompileLimits,
 }
 
 impl<'src> Compiler<'src> {
@@ -170,7 +219,9 @@ impl<'src> Compiler<'src> {
                 (mem.copy_string(prev_tok.msg).as_ptr(), FunctionKind::Method)
             }
             FunctionKindT::Script => (null_mut(), FunctionKind::Script),
-            FunctionKindT::Initializer => (null_mut(), FunctionKind::Initializer),
+            FunctionKindT::Initializer => {
+                (mem.copy_string("init").as_ptr(), FunctionKind::Initializer)
+            }
         };
 
         let function = mem.alloc_obj(ObjFunction::new(function_name));
@@ -181,11 +232,17 @@ impl<'src> Compiler<'src> {
             function,
             function_kind,
             locals: Locals {
-                stack: [Self::UNINTIALIZED_LOCAL; u8::MAX as usize],
+                stack: [Self::UNINTIALIZED_LOCAL; U8_COUNT],
                 count: 0,
             },
             scope_depth: 0,
             upvalues: [Self::UNINTIALIZED_UPVALUE; u8::MAX as usize],
+            loops: vec![],
+            last_number: None,
+            last_call: None,
+            branch_calls: vec![],
+            fold_barrier: 0,
+            limits: CompileLimits::default(),
         };
 
         // Safety:
@@ -194,10 +251,13 @@ impl<'src> Compiler<'src> {
         unsafe {
             let mut local_ptr = this.locals.stack[0].as_mut_ptr();
             (*local_ptr).is_captured = false;
+            (*local_ptr).is_const = false;
             (*local_ptr).depth = Some(0);
             (*local_ptr).name = Token {
                 kind: TokenKind::Nil,
                 line: 0,
+                column: 0,
+                file: None,
                 msg: if function_kind != FunctionKind::Function {
                     "this"
                 } else {
@@ -249,7 +309,7 @@ impl<'src> Compiler<'src> {
                 .rev()
             {
                 let upvalue = upvalue.assume_init();
-                if upvalue.index == index && upvalue.is_local {
+                if upvalue.index == index && upvalue.is_local == is_local {
                     return i as u8;
                 }
             }
@@ -294,13 +354,31 @@ impl<'src> Compiler<'src> {
         }
     }
 
+    /// Whether `name` resolves to a `const` local of this function or of one
+    /// enclosing it
+    fn is_const_local(&self, name: Token) -> bool {
+        let local = self
+            .locals
+            .stack
+            .iter()
+            .take(self.locals.count)
+            .rev()
+            .map(|local| unsafe { local.assume_init_ref() })
+            .find(|local| local.name.msg == name.msg);
+        match (local, &self.enclosing) {
+            (Some(local), _) => local.is_const,
+            (None, Some(enclosing)) => enclosing.is_const_local(name),
+            (None, None) => false,
+        }
+    }
+
     fn resolve_local(&mut self, name: Token, errors: &mut Vec<&str>) -> Option<u8> {
         for (i, local) in self
             .locals
             .stack
             .iter()
             .enumerate()
-            .take(self.locals.count as usize)
+            .take(self.locals.count)
             .rev()
         {
             let local = unsafe { local.assume_init_ref() };
@@ -316,10 +394,117 @@ impl<'src> Compiler<'src> {
     }
 }
 
+/// Soft limits on what one compile accepts: the size of each function's
+/// chunk and how deeply the source nests. Compiling stops with an error once
+/// one is passed so a huge generated program can't exhaust memory or
+/// overflow the stack. The defaults are far beyond anything written by hand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileLimits {
+    pub max_constants: usize,
+    /// In bytes
+    pub max_code: usize,
+    /// How many expressions or statements (and function bodies) can be
+    /// nested in each other
+    pub max_nesting: usize,
+}
+
+impl Default for CompileLimits {
+    fn default() -> Self {
+        Self {
+            // as many as `ConstantLong` can address
+            max_constants: 1 << 24,
+            max_code: 1 << 26,
+            max_nesting: 250,
+        }
+    }
+}
+
+/// Why [`compile`] failed
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    /// Every error reported, in source order, there's always at least one
+    pub errors: Vec<String>,
+}
+
+impl CompileError {
+    pub fn count(&self) -> usize {
+        self.errors.len()
+    }
+
+    pub fn first(&self) -> &str {
+        &self.errors[0]
+    }
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.first())?;
+        match self.count() {
+            1 => Ok(()),
+            2 => write!(f, " (and 1 more error)"),
+            count => write!(f, " (and {} more errors)", count - 1),
+        }
+    }
+}
+
+/// The line of `src` that `token` starts on with the token underlined,
+/// `^~~~`, below it. Tokens spanning lines are underlined to the end of their
+/// first line. `None` for tokens that aren't a span of `src`
+fn underline(src: &str, token: Token) -> Option<String> {
+    if matches!(
+        token.kind,
+        TokenKind::Eof | TokenKind::Error | TokenKind::Synthetic
+    ) {
+        return None;
+    }
+    let start = (token.msg.as_ptr() as usize).checked_sub(src.as_ptr() as usize)?;
+    if start + token.msg.len() > src.len() {
+        return None;
+    }
+
+    let line_start = src[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = src[start..].find('\n').map_or(src.len(), |i| start + i);
+    let line = src[line_start..line_end].trim_end_matches('\r');
+    // keep tabs so the marks line up however wide they're shown
+    let indent: String = src[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = token.msg.split('\n').next().unwrap_or("").chars().count();
+
+    Some(format!(
+        "    {line}\n    {indent}^{}",
+        "~".repeat(width.saturating_sub(1))
+    ))
+}
+
+/// Compiles `src` into its top-level script function, allocated in `mem`
+pub fn compile(src: &str, mem: &mut Mem) -> Result<Gc<ObjFunction>, CompileError> {
+    compile_with_limits(src, mem, CompileLimits::default())
+}
+
+/// Like [`compile`], holding the source to `limits` instead of the defaults
+pub fn compile_with_limits(
+    src: &str,
+    mem: &mut Mem,
+    limits: CompileLimits,
+) -> Result<Gc<ObjFunction>, CompileError> {
+    let mut parser = Parser::with_limits(src, mem, limits);
+    if parser.compile() {
+        Ok(parser.compiler.function)
+    } else {
+        Err(CompileError {
+            errors: parser.errors,
+        })
+    }
+}
+
 pub struct Parser<'a, 'src> {
     pub compiler: Box<Compiler<'src>>,
     mem: &'a mut Mem,
     scanner: Scanner<'src>,
+    /// Kept to show the offending line under each error
+    src: &'src str,
 
     // probably a bad idea to make maybeuninit but 2 lazy rn
     cur: MaybeUninit<Token<'src>>,
@@ -327,18 +512,43 @@ pub struct Parser<'a, 'src> {
 
     had_error: bool,
     panic_mode: bool,
+    /// Every reported compile error, as printed but without its source line
+    errors: Vec<String>,
+    /// When set, a top-level expression statement that ends the script
+    /// returns its value instead of popping it, see [`Parser::compile_trailing_expr`]
+    trailing_expr: bool,
+    /// Set when that trailing expression also left out its `;`
+    bare_trailing_expr: bool,
+    /// Global slots this script made `const`, undone if it fails to compile
+    /// so the names stay usable (e.g. in the next REPL line)
+    new_consts: Vec<u32>,
+    /// Global slots this script defines without `const`, which a `const`
+    /// after them can't redeclare
+    defined_globals: Vec<u32>,
+    /// Set once the source went past one of its `CompileLimits`
+    hit_limit: bool,
+    /// How many expressions and statements the parser is inside of
+    nesting: usize,
 }
 
 impl<'a, 'src: 'a> Parser<'a, 'src> {
-    pub const PARSE_RULES: [ParseRule<'a, 'src>; 40] = [
+    pub const PARSE_RULES: [ParseRule<'a, 'src>; 64] = [
         // left paren
         parse_rule!(pre = Parser::grouping, inf = Parser::call, Precedence::Call),
         // right paren
         none_prec!(),
         // left brace
-        none_prec!(),
+        parse_rule!(pre = Parser::map, Precedence::None),
         // right brace
         none_prec!(),
+        // left bracket
+        parse_rule!(
+            pre = Parser::list,
+            inf = Parser::subscript,
+            Precedence::Call
+        ),
+        // right bracket
+        none_prec!(),
         // comma
         none_prec!(),
         // dot
@@ -353,22 +563,48 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         parse_rule!(inf = Parser::binary, Precedence::Factor),
         // star
         parse_rule!(inf = Parser::binary, Precedence::Factor),
+        // percent
+        parse_rule!(inf = Parser::binary, Precedence::Factor),
+        // question
+        parse_rule!(inf = Parser::conditional, Precedence::Conditional),
+        // colon
+        none_prec!(),
+        // ampersand
+        parse_rule!(inf = Parser::binary, Precedence::BitAnd),
+        // pipe
+        parse_rule!(inf = Parser::binary, Precedence::BitOr),
+        // caret
+        parse_rule!(inf = Parser::binary, Precedence::BitXor),
         // bang
         parse_rule!(pre = Parser::unary, Precedence::None),
         // bangequal
         parse_rule!(inf = Parser::binary, Precedence::Equality),
         // equal
-        parse_rule!(inf = Parser::binary, Precedence::Equality),
+        none_prec!(),
         // equalequal
-        parse_rule!(inf = Parser::binary, Precedence::Comparison),
+        parse_rule!(inf = Parser::binary, Precedence::Equality),
         // greater
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
         // greaterequal
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
+        // greatergreater
+        parse_rule!(inf = Parser::binary, Precedence::Shift),
         // less
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
         // lessequal
         parse_rule!(inf = Parser::binary, Precedence::Comparison),
+        // lessless
+        parse_rule!(inf = Parser::binary, Precedence::Shift),
+        // plusequal
+        none_prec!(),
+        // minusequal
+        none_prec!(),
+        // starequal
+        none_prec!(),
+        // starstar
+        parse_rule!(inf = Parser::binary, Precedence::Power),
+        // slashequal
+        none_prec!(),
         // identifier
         parse_rule!(pre = Parser::variable, Precedence::None),
         // string
@@ -377,8 +613,20 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         parse_rule!(pre = Parser::number, Precedence::None),
         // and
         parse_rule!(inf = Parser::and, Precedence::And),
+        // assert
+        none_prec!(),
+        // break
+        none_prec!(),
+        // case
+        none_prec!(),
         // class
         none_prec!(),
+        // const
+        none_prec!(),
+        // continue
+        none_prec!(),
+        // default
+        none_prec!(),
         // else
         none_prec!(),
         // false
@@ -386,9 +634,11 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         // for
         none_prec!(),
         // fun
-        none_prec!(),
+        parse_rule!(pre = Parser::lambda, Precedence::None),
         // if
         none_prec!(),
+        // in
+        none_prec!(),
         // nil
         parse_rule!(pre = Parser::literal, Precedence::None),
         // or
@@ -399,6 +649,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         none_prec!(),
         // super
         parse_rule!(pre = Parser::super_, Precedence::None),
+        // switch
+        none_prec!(),
         // this
         parse_rule!(pre = Parser::this, Precedence::None),
         // true
@@ -407,6 +659,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         none_prec!(),
         // while
         none_prec!(),
+        // write
+        none_prec!(),
         // error
         none_prec!(),
         // eof
@@ -414,17 +668,32 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     ];
 
     pub fn new(src: &'src str, mem: &'a mut Mem) -> Self {
+        Self::with_limits(src, mem, CompileLimits::default())
+    }
+
+    /// Makes a parser whose chunks are held to `limits` instead of the
+    /// defaults
+    pub fn with_limits(src: &'src str, mem: &'a mut Mem, limits: CompileLimits) -> Self {
         let scanner = Scanner::new(src);
-        let compiler = Box::new(Compiler::new(FunctionKindT::Script, None, mem));
+        let mut compiler = Box::new(Compiler::new(FunctionKindT::Script, None, mem));
+        compiler.limits = limits;
 
         Self {
             compiler,
             mem,
             scanner,
+            src,
             cur: MaybeUninit::uninit(),
             prev: MaybeUninit::uninit(),
             had_error: false,
             panic_mode: false,
+            errors: vec![],
+            trailing_expr: false,
+            bare_trailing_expr: false,
+            new_consts: vec![],
+            defined_globals: vec![],
+            hit_limit: false,
+            nesting: 0,
         }
     }
 
@@ -446,9 +715,34 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
 
         self.end();
+        if self.had_error {
+            for slot in self.new_consts.drain(..) {
+                self.mem.globals.set_const(slot, false);
+            }
+        }
         !self.had_error
     }
 
+    #[cfg(test)]
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    /// Like [`Parser::compile`], but a top-level expression statement at the
+    /// end of the script becomes the script's return value, and its `;` may
+    /// be omitted
+    pub fn compile_trailing_expr(&mut self) -> bool {
+        self.trailing_expr = true;
+        self.compile()
+    }
+
+    /// Whether the script compiled by [`Parser::compile_trailing_expr`] ended
+    /// with an expression that had no `;`, which is how the REPL tells the
+    /// lines to echo apart from statements
+    pub fn ends_with_bare_expr(&self) -> bool {
+        self.bare_trailing_expr
+    }
+
     fn synchronize(&mut self) {
         self.panic_mode = false;
 
@@ -459,7 +753,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
             use TokenKind::*;
             match self.cur().kind {
-                Class | Fun | Var | For | If | While | Print | Return => return,
+                Class | Const | Fun | Var | For | If | Switch | While | Print | Write | Return
+                | Assert | Break | Continue => return,
                 _ => (),
             }
 
@@ -491,7 +786,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         &Self::PARSE_RULES[kind as u8 as usize]
     }
 
-    fn named_variable(&mut self, name: Token, ctx: ParseRuleCtx) {
+    fn named_variable(&mut self, name: Token<'src>, ctx: ParseRuleCtx) {
         let (arg, get_op, set_op) = match self.resolve_local(name) {
             Some(arg) => (arg, Opcode::GetLocal as u8, Opcode::SetLocal as u8),
             None => self
@@ -499,7 +794,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
                 .map(|arg| (arg, Opcode::GetUpvalue as u8, Opcode::SetUpvalue as u8))
                 .unwrap_or_else(|| {
                     (
-                        self.identifier_constant(name),
+                        self.global_constant(name),
                         Opcode::GetGlobal as u8,
                         Opcode::SetGlobal as u8,
                     )
@@ -507,13 +802,65 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         };
 
         if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+            self.check_assignable(name, set_op, arg);
+            self.expression();
+            self.emit_bytes(set_op, arg);
+        } else if ctx.can_assign && let Some(op) = self.match_compound_assign() {
+            self.check_assignable(name, set_op, arg);
+            self.emit_get(get_op, arg);
             self.expression();
+            self.emit_byte(op as u8);
             self.emit_bytes(set_op, arg);
+        } else {
+            self.emit_get(get_op, arg);
+        }
+    }
+
+    /// Emits `get_op arg`, using the operand-less `GetLocal0`..`GetLocal3`
+    /// for the lowest local slots
+    fn emit_get(&mut self, get_op: u8, arg: u8) {
+        if get_op == Opcode::GetLocal as u8 && arg < 4 {
+            self.emit_byte(Opcode::GetLocal0 as u8 + arg);
         } else {
             self.emit_bytes(get_op, arg);
         }
     }
 
+    /// Reports assigning to `name` if it's a `const`, `set_op` and `arg` are
+    /// how `named_variable` resolved it
+    fn check_assignable(&mut self, name: Token<'src>, set_op: u8, arg: u8) {
+        let is_const = if set_op == Opcode::SetGlobal as u8 {
+            let slot = self.global_slot(arg);
+            self.mem.globals.is_const(slot)
+        } else {
+            self.compiler.is_const_local(name)
+        };
+        if is_const {
+            self.error_at(name, &format!("Cannot assign to const '{}'.", name.msg));
+        }
+    }
+
+    /// The global slot of the name in constant `global`
+    fn global_slot(&mut self, global: u8) -> u32 {
+        let name = self.compiler.current_chunk().constants[global as usize]
+            .as_obj_str()
+            .unwrap();
+        self.mem.globals.slot(name)
+    }
+
+    /// Consumes a `+=`, `-=`, `*=` or `/=`, returning the arithmetic it does
+    fn match_compound_assign(&mut self) -> Option<Opcode> {
+        let op = match self.cur().kind {
+            TokenKind::PlusEqual => Opcode::Add,
+            TokenKind::MinusEqual => Opcode::Subtract,
+            TokenKind::StarEqual => Opcode::Multiply,
+            TokenKind::SlashEqual => Opcode::Divide,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
     fn declaration(&mut self) {
         if self.match_tok(TokenKind::Class) {
             self.class_declaration()
@@ -521,6 +868,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.fn_declaration();
         } else if self.match_tok(TokenKind::Var) {
             self.var_declaration();
+        } else if self.match_tok(TokenKind::Const) {
+            self.const_declaration();
         } else {
             self.statement();
         }
@@ -616,7 +965,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn or(&mut self, _ctx: ParseRuleCtx) {
-        let else_jump = self.emit_jump(Opcode::Jump as u8);
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         let end_jump = self.emit_jump(Opcode::Jump as u8);
 
         self.patch_jump(else_jump);
@@ -627,16 +976,36 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.patch_jump(end_jump);
     }
 
+    fn conditional(&mut self, _ctx: ParseRuleCtx) {
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+        self.emit_byte(Opcode::Pop as u8);
+        self.expression();
+        self.consume(
+            TokenKind::Colon,
+            "Expect ':' after then branch of conditional expression.",
+        );
+
+        let len = self.compiler.current_chunk().len();
+        if let Some(call) = self.compiler.last_call.filter(|call| call + 2 == len) {
+            self.compiler.branch_calls.push(call);
+        }
+        let end_jump = self.emit_jump(Opcode::Jump as u8);
+        self.patch_jump(else_jump);
+        self.emit_byte(Opcode::Pop as u8);
+
+        // Parsing the else branch at our own precedence makes `?:` right-associative
+        self.parse_precedence(Precedence::Conditional);
+        self.patch_jump(end_jump);
+    }
+
     fn number(&mut self, _ctx: ParseRuleCtx) {
-        let value: f64 = self.prev().msg.parse().unwrap();
+        let value = self.prev().number_value();
         self.emit_constant(value.into())
     }
 
     fn string(&mut self, _ctx: ParseRuleCtx) {
-        let string = self.prev().msg;
-
-        // get rid of the quotations
-        let obj_str = self.mem.copy_string(&string[1..string.len() - 1]);
+        let string = self.prev().string_value();
+        let obj_str = self.mem.copy_string(&string);
 
         self.emit_constant(Value::Obj(obj_str.cast()));
     }
@@ -657,6 +1026,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn call(&mut self, _ctx: ParseRuleCtx) {
         let arg_count = self.argument_list();
+        self.compiler.last_call = Some(self.compiler.current_chunk().len());
         self.emit_bytes(Opcode::Call as u8, arg_count);
     }
 
@@ -664,11 +1034,11 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         let mut arg_count = 0;
         if !self.check(TokenKind::RightParen) {
             loop {
-                self.expression();
                 if arg_count == u8::MAX {
-                    self.error("Can't have more than 255 arguments");
+                    self.error_at_current("Can't have more than 255 arguments.");
                 }
-                arg_count += 1;
+                self.expression();
+                arg_count = arg_count.saturating_add(1);
                 if !self.match_tok(TokenKind::Comma) {
                     break;
                 }
@@ -687,6 +1057,14 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         if ctx.can_assign && self.match_tok(TokenKind::Equal) {
             self.expression();
             self.emit_bytes(Opcode::SetProperty as u8, name);
+        } else if ctx.can_assign && let Some(op) = self.match_compound_assign() {
+            // The receiver stays under the old value for the `SetProperty`,
+            // so it's only evaluated once
+            self.emit_byte(Opcode::Dup as u8);
+            self.emit_bytes(Opcode::GetProperty as u8, name);
+            self.expression();
+            self.emit_byte(op as u8);
+            self.emit_bytes(Opcode::SetProperty as u8, name);
         } else if self.match_tok(TokenKind::LeftParen) {
             let arg_count = self.argument_list();
             self.emit_bytes(Opcode::Invoke as u8, name);
@@ -696,6 +1074,61 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
     }
 
+    fn list(&mut self, _ctx: ParseRuleCtx) {
+        let mut item_count: u8 = 0;
+        if !self.check(TokenKind::RightBracket) {
+            loop {
+                if item_count == u8::MAX {
+                    self.error_at_current("Can't have more than 255 items in a list literal.");
+                }
+                self.expression();
+                item_count = item_count.saturating_add(1);
+                // allow a trailing comma
+                if !self.match_tok(TokenKind::Comma) || self.check(TokenKind::RightBracket) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightBracket, "Expect ']' after list items.");
+        self.emit_bytes(Opcode::BuildList as u8, item_count);
+    }
+
+    /// Only reached in expression position, a `{` starting a statement is a block
+    fn map(&mut self, _ctx: ParseRuleCtx) {
+        let mut entry_count: u8 = 0;
+        if !self.check(TokenKind::RightBrace) {
+            loop {
+                if entry_count == u8::MAX {
+                    self.error_at_current("Can't have more than 255 entries in a map literal.");
+                }
+                self.expression();
+                self.consume(TokenKind::Colon, "Expect ':' after map key.");
+                self.expression();
+                entry_count = entry_count.saturating_add(1);
+                // allow a trailing comma
+                if !self.match_tok(TokenKind::Comma) || self.check(TokenKind::RightBrace) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after map entries.");
+        self.emit_bytes(Opcode::BuildMap as u8, entry_count);
+    }
+
+    fn subscript(&mut self, ctx: ParseRuleCtx) {
+        self.expression();
+        self.consume(TokenKind::RightBracket, "Expect ']' after index.");
+
+        if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+            self.expression();
+            self.emit_byte(Opcode::IndexSet as u8);
+        } else {
+            self.emit_byte(Opcode::IndexGet as u8);
+        }
+    }
+
     fn unary(&mut self, _ctx: ParseRuleCtx) {
         let op_kind = self.prev().kind;
 
@@ -710,8 +1143,18 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn binary(&mut self, _ctx: ParseRuleCtx) {
         let op_kind = self.prev().kind;
+        let lhs = self.trailing_number();
         let rule = Self::get_rule(op_kind);
-        self.parse_precedence(Precedence::from_u8(rule.precedence as u8 + 1).unwrap());
+        // `**` is right associative, `2 ** 3 ** 2` is `2 ** (3 ** 2)`
+        let rhs_precedence = match op_kind {
+            TokenKind::StarStar => rule.precedence,
+            _ => Precedence::from_u8(rule.precedence as u8 + 1).unwrap(),
+        };
+        self.parse_precedence(rhs_precedence);
+
+        if let Some(lhs) = lhs && self.fold_binary(op_kind, lhs) {
+            return;
+        }
 
         match op_kind {
             TokenKind::BangEqual => self.emit_bytes(Opcode::Equal as u8, Opcode::Not as u8),
@@ -723,11 +1166,56 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             TokenKind::Plus => self.emit_byte(Opcode::Add as u8),
             TokenKind::Minus => self.emit_byte(Opcode::Subtract as u8),
             TokenKind::Star => self.emit_byte(Opcode::Multiply as u8),
+            TokenKind::Percent => self.emit_byte(Opcode::Modulo as u8),
             TokenKind::Slash => self.emit_byte(Opcode::Divide as u8),
+            TokenKind::StarStar => self.emit_byte(Opcode::Power as u8),
+            TokenKind::Ampersand => self.emit_byte(Opcode::BitAnd as u8),
+            TokenKind::Pipe => self.emit_byte(Opcode::BitOr as u8),
+            TokenKind::Caret => self.emit_byte(Opcode::BitXor as u8),
+            TokenKind::LessLess => self.emit_byte(Opcode::ShiftLeft as u8),
+            TokenKind::GreaterGreater => self.emit_byte(Opcode::ShiftRight as u8),
             other => unreachable!("{:?}", other),
         }
     }
 
+    /// The number constant load at the very end of the current chunk, if
+    /// nothing jumps into it and it can still be folded
+    fn trailing_number(&self) -> Option<NumberLoad> {
+        let load = self.compiler.last_number?;
+        (load.end == self.compiler.current_chunk().len()
+            && load.start >= self.compiler.fold_barrier)
+            .then_some(load)
+    }
+
+    /// Replaces the loads of `lhs` and the number right after it with a single
+    /// load of the result. Division and modulo by zero are left for the VM.
+    fn fold_binary(&mut self, op_kind: TokenKind, lhs: NumberLoad) -> bool {
+        let rhs = match self.trailing_number() {
+            Some(rhs) if rhs.start == lhs.end => rhs,
+            _ => return false,
+        };
+
+        let value = match op_kind {
+            TokenKind::Plus => lhs.value + rhs.value,
+            TokenKind::Minus => lhs.value - rhs.value,
+            TokenKind::Star => lhs.value * rhs.value,
+            TokenKind::StarStar => lhs.value.powf(rhs.value),
+            TokenKind::Slash if rhs.value != 0.0 => lhs.value / rhs.value,
+            TokenKind::Percent if rhs.value != 0.0 => lhs.value % rhs.value,
+            _ => return false,
+        };
+
+        let chunk = self.compiler.current_chunk_mut();
+        chunk.truncate(lhs.start);
+        if lhs.constant_idx + 1 == rhs.constant_idx && rhs.constant_idx + 1 == chunk.constants.len()
+        {
+            chunk.constants.truncate(lhs.constant_idx);
+        }
+        self.emit_constant(Value::Number(value));
+
+        true
+    }
+
     fn function(&mut self, kind: FunctionKind) {
         let kindt = match kind {
             FunctionKind::Function => FunctionKindT::Function(self.prev()),
@@ -735,12 +1223,24 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             FunctionKind::Script => FunctionKindT::Script,
             FunctionKind::Initializer => FunctionKindT::Initializer,
         };
+        self.compile_function(kindt);
+    }
+
+    /// `fun (params) { body }` in expression position. A statement starting
+    /// with `fun` is always a declaration, so calling one right away needs
+    /// parentheses: `(fun () { ... })();`
+    fn lambda(&mut self, _ctx: ParseRuleCtx) {
+        self.compile_function(FunctionKindT::Function(Token::synthetic("<anonymous>")));
+    }
 
+    /// Compiles the parameters and body that follow and emits the closure
+    fn compile_function(&mut self, kindt: FunctionKindT<Token<'src>>) {
         let temp = self.compiler.class_compiler.take();
         let temp_compiler = std::mem::replace(
             &mut self.compiler,
             Box::new(Compiler::new(kindt, temp, self.mem)),
         );
+        self.compiler.limits = temp_compiler.limits;
         self.compiler.enclosing = Some(temp_compiler);
 
         self.begin_scope();
@@ -753,7 +1253,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
                         self.compiler.current_fn_mut().arity = new_arity;
                     }
                     None => {
-                        self.error_at_current("Can't have more than 255 parameters");
+                        self.error_at_current("Can't have more than 255 parameters.");
                     }
                 };
 
@@ -767,7 +1267,10 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.consume(TokenKind::RightParen, "Expect ')' after parameters.");
         self.consume(TokenKind::LeftBrace, "Expect '{' before function body.");
 
-        self.block();
+        if self.nest("Statement nesting too deep.") {
+            self.block();
+            self.unnest();
+        }
 
         self.end();
 
@@ -797,7 +1300,12 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn var_declaration(&mut self) {
-        let global = self.parse_variable("Expect variable name.");
+        self.consume(TokenKind::Identifier, "Expect variable name.");
+        if self.check(TokenKind::Comma) {
+            self.destructuring_declaration();
+            return;
+        }
+        let global = self.declare_named(self.prev());
 
         if self.match_tok(TokenKind::Equal) {
             self.expression();
@@ -813,15 +1321,109 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.define_variable(global);
     }
 
+    /// `var a, b = list;` binds each name to the list's item at its position,
+    /// it's a runtime error if the value isn't a list or has fewer items than
+    /// names. Like a plain `var`, the names are declared before the
+    /// initializer, which can't read the locals among them
+    fn destructuring_declaration(&mut self) {
+        // the names' constants, which are all 0 for locals
+        let mut names = vec![self.declare_named(self.prev())];
+        while self.match_tok(TokenKind::Comma) {
+            names.push(self.parse_variable("Expect variable name."));
+        }
+        if names.len() > u8::MAX as usize {
+            self.error("Can't destructure into more than 255 names.");
+        }
+        self.consume(TokenKind::Equal, "Expect '=' after variable names.");
+        self.expression();
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        // the items land in the names' slots for locals, globals are defined
+        // from the top of the stack down
+        self.emit_bytes(Opcode::Unpack as u8, names.len() as u8);
+        if self.compiler.scope_depth == 0 {
+            for global in names.into_iter().rev() {
+                self.define_variable(global);
+            }
+            return;
+        }
+
+        let scope_depth = self.compiler.scope_depth as u32;
+        let count = self.compiler.locals.count;
+        for local in &mut self.compiler.locals.stack[count.saturating_sub(names.len())..count] {
+            unsafe { local.assume_init_mut() }.depth = Some(scope_depth);
+        }
+    }
+
+    fn const_declaration(&mut self) {
+        let name = self.cur();
+        let global = self.parse_variable("Expect constant name.");
+        // marked before the initializer, which can't assign to it either
+        if self.compiler.scope_depth > 0 {
+            unsafe {
+                self.compiler.locals.stack[self.compiler.locals.count - 1]
+                    .assume_init_mut()
+                    .is_const = true;
+            }
+        } else {
+            let slot = self.global_slot(global);
+            // a var that's already there (or will be by the time this runs)
+            // could still be assigned by code compiled before the `const`
+            let defined =
+                self.mem.globals.get_slot(slot).is_some() || self.defined_globals.contains(&slot);
+            if defined && !self.mem.globals.is_const(slot) {
+                self.error_at(name, &format!("Cannot redeclare '{}' as const.", name.msg));
+            }
+            if !self.mem.globals.is_const(slot) {
+                self.mem.globals.set_const(slot, true);
+                self.new_consts.push(slot);
+            }
+        }
+
+        self.consume(TokenKind::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenKind::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
     fn parse_variable(&mut self, err_msg: &str) -> u8 {
         self.consume(TokenKind::Identifier, err_msg);
 
-        self.declare_variable();
+        self.declare_named(self.prev())
+    }
+
+    /// Declares `name`, returning its name constant if it's a global (and 0
+    /// for locals, which don't have one)
+    fn declare_named(&mut self, name: Token<'src>) -> u8 {
+        self.declare_variable_named(name);
         if self.compiler.scope_depth > 0 {
             return 0;
         }
 
-        let name = self.prev();
+        self.identifier_constant(name)
+    }
+
+    fn declare_variable(&mut self) {
+        self.declare_variable_named(self.prev());
+    }
+
+    fn declare_variable_named(&mut self, name: Token<'src>) {
+        if self.compiler.scope_depth == 0 {
+            // globals can be redeclared, but not once they're const
+            let string = self.mem.copy_string(name.msg);
+            let slot = self.mem.globals.slot(string);
+            if self.mem.globals.is_const(slot) {
+                self.error_at(name, &format!("Cannot redeclare const '{}'.", name.msg));
+            }
+            return;
+        }
 
         let mut had_error = false;
         for local in self
@@ -829,7 +1431,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             .locals
             .stack
             .iter()
-            .take(self.compiler.locals.count as usize)
+            .take(self.compiler.locals.count)
             .rev()
         {
             let local = unsafe { local.assume_init_ref() };
@@ -839,39 +1441,32 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
                 break;
             }
 
-            if name == local.name {
+            if name.msg == local.name.msg {
                 had_error = true;
             }
         }
 
         if had_error {
-            self.error("Already a variable with this name in this scope.");
+            self.error_at(name, "Already a variable with this name in this scope.");
         }
 
-        self.identifier_constant(name)
-    }
-
-    fn declare_variable(&mut self) {
-        if self.compiler.scope_depth == 0 {
-            return;
-        }
-
-        self.add_local(&self.prev());
+        self.add_local(&name);
     }
 
     fn add_local(&mut self, tok: &Token<'src>) {
-        if self.compiler.locals.count == u8::MAX {
+        if self.compiler.locals.count == U8_COUNT {
             self.error("Too many local variables in function.");
             return;
         }
 
-        let local = self.compiler.locals.stack[self.compiler.locals.count as usize].as_mut_ptr();
+        let local = self.compiler.locals.stack[self.compiler.locals.count].as_mut_ptr();
         self.compiler.locals.count += 1;
 
         unsafe {
             (*local).name = *tok;
             (*local).depth = None;
             (*local).is_captured = false;
+            (*local).is_const = false;
         }
     }
 
@@ -881,6 +1476,13 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             return;
         }
 
+        let name = self.compiler.current_chunk().constants[global as usize]
+            .as_obj_str()
+            .unwrap();
+        let slot = self.mem.globals.slot(name);
+        if !self.mem.globals.is_const(slot) {
+            self.defined_globals.push(slot);
+        }
         self.emit_bytes(Opcode::DefineGlobal as u8, global)
     }
 
@@ -890,7 +1492,7 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         }
         let scope_depth = self.compiler.scope_depth;
         unsafe {
-            self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
+            self.compiler.locals.stack[self.compiler.locals.count - 1]
                 .assume_init_mut()
                 .depth = Some(scope_depth as u32);
         }
@@ -901,9 +1503,28 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.make_constant(constant)
     }
 
+    /// Like `identifier_constant`, but also gives the global its slot now so
+    /// the VM never has to
+    fn global_constant(&mut self, name: Token) -> u8 {
+        let name = self.mem.copy_string(name.msg);
+        self.mem.globals.slot(name);
+        self.make_constant(Value::Obj(name.cast()))
+    }
+
     fn statement(&mut self) {
+        if self.nest("Statement nesting too deep.") {
+            self.nested_statement();
+            self.unnest();
+        }
+    }
+
+    fn nested_statement(&mut self) {
         if self.match_tok(TokenKind::Print) {
             self.print_statement();
+        } else if self.match_tok(TokenKind::Write) {
+            self.write_statement();
+        } else if self.match_tok(TokenKind::Assert) {
+            self.assert_statement();
         } else if self.match_tok(TokenKind::For) {
             self.for_statement();
         } else if self.match_tok(TokenKind::LeftBrace) {
@@ -912,21 +1533,29 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             self.end_scope();
         } else if self.match_tok(TokenKind::If) {
             self.if_statement();
+        } else if self.match_tok(TokenKind::Switch) {
+            self.switch_statement();
         } else if self.match_tok(TokenKind::Return) {
             self.return_statement();
         } else if self.match_tok(TokenKind::While) {
             self.while_statement();
+        } else if self.match_tok(TokenKind::Break) {
+            self.break_statement();
+        } else if self.match_tok(TokenKind::Continue) {
+            self.continue_statement();
         } else {
             self.expression_statement();
         }
     }
 
-    fn emit_loop(&mut self, loop_start: usize) {
+    /// Emits the jump back to `loop_start`, `keyword` is the loop's `for`,
+    /// `while` or `continue` for reporting a loop that's too long
+    fn emit_loop(&mut self, loop_start: usize, keyword: Token<'src>) {
         self.emit_byte(Opcode::Loop as u8);
 
         let offset = self.compiler.current_chunk().len() - loop_start + 2;
         if offset > u16::MAX as usize {
-            self.error("Loop body too large.");
+            self.error_at(keyword, "Loop body too large.");
         }
 
         self.emit_byte(((offset as u16) >> 8) as u8);
@@ -934,9 +1563,15 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn for_statement(&mut self) {
+        let keyword = self.prev();
         self.begin_scope();
 
         self.consume(TokenKind::LeftParen, "Expect '(' after 'for'.");
+        if self.starts_for_in() {
+            self.for_in_statement(keyword);
+            self.end_scope();
+            return;
+        }
 
         // Handle the initializer caluse
         if self.match_tok(TokenKind::Semicolon) {
@@ -974,23 +1609,106 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
             self.consume(TokenKind::RightParen, "Expect ')' after for clauses.");
 
-            self.emit_loop(loop_start);
+            self.emit_loop(loop_start, keyword);
             loop_start = increment_start;
             self.patch_jump(body_jump);
         }
 
+        self.begin_loop(loop_start);
         self.statement();
-        self.emit_loop(loop_start);
+        self.emit_loop(loop_start, keyword);
 
         if let Some(exit_jump) = exit_jump {
             self.patch_jump(exit_jump);
             self.emit_byte(Opcode::Pop as u8);
         }
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    /// Whether the clauses are `name in ...` or `var name in ...`, the `var`
+    /// is consumed if so
+    fn starts_for_in(&mut self) -> bool {
+        let mut scanner = self.scanner.clone();
+        let mut name = self.cur();
+        if name.kind == TokenKind::Var {
+            name = scanner.token();
+        }
+        if name.kind != TokenKind::Identifier || scanner.token().kind != TokenKind::In {
+            return false;
+        }
+
+        self.match_tok(TokenKind::Var);
+        true
+    }
+
+    /// `for (name in iterable) body` runs as
+    ///
+    /// ```text
+    /// {
+    ///     var <seq> = iterable;
+    ///     var <index> = 0;
+    ///     while (<index> < len(<seq>)) {
+    ///         var name = <seq>[<index>];
+    ///         <index> = <index> + 1;
+    ///         body
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// where `Iter` keeps a list as it is and swaps a map for a list of its
+    /// keys, anything else is an error. `name` is a new local each time
+    /// around, so closures in the body capture each item separately
+    fn for_in_statement(&mut self, keyword: Token<'src>) {
+        self.consume(TokenKind::Identifier, "Expect loop variable name.");
+        let name = self.prev();
+        self.consume(TokenKind::In, "Expect 'in' after loop variable.");
+        self.expression();
+        self.consume(TokenKind::RightParen, "Expect ')' after for clauses.");
 
+        self.emit_byte(Opcode::Iter as u8);
+        self.add_local(&Token::synthetic("for seq"));
+        self.mark_initialized();
+        self.emit_constant(Value::Number(0.0));
+        self.add_local(&Token::synthetic("for index"));
+        self.mark_initialized();
+        let index = (self.compiler.locals.count - 1) as u8;
+        let seq = index.wrapping_sub(1);
+
+        let loop_start = self.compiler.current_chunk().len();
+        self.emit_get(Opcode::GetLocal as u8, index);
+        self.emit_get(Opcode::GetLocal as u8, seq);
+        self.emit_byte(Opcode::Len as u8);
+        self.emit_byte(Opcode::Less as u8);
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
+        self.emit_byte(Opcode::Pop as u8);
+
+        self.begin_loop(loop_start);
+        self.begin_scope();
+        self.emit_get(Opcode::GetLocal as u8, seq);
+        self.emit_get(Opcode::GetLocal as u8, index);
+        self.emit_byte(Opcode::IndexGet as u8);
+        self.add_local(&name);
+        self.mark_initialized();
+
+        self.emit_get(Opcode::GetLocal as u8, index);
+        self.emit_constant(Value::Number(1.0));
+        self.emit_byte(Opcode::Add as u8);
+        self.emit_bytes(Opcode::SetLocal as u8, index);
+        self.emit_byte(Opcode::Pop as u8);
+
+        self.statement();
         self.end_scope();
+        self.emit_loop(loop_start, keyword);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(Opcode::Pop as u8);
+        self.end_loop();
     }
 
     fn while_statement(&mut self) {
+        let keyword = self.prev();
         let loop_start = self.compiler.current_chunk().len();
 
         self.consume(TokenKind::LeftParen, "Expect '(' after 'while'.");
@@ -999,11 +1717,106 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
         let exit_jump = self.emit_jump(Opcode::JumpIfFalse as u8);
         self.emit_byte(Opcode::Pop as u8);
+        self.begin_loop(loop_start);
         self.statement();
-        self.emit_loop(loop_start);
+        self.emit_loop(loop_start, keyword);
 
         self.patch_jump(exit_jump);
         self.emit_byte(Opcode::Pop as u8);
+        self.end_loop();
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        self.compiler.loops.push(Loop {
+            start,
+            scope_depth: self.compiler.scope_depth,
+            break_jumps: vec![],
+        });
+    }
+
+    /// Patches the loop's `break`s to jump here, past the condition's `Pop`
+    fn end_loop(&mut self) {
+        let lp = self.compiler.loops.pop().unwrap();
+        for jump in lp.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        let scope_depth = self.compiler.loops.last().map(|lp| lp.scope_depth);
+        if scope_depth.is_none() {
+            self.error("Can't use 'break' outside of a loop.");
+        }
+        self.consume(TokenKind::Semicolon, "Expect ';' after 'break'.");
+
+        if let Some(scope_depth) = scope_depth {
+            self.discard_locals(scope_depth);
+            let jump = self.emit_jump(Opcode::Jump as u8);
+            self.compiler
+                .loops
+                .last_mut()
+                .unwrap()
+                .break_jumps
+                .push(jump);
+        }
+    }
+
+    fn continue_statement(&mut self) {
+        let keyword = self.prev();
+        let target = self
+            .compiler
+            .loops
+            .last()
+            .map(|lp| (lp.start, lp.scope_depth));
+        if target.is_none() {
+            self.error("Can't use 'continue' outside of a loop.");
+        }
+        self.consume(TokenKind::Semicolon, "Expect ';' after 'continue'.");
+
+        if let Some((start, scope_depth)) = target {
+            self.discard_locals(scope_depth);
+            self.emit_loop(start, keyword);
+        }
+    }
+
+    /// Emits the pops for locals deeper than `depth` without forgetting them,
+    /// returning how many there were. Runs of uncaptured locals are popped
+    /// together, captured ones still need their own `CloseUpvalue`
+    fn discard_locals(&mut self, depth: usize) -> usize {
+        let mut discarded = 0;
+        let mut pending_pops = 0;
+        for i in (0..self.compiler.locals.count).rev() {
+            let local = unsafe { self.compiler.locals.stack[i].assume_init_ref() };
+            if local
+                .depth
+                .map_or(true, |local_depth| local_depth as usize <= depth)
+            {
+                break;
+            }
+
+            discarded += 1;
+            if local.is_captured {
+                self.emit_pops(pending_pops);
+                pending_pops = 0;
+                self.emit_byte(Opcode::CloseUpvalue as u8);
+            } else {
+                pending_pops += 1;
+            }
+        }
+        self.emit_pops(pending_pops);
+
+        discarded
+    }
+
+    fn emit_pops(&mut self, mut count: usize) {
+        while count > 0 {
+            let popped = count.min(u8::MAX as usize);
+            match popped {
+                1 => self.emit_byte(Opcode::Pop as u8),
+                _ => self.emit_bytes(Opcode::PopN as u8, popped as u8),
+            }
+            count -= popped;
+        }
     }
 
     fn return_statement(&mut self) {
@@ -1020,10 +1833,42 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
             self.expression();
             self.consume(TokenKind::Semicolon, "Expect ';' after return value.");
+            self.mark_tail_call();
             self.emit_byte(Opcode::Return as u8);
         }
     }
 
+    /// Turns a call that is the last thing the returned expression does into
+    /// a `TailCall`. The `Return` after it still runs when the callee is not
+    /// a closure, or when a jump skipped the call.
+    ///
+    /// A call as the right operand of `and` and `or` counts (`return n == 0
+    /// or f(n - 1);`), one as the left operand doesn't, it isn't the last
+    /// instruction. Both branches of a `?:` count: the else branch's call is
+    /// the last instruction, and the then branch's is followed by a jump
+    /// straight to the `Return`, unless the `?:` is itself in a then branch
+    /// and its jump lands on the outer one's
+    fn mark_tail_call(&mut self) {
+        let len = self.compiler.current_chunk().len();
+        let branch_calls = std::mem::take(&mut self.compiler.branch_calls);
+        let chunk = self.compiler.current_chunk_mut();
+        if self
+            .compiler
+            .last_call
+            .map_or(false, |call| call + 2 == len)
+        {
+            chunk.code[len - 2] = Opcode::TailCall as u8;
+        }
+
+        for call in branch_calls {
+            // `Call n`, then `Jump` and its 2 byte offset
+            let jump = u16::from_be_bytes([chunk.code[call + 3], chunk.code[call + 4]]);
+            if call + 5 + jump as usize == len {
+                chunk.code[call] = Opcode::TailCall as u8;
+            }
+        }
+    }
+
     fn if_statement(&mut self) {
         self.consume(TokenKind::LeftParen, "Expect '(' after 'if'.");
         self.expression();
@@ -1046,7 +1891,111 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.patch_jump(else_jump);
     }
 
-    fn emit_jump(&mut self, instr: u8) -> u32 {
+    /// Cases don't fall through, each one ends by jumping past the rest of the
+    /// switch. The subject stays on the stack while it's compared to the case
+    /// values and is popped before the statements of a case run, so they see
+    /// the same stack as the code around the switch
+    fn switch_statement(&mut self) {
+        self.consume(TokenKind::LeftParen, "Expect '(' after 'switch'.");
+        // The subject is kept in a local no one can name, so every case can
+        // read it back
+        self.begin_scope();
+        self.expression();
+        self.add_local(&Token::synthetic(""));
+        self.mark_initialized();
+        let subject = (self.compiler.locals.count - 1) as u8;
+        self.consume(TokenKind::RightParen, "Expect ')' after value.");
+        self.consume(TokenKind::LeftBrace, "Expect '{' before switch cases.");
+
+        let mut end_jumps = vec![];
+        let mut case_values = vec![];
+        let mut has_default = false;
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            if self.match_tok(TokenKind::Case) {
+                if has_default {
+                    self.error("Can't have a case after the default case.");
+                }
+
+                self.emit_bytes(Opcode::GetLocal as u8, subject);
+                let value_start = self.compiler.current_chunk().len();
+                self.expression();
+                // only literals can be told apart at compile time
+                if let Some(value) = self.literal_since(value_start) {
+                    if case_values.contains(&value) {
+                        self.error("Duplicate case value.");
+                    }
+                    case_values.push(value);
+                }
+                self.consume(TokenKind::Colon, "Expect ':' after case value.");
+
+                // subject -> value -> equal -> next_case -> pop -> stmts -> end
+                self.emit_byte(Opcode::Equal as u8);
+                let next_case = self.emit_jump(Opcode::JumpIfFalse as u8);
+                self.emit_byte(Opcode::Pop as u8);
+                self.case_body();
+                end_jumps.push(self.emit_jump(Opcode::Jump as u8));
+
+                self.patch_jump(next_case);
+                self.emit_byte(Opcode::Pop as u8);
+            } else if self.match_tok(TokenKind::Default) {
+                if has_default {
+                    self.error("Can't have more than one default case.");
+                }
+                has_default = true;
+                self.consume(TokenKind::Colon, "Expect ':' after 'default'.");
+
+                self.case_body();
+                end_jumps.push(self.emit_jump(Opcode::Jump as u8));
+            } else {
+                self.error_at_current("Expect 'case' or 'default'.");
+                self.advance();
+            }
+        }
+
+        self.consume(TokenKind::RightBrace, "Expect '}' after switch cases.");
+
+        for end_jump in end_jumps {
+            self.patch_jump(end_jump);
+        }
+        self.end_scope();
+    }
+
+    /// The statements up to the next case, in a scope of their own
+    fn case_body(&mut self) {
+        self.begin_scope();
+        while !self.check(TokenKind::Case)
+            && !self.check(TokenKind::Default)
+            && !self.check(TokenKind::RightBrace)
+            && !self.check(TokenKind::Eof)
+        {
+            self.declaration();
+        }
+        self.end_scope();
+    }
+
+    /// The value of the literal compiled from `start` to the end of the
+    /// current chunk, if that code is a single literal load
+    fn literal_since(&self, start: usize) -> Option<Value> {
+        let chunk = self.compiler.current_chunk();
+        if start >= chunk.len() {
+            return None;
+        }
+
+        let mut offset = start;
+        let value = match chunk.disassemble_instruction(&mut offset)? {
+            Instruction::Constant(Opcode::Constant | Opcode::ConstantLong, value) => value,
+            Instruction::Simple(Opcode::True) => Value::Bool(true),
+            Instruction::Simple(Opcode::False) => Value::Bool(false),
+            Instruction::Simple(Opcode::Nil) => Value::Nil,
+            _ => return None,
+        };
+        (offset == chunk.len()).then_some(value)
+    }
+
+    /// Returns the offset to patch and the token the jump was emitted after,
+    /// a jump too long to encode is reported there rather than at the end of
+    /// the code it skips
+    fn emit_jump(&mut self, instr: u8) -> (u32, Token<'src>) {
         self.emit_byte(instr);
 
         // using 2 bytes as a 16 bit int for the offset to jump
@@ -1055,20 +2004,21 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.emit_byte(0xff);
 
         // returns index of the first byte of the jump offset
-        self.compiler.current_chunk().len() as u32 - 2
+        (self.compiler.current_chunk().len() as u32 - 2, self.prev())
     }
 
-    fn patch_jump(&mut self, offset: u32) {
+    fn patch_jump(&mut self, (offset, token): (u32, Token<'src>)) {
         // -2 to adjust for the 2 bytes for the jump offset
         // this will be the index just before the next instruction
         let jump = self.compiler.current_chunk().len() as u32 - offset - 2;
 
         if jump > u16::MAX as u32 {
-            self.error("Too much code to jump over.");
+            self.error_at(token, "Too much code to jump over.");
         }
 
         self.compiler.current_chunk_mut().code[offset as usize] = (jump >> 8) as u8;
         self.compiler.current_chunk_mut().code[offset as usize + 1] = jump as u8;
+        self.compiler.fold_barrier = self.compiler.current_chunk().len();
     }
 
     fn block(&mut self) {
@@ -1085,27 +2035,8 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     fn end_scope(&mut self) {
         self.compiler.scope_depth -= 1;
 
-        while self.compiler.locals.count > 0
-            && unsafe {
-                self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
-                    .assume_init_ref()
-                    .depth
-                    .map(|val| val as isize)
-                    .unwrap_or(-1)
-            } > self.compiler.scope_depth as isize
-        {
-            let is_captured = unsafe {
-                self.compiler.locals.stack[self.compiler.locals.count as usize - 1]
-                    .assume_init_ref()
-                    .is_captured
-            };
-            self.emit_byte(if is_captured {
-                Opcode::CloseUpvalue as u8
-            } else {
-                Opcode::Pop as u8
-            });
-            self.compiler.locals.count -= 1;
-        }
+        let discarded = self.discard_locals(self.compiler.scope_depth);
+        self.compiler.locals.count -= discarded;
     }
 
     fn match_tok(&mut self, kind: TokenKind) -> bool {
@@ -1123,9 +2054,48 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
         self.emit_byte(Opcode::Print as u8)
     }
 
+    /// `write x;`, `print` without the newline
+    fn write_statement(&mut self) {
+        self.expression();
+        self.consume(TokenKind::Semicolon, "Expect ';' after value.");
+        self.emit_byte(Opcode::Write as u8)
+    }
+
+    /// The asserted expression's source text goes in the constant table so a
+    /// failure can say what didn't hold
+    fn assert_statement(&mut self) {
+        let first = self.cur();
+        self.expression();
+        // After an error `prev` may be an error token that isn't part of the source
+        let text = if self.panic_mode {
+            ""
+        } else {
+            self.scanner.text_between(first, self.prev())
+        };
+        self.consume(TokenKind::Semicolon, "Expect ';' after assertion.");
+
+        let text = Value::Obj(self.mem.copy_string(text).cast());
+        let constant = self.make_constant(text);
+        self.emit_bytes(Opcode::Assert as u8, constant);
+    }
+
     fn expression_statement(&mut self) {
         self.expression();
+
+        let trailing = self.trailing_expr
+            && self.compiler.function_kind == FunctionKind::Script
+            && self.compiler.scope_depth == 0;
+        if trailing && self.check(TokenKind::Eof) {
+            self.bare_trailing_expr = true;
+            self.emit_byte(Opcode::Return as u8);
+            return;
+        }
+
         self.consume(TokenKind::Semicolon, "Expect ';' after expression.");
+        if trailing && self.check(TokenKind::Eof) {
+            self.emit_byte(Opcode::Return as u8);
+            return;
+        }
         self.emit_byte(Opcode::Pop as u8)
     }
 
@@ -1135,32 +2105,6 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
 
     fn end(&mut self) {
         self.emit_return();
-        #[cfg(debug_assertions)]
-        {
-            if !self.had_error {
-                unsafe {
-                    let name = self
-                        .compiler
-                        .function
-                        .as_ref()
-                        .name
-                        .as_ref()
-                        .map(|s| s.as_str())
-                        .unwrap_or("script");
-                    println!(
-                        "{} :: {:#?} :: upvalues :: {:#?}",
-                        name,
-                        self.compiler.current_chunk(),
-                        self.compiler
-                            .upvalues
-                            .iter()
-                            .take(self.compiler.current_fn().upvalue_count as usize)
-                            .map(|val| val.assume_init())
-                            .collect::<Vec<_>>()
-                    );
-                }
-            }
-        }
     }
 
     /// This is badly named. This function is called if a return statement has no expression,
@@ -1170,17 +2114,26 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     fn emit_return(&mut self) {
         if self.compiler.function_kind == FunctionKind::Initializer {
             // slot 0 contains the instance
-            self.emit_bytes(Opcode::GetLocal as u8, 0);
+            self.emit_byte(Opcode::GetLocal0 as u8);
         } else {
             self.emit_byte(Opcode::Nil as u8);
         }
         self.emit_byte(Opcode::Return as u8)
     }
 
+    /// Keeps the chunk's file names in step with `#line` directives
+    fn mark_file(&mut self) {
+        if let Some(file) = self.prev().file {
+            self.compiler.current_chunk_mut().set_file(file);
+        }
+    }
+
     fn emit_byte(&mut self, byte: u8) {
+        self.mark_file();
         self.compiler
             .current_chunk_mut()
-            .write(byte, self.prev().line)
+            .write(byte, self.prev().line);
+        self.check_limits();
     }
 
     fn emit_bytes(&mut self, a: u8, b: u8) {
@@ -1189,18 +2142,80 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(Opcode::Constant as u8, constant);
+        self.mark_file();
+        let line = self.prev().line;
+        let chunk = self.compiler.current_chunk_mut();
+        let start = chunk.len();
+        let constant_idx = chunk.constants.len();
+        if chunk.write_constant(value, line).is_err() {
+            self.error("Too many constants in one chunk");
+            return;
+        }
+        self.check_limits();
+
+        if let Value::Number(value) = value {
+            self.compiler.last_number = Some(NumberLoad {
+                start,
+                end: self.compiler.current_chunk().len(),
+                constant_idx,
+                value,
+            });
+        }
     }
 
+    /// Adds a constant for instructions with a one byte operand
     fn make_constant(&mut self, value: Value) -> u8 {
         let constant_idx = self.compiler.current_chunk_mut().add_constant(value);
-        if constant_idx >= u8::MAX {
+        self.check_limits();
+        if constant_idx > u8::MAX as usize {
             self.error("Too many constants in one chunk");
             return 0;
         }
 
-        constant_idx
+        constant_idx as u8
+    }
+
+    /// Reports the current chunk outgrowing `CompileLimits`
+    fn check_limits(&mut self) {
+        let limits = self.compiler.limits;
+        let chunk = self.compiler.current_chunk();
+        let msg = if chunk.constants.len() > limits.max_constants {
+            format!("Too many constants, the limit is {}.", limits.max_constants)
+        } else if chunk.len() > limits.max_code {
+            format!("Too much code, the limit is {} bytes.", limits.max_code)
+        } else {
+            return;
+        };
+
+        self.stop_compiling(&msg);
+    }
+
+    /// Goes one level deeper into the source, or reports it nesting past
+    /// `CompileLimits::max_nesting` and returns false. Call `unnest` after a
+    /// true
+    fn nest(&mut self, msg: &str) -> bool {
+        if self.nesting == self.compiler.limits.max_nesting {
+            self.stop_compiling(msg);
+            return false;
+        }
+
+        self.nesting += 1;
+        true
+    }
+
+    fn unnest(&mut self) {
+        self.nesting -= 1;
+    }
+
+    /// Reports going past a limit and skips the rest of the source, so
+    /// nothing more is compiled
+    fn stop_compiling(&mut self, msg: &str) {
+        if !self.hit_limit {
+            self.error(msg);
+            self.hit_limit = true;
+            self.scanner = Scanner::new("");
+            self.advance();
+        }
     }
 
     fn consume(&mut self, kind: TokenKind, msg: &str) {
@@ -1234,26 +2249,42 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 
     fn error_at(&mut self, token: Token<'src>, msg: &str) {
-        if self.panic_mode {
+        // past a limit the source is cut short, what that breaks isn't worth reporting
+        if self.panic_mode || self.hit_limit {
             return;
         }
 
         self.panic_mode = true;
 
-        eprint!("[line {}] Error", token.line);
+        let location = match token.kind {
+            TokenKind::Eof => " at end".to_string(),
+            TokenKind::Error => String::new(),
+            _ => format!(" at '{}'", token.msg),
+        };
+        let file = token
+            .file
+            .map_or(String::new(), |file| format!(" of {file}"));
+        let error = format!(
+            "[line {}:{}{file}] Error{location}: {msg}",
+            token.line, token.column
+        );
 
-        if token.kind == TokenKind::Eof {
-            eprint!(" at end")
-        } else if token.kind == TokenKind::Error {
-        } else {
-            eprint!(" at {}", token.msg)
+        match underline(self.src, token) {
+            Some(snippet) => eprintln!("{error}\n{snippet}"),
+            None => eprintln!("{error}"),
         }
-
-        eprintln!(": {msg}");
+        self.errors.push(error);
         self.had_error = true;
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if self.nest("Expression nesting too deep.") {
+            self.parse_nested_precedence(precedence);
+            self.unnest();
+        }
+    }
+
+    fn parse_nested_precedence(&mut self, precedence: Precedence) {
         self.advance();
         let rule = match Self::get_rule(self.prev().kind).prefix {
             Some(rule) => rule,
@@ -1277,7 +2308,9 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
             infix_rule(self, ctx);
         }
 
-        if ctx.can_assign && self.match_tok(TokenKind::Equal) {
+        if ctx.can_assign
+            && (self.match_tok(TokenKind::Equal) || self.match_compound_assign().is_some())
+        {
             self.error("Invalid assignment target.");
         }
     }
@@ -1327,333 +2360,683 @@ impl<'a, 'src: 'a> Parser<'a, 'src> {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum TokenKind {
-    // Single-character tokens.
-    LeftParen = 0,
-    RightParen,
-    LeftBrace,
-    RightBrace,
-    Comma,
-    Dot,
-    Minus,
-    Plus,
-    Semicolon,
-    Slash,
-    Star,
-
-    // One or two character tokens.
-    Bang,
-    BangEqual,
-    Equal,
-    EqualEqual,
-    Greater,
-    GreaterEqual,
-    Less,
-    LessEqual,
-
-    // Literals.
-    Identifier,
-    String,
-    Number,
-
-    // Keywords.
-    And,
-    Class,
-    Else,
-    False,
-    For,
-    Fun,
-    If,
-    Nil,
-    Or,
-    Print,
-    Return,
-    Super,
-    This,
-    True,
-    Var,
-    While,
-
-    Error,
-    Eof,
-
-    Synthetic,
-}
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::{Chunk, Opcode, Operand},
+        compile::{compile, compile_with_limits, CompileLimits, Parser},
+        eval, interpret,
+        mem::Mem,
+        obj::ObjFunction,
+        scanner::{tokens, Token},
+        value::Value,
+        vm::{InterpretError, TraceFrame, VM},
+    };
 
-#[derive(Copy, Clone, Debug, PartialEq)]
-pub struct Token<'src> {
-    kind: TokenKind,
-    line: u32,
-    msg: &'src str,
-}
+    #[test]
+    fn invalid_assignment_target() {
+        for src in ["1 = 2;", "var a = 1; var b = 2; a + b = 3;"] {
+            let mut vm = VM::new();
+            let err = interpret(&mut vm, src);
+            assert_eq!(err, Err(InterpretError::CompileError));
+        }
+    }
+
+    #[test]
+    fn get_local_superinstructions() {
+        let mut mem = Mem::new();
+        let src = "{ var a = 1; var b = 2; var c = 3; var d = 4; print a + d; }";
+        let function = compile(src, &mut mem).unwrap();
+        assert_eq!(
+            function.chunk.disassemble("script"),
+            r#"== script ==
+0000    1 OP_CONSTANT      Number(1.0)
+0002    | OP_CONSTANT      Number(2.0)
+0004    | OP_CONSTANT      Number(3.0)
+0006    | OP_CONSTANT      Number(4.0)
+0008    | OP_GET_LOCAL_1
+0009    | OP_GET_LOCAL     4
+0011    | OP_ADD
+0012    | OP_PRINT
+0013    | OP_POP_N         4
+0015    | OP_NIL
+0016    | OP_RETURN
+"#
+        );
 
-impl<'src> Token<'src> {
-    fn synthetic(msg: &'src str) -> Self {
-        Self {
-            kind: TokenKind::Synthetic,
-            line: u32::MAX,
-            msg,
-        }
-    }
+        // the same reads through the generic `GetLocal` and through the one
+        // byte forms, mixed with a slot past them
+        let run = |low_slots: bool| {
+            let mut vm = VM::new();
+            let mut chunk = Chunk::new();
+            for n in [10.0, 20.0, 30.0, 40.0] {
+                chunk.write_constant(Value::Number(n), 1);
+            }
+            for (slot, op) in [
+                (1, Opcode::Subtract),
+                (4, Opcode::Multiply),
+                (2, Opcode::Add),
+            ] {
+                chunk.write_byte_op(Opcode::GetLocal, 3, 1).unwrap();
+                if low_slots && slot < 4 {
+                    let op = Opcode::from_u8(Opcode::GetLocal0 as u8 + slot).unwrap();
+                    chunk.write_op(op, 1).unwrap();
+                } else {
+                    chunk.write_byte_op(Opcode::GetLocal, slot, 1).unwrap();
+                }
+                chunk.write_op(op, 1).unwrap();
+                chunk.write_op(Opcode::Add, 1).unwrap();
+            }
+            chunk.write_op(Opcode::Return, 1).unwrap();
+            let mut function = vm.mem.alloc_obj(ObjFunction::new(std::ptr::null_mut()));
+            function.chunk = chunk;
+            vm.init(function);
+            vm.run()
+        };
+        // each result lands on slot 4: 40 + (30 - 10), then + 30 * 60, then + (30 + 20)
+        assert_eq!(run(false), Ok(Value::Number(1910.0)));
+        assert_eq!(run(true), run(false));
+
+        let src = r#"fun f(p1, p2, p3, p4, p5) {
+    var sum = p1 + p2 * p3 - p4 / p5;
+    p1 += 10;
+    return [p1, p2, p3, p4, p5, sum];
 }
-
-pub struct Scanner<'src> {
-    src: &'src [u8],
-    start: usize,
-    current: usize,
-    line: usize,
+class A {
+    init(x) { this.x = x; }
 }
-
-impl<'src> Scanner<'src> {
-    pub fn new(src: &'src str) -> Self {
-        Self {
-            src: src.as_bytes(),
-            start: 0,
-            current: 0,
-            line: 1,
-        }
-    }
-
-    fn advance(&mut self) -> u8 {
-        let ret = self.src[self.current];
-        self.current += 1;
-        ret
-    }
-
-    fn peek(&mut self) -> u8 {
-        self.src.get(self.current).cloned().unwrap_or(b'\0')
+str(f(1, 2, 3, 4, 5)) + str(A(7).x);"#;
+        let mut vm = VM::new();
+        assert_eq!(
+            eval(&mut vm, src).unwrap().to_string(),
+            "[11, 2, 3, 4, 5, 6.2]7"
+        );
     }
 
-    fn peek_next(&mut self) -> u8 {
-        if self.is_at_end() {
-            b'\0'
-        } else {
-            self.src[self.current + 1]
+    #[test]
+    fn local_declaration_errors() {
+        for src in ["{ var a = 1; var a = 2; }", "{ var a = a; }"] {
+            let mut vm = VM::new();
+            let err = interpret(&mut vm, src);
+            assert_eq!(err, Err(InterpretError::CompileError));
         }
-    }
-
-    fn skip_whitespace(&mut self) {
-        loop {
-            let c = self.peek();
 
-            match c {
-                b' ' | b'\r' | b'\t' => {
-                    self.advance();
-                }
-                b'\n' => {
-                    self.line += 1;
-                    self.advance();
-                }
-                b'/' => {
-                    if self.peek_next() == b'/' {
-                        while self.peek() != b'\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
-                    }
-                }
-                _ => return,
-            }
-        }
+        // globals may be redeclared
+        let mut vm = VM::new();
+        interpret(&mut vm, "var a = 1; var a = 2;").unwrap();
     }
 
-    pub fn token(&mut self) -> Token<'src> {
-        self.skip_whitespace();
-        self.start = self.current;
-
-        if self.is_at_end() {
-            return self.make_token(TokenKind::Eof);
-        }
-
-        let c = self.advance();
-
-        if Self::is_alpha(c) {
-            return self.identifier();
+    #[test]
+    fn parameter_and_argument_limits() {
+        fn list(prefix: &str, count: usize) -> String {
+            (0..count)
+                .map(|i| format!("{prefix}{i}"))
+                .collect::<Vec<_>>()
+                .join(", ")
         }
 
-        if Self::is_digit(c) {
-            return self.number();
-        }
-
-        match c {
-            b'(' => return self.make_token(TokenKind::LeftParen),
-            b')' => return self.make_token(TokenKind::RightParen),
-            b'{' => return self.make_token(TokenKind::LeftBrace),
-            b'}' => return self.make_token(TokenKind::RightBrace),
-            b';' => return self.make_token(TokenKind::Semicolon),
-            b',' => return self.make_token(TokenKind::Comma),
-            b'.' => return self.make_token(TokenKind::Dot),
-            b'-' => return self.make_token(TokenKind::Minus),
-            b'+' => return self.make_token(TokenKind::Plus),
-            b'/' => return self.make_token(TokenKind::Slash),
-            b'*' => return self.make_token(TokenKind::Star),
-            b'!' => {
-                let kind = if self.matches(b'=') {
-                    TokenKind::BangEqual
-                } else {
-                    TokenKind::Bang
-                };
-                return self.make_token(kind);
-            }
-            b'=' => {
-                let kind = if self.matches(b'=') {
-                    TokenKind::EqualEqual
-                } else {
-                    TokenKind::Equal
-                };
-                return self.make_token(kind);
-            }
-            b'<' => {
-                let kind = if self.matches(b'=') {
-                    TokenKind::LessEqual
-                } else {
-                    TokenKind::Less
-                };
-                return self.make_token(kind);
-            }
-            b'>' => {
-                let kind = if self.matches(b'=') {
-                    TokenKind::GreaterEqual
-                } else {
-                    TokenKind::Greater
-                };
-                return self.make_token(kind);
-            }
-            b'"' => return self.string(),
-            _ => (),
-        }
-
-        self.error_token("Unexpected character.")
-    }
+        let src = format!(
+            "fun f({}) {{ return p254; }} var result = f({});",
+            list("p", 255),
+            list("", 255)
+        );
+        let mut vm = VM::new();
+        interpret(&mut vm, &src).unwrap();
+        let result = vm.get_string("result").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(result), Some(Value::Number(254.0)));
+
+        let src = format!("fun f({}) {{}}", list("p", 256));
+        let column = src.find("p255").unwrap() + 1;
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(&src, &mut mem);
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.errors(),
+            [format!(
+                "[line 1:{column}] Error at 'p255': Can't have more than 255 parameters."
+            )]
+        );
 
-    fn is_alpha(c: u8) -> bool {
-        (b'a'..=b'z').contains(&c) || (b'A'..=b'Z').contains(&c) || c == b'_'
+        let src = format!("fun f() {{}} f({});", list("", 256));
+        let column = src.find("255").unwrap() + 1;
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(&src, &mut mem);
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.errors(),
+            [format!(
+                "[line 1:{column}] Error at '255': Can't have more than 255 arguments."
+            )]
+        );
     }
 
-    fn identifier(&mut self) -> Token<'src> {
-        while Self::is_alpha(self.peek()) || Self::is_digit(self.peek()) {
-            self.advance();
-        }
-
-        self.make_token(self.identifier_kind())
+    #[test]
+    fn error_columns() {
+        let cases = [
+            (
+                "var x = 1 +;",
+                "[line 1:12] Error at ';': Expect expression",
+            ),
+            // tabs and multi-byte chars are one column each
+            (
+                "print \"é\";\n\tvar x = 1 +;",
+                "[line 2:13] Error at ';': Expect expression",
+            ),
+            // multi-line tokens report where they start
+            (
+                "print \"a\nb\" 1;",
+                "[line 2:4] Error at '1': Expect ';' after value.",
+            ),
+            ("print 1 @;", "[line 1:9] Error: Unexpected character."),
+            (
+                "print 1",
+                "[line 1:8] Error at end: Expect ';' after value.",
+            ),
+        ];
+
+        for (src, expected) in cases {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            assert!(!parser.compile());
+            assert_eq!(parser.errors().first().map(String::as_str), Some(expected));
+        }
+    }
+
+    #[test]
+    fn reports_every_error() {
+        // after an error the parser skips to the next statement, so each
+        // statement's error is reported once and nothing in between is
+        let src = "var a = ;\nprint a;\nvar b = 1 +\nassert b;\nprint (;";
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.errors(),
+            [
+                "[line 1:9] Error at ';': Expect expression",
+                "[line 4:1] Error at 'assert': Expect expression",
+                "[line 5:8] Error at ';': Expect expression",
+            ]
+        );
     }
 
-    fn identifier_kind(&self) -> TokenKind {
-        match self.src[self.start] {
-            b'a' => self.check_keyword(1, 2, "nd", TokenKind::And),
-            b'c' => self.check_keyword(1, 4, "lass", TokenKind::Class),
-            b'e' => self.check_keyword(1, 3, "lse", TokenKind::Else),
-            b'f' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
-                b'a' => self.check_keyword(2, 3, "lse", TokenKind::False),
-                b'o' => self.check_keyword(2, 1, "r", TokenKind::For),
-                b'u' => self.check_keyword(2, 1, "n", TokenKind::Fun),
-                _ => TokenKind::Identifier,
-            },
-            b'i' => self.check_keyword(1, 1, "f", TokenKind::If),
-            b'n' => self.check_keyword(1, 2, "il", TokenKind::Nil),
-            b'o' => self.check_keyword(1, 1, "r", TokenKind::Or),
-            b'p' => self.check_keyword(1, 4, "rint", TokenKind::Print),
-            b'r' => self.check_keyword(1, 5, "eturn", TokenKind::Return),
-            b's' => self.check_keyword(1, 4, "uper", TokenKind::Super),
-            b't' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
-                b'h' => self.check_keyword(2, 2, "is", TokenKind::This),
-                b'r' => self.check_keyword(2, 2, "ue", TokenKind::True),
-                _ => TokenKind::Identifier,
-            },
-            b'v' => self.check_keyword(1, 2, "ar", TokenKind::Var),
-            b'w' => self.check_keyword(1, 4, "hile", TokenKind::While),
-            _ => TokenKind::Identifier,
+    #[test]
+    fn jumps_too_long_to_encode() {
+        // `nil;` compiles to 2 bytes, enough of them overflow a 16 bit jump
+        let body = "\nnil;".repeat(u16::MAX as usize / 2 + 1);
+        for (src, expected) in [
+            (
+                format!("var a = 1;\nif (a) {{{body}\n}}"),
+                "[line 2:6] Error at ')': Too much code to jump over.",
+            ),
+            (
+                // each `+ e` is a 2 byte `GetLocal` and an `Add`, a local
+                // past the fourth slot so it isn't a one byte `GetLocal3`
+                format!(
+                    "{{\nvar a; var b; var c; var d; var e = 1;\nprint e and (e{});\n}}",
+                    "\n+ e".repeat(22_000)
+                ),
+                "[line 3:9] Error at 'and': Too much code to jump over.",
+            ),
+            (
+                format!("var a = false;\nwhile (a) {{{body}\n}}"),
+                "[line 2:1] Error at 'while': Loop body too large.",
+            ),
+        ] {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(&src, &mut mem);
+            assert!(!parser.compile());
+            assert_eq!(parser.errors(), [expected]);
         }
     }
 
-    fn check_keyword(&self, start: usize, len: usize, rest: &str, kind: TokenKind) -> TokenKind {
-        if self.current - self.start == start + len
-            && &self.src[(self.start + start)..(self.start + start + len)] == rest.as_bytes()
-        {
-            return kind;
+    #[test]
+    fn return_placement_errors() {
+        for (src, expected) in [
+            (
+                "return;",
+                "[line 1:1] Error at 'return': Can't return from top-level code.",
+            ),
+            (
+                "{ return 1; }",
+                "[line 1:3] Error at 'return': Can't return from top-level code.",
+            ),
+            (
+                "class A { init() { return 1; } }",
+                "[line 1:20] Error at 'return': Can't return a value from an initializer.",
+            ),
+        ] {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            assert!(!parser.compile(), "{src}");
+            assert_eq!(parser.errors(), [expected]);
         }
 
-        TokenKind::Identifier
+        // a bare return from an initializer still returns the instance
+        let src = r#"
+class A {
+    init(early) {
+        this.early = early;
+        if (early) return;
+        this.late = true;
     }
+}
+var a = A(true);
+var b = A(false);
+fun f() { return; }
+var nothing = f();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(eval(&mut vm, "a.early"), Ok(Value::Bool(true)));
+        assert_eq!(eval(&mut vm, "b.late"), Ok(Value::Bool(true)));
+        assert_eq!(eval(&mut vm, "nothing"), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn line_directives() {
+        let mut mem = Mem::new();
+        let src = "var a = 1;\n#line 40 \"gen.lox\"\nvar b = 2;\n#line 7\n  var c = ;";
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.errors(),
+            ["[line 7:11 of gen.lox] Error at ';': Expect expression"]
+        );
 
-    fn string(&mut self) -> Token<'src> {
-        while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1;
+        let src = r#"
+fun fail() {
+#line 20 "lib.lox"
+    return -nil;
+}
+#line 1 "main.lox"
+fail();"#;
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src).unwrap_err();
+        assert_eq!(
+            err,
+            InterpretError::RuntimeError {
+                line: 20,
+                message: "Operand must be a number.".to_string(),
+                frames: vec![
+                    TraceFrame {
+                        function: Some("fail".to_string()),
+                        line: 20,
+                        file: Some("lib.lox".to_string())
+                    },
+                    TraceFrame {
+                        function: None,
+                        line: 1,
+                        file: Some("main.lox".to_string())
+                    },
+                ],
             }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: Operand must be a number.\n[line 20 of lib.lox] in fail()\n[line 1 of main.lox] in script"
+        );
 
-            self.advance();
-        }
-
-        if self.is_at_end() {
-            return self.error_token("Unterminated string.");
-        }
-
-        // closing quote
-        self.advance();
-        self.make_token(TokenKind::String)
+        // file names survive serializing the chunk
+        let chunk = {
+            let mut parser = Parser::new(src, &mut mem);
+            assert!(parser.compile());
+            parser.compiler.function.chunk.to_bytes()
+        };
+        let chunk = Chunk::from_bytes(&chunk, &mut mem).unwrap();
+        // the closure for `fail` is made after its `}` on line 21 of lib.lox
+        assert_eq!(chunk.file_at(0), Some("lib.lox"));
+        assert_eq!(chunk.file_at(chunk.len() - 1), Some("main.lox"));
+
+        for src in [
+            "#line",
+            "#line x",
+            "#line 0",
+            "#line 3 name",
+            "#line 3 \"a\" b",
+            "#lines 3",
+        ] {
+            let tokens: Vec<Token> = tokens(src).collect();
+            assert_eq!(tokens.len(), 1, "{src}");
+            assert_eq!(
+                tokens[0].msg, "Expect '#line N' or '#line N \"name\"'.",
+                "{src}"
+            );
+        }
+        // only at the start of a line
+        let tokens: Vec<Token> = tokens("1 #line 3").collect();
+        assert_eq!(tokens[1].msg, "Unexpected character.");
+    }
+
+    #[test]
+    fn unterminated_string_reports_its_start() {
+        let src = "var a = 1;\nvar s = \"never closed;\nprint a;\nprint s;\nvar b = 2;";
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.errors(),
+            ["[line 2:9] Error: Unterminated string.".to_string()]
+        );
     }
 
-    fn is_digit(c: u8) -> bool {
-        match c {
-            b'0' | b'1' | b'2' | b'3' | b'4' | b'5' | b'6' | b'7' | b'8' | b'9' => true,
-            _ => false,
-        }
+    #[test]
+    fn disassemble() {
+        let src = r#"
+var x = 1 + 2;
+if (x > 2) print x;"#;
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+
+        let listing = parser.compiler.function.chunk.disassemble("script");
+        assert_eq!(
+            listing,
+            r#"== script ==
+0000    2 OP_CONSTANT      Number(3.0)
+0002    | OP_DEFINE_GLOBAL "x"
+0004    3 OP_GET_GLOBAL    "x"
+0006    | OP_CONSTANT      Number(2.0)
+0008    | OP_GREATER
+0009    | OP_JUMP_IF_FALSE 9 -> 19
+0012    | OP_POP
+0013    | OP_GET_GLOBAL    "x"
+0015    | OP_PRINT
+0016    | OP_JUMP          16 -> 20
+0019    | OP_POP
+0020    | OP_NIL
+0021    | OP_RETURN
+"#
+        );
     }
 
-    fn number(&mut self) -> Token<'src> {
-        while Self::is_digit(self.peek()) {
-            self.advance();
-        }
-
-        if self.peek() == b'.' && Self::is_digit(self.peek_next()) {
-            // consume the '.'
-            self.advance();
-
-            while Self::is_digit(self.peek()) {
-                self.advance();
-            }
-        }
+    #[test]
+    fn scope_exit_pops_in_bulk() {
+        let src = r#"{ var a = 1; var b = 2; var c = 3; }
+{ var d = 4; }
+{ var e; var f; fun g() { return e; } var h; }"#;
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+
+        let listing = parser.compiler.function.chunk.disassemble("script");
+        assert_eq!(
+            listing,
+            r#"== script ==
+0000    1 OP_CONSTANT      Number(1.0)
+0002    | OP_CONSTANT      Number(2.0)
+0004    | OP_CONSTANT      Number(3.0)
+0006    | OP_POP_N         3
+0008    2 OP_CONSTANT      Number(4.0)
+0010    | OP_POP
+0011    3 OP_NIL
+0012    | OP_NIL
+0013    | OP_CLOSURE       Function { name: "g", arity: 0 } (local 1)
+0017    | OP_NIL
+0018    | OP_POP_N         3
+0020    | OP_CLOSE_UPVALUE
+0021    | OP_NIL
+0022    | OP_RETURN
+"#
+        );
 
-        self.make_token(TokenKind::Number)
+        let locals: String = (0..200).map(|i| format!("var l{i} = {i};")).collect();
+        let src = format!("var before = 1; {{ {locals} }} var after = before + 1;");
+        let mut vm = VM::new();
+        interpret(&mut vm, &src).unwrap();
+        assert_eq!(unsafe { vm.stack.top.offset_from(vm.stack.stack) }, 1);
+        let after = vm.get_string("after").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(after), Some(Value::Number(2.0)));
     }
 
-    fn matches(&mut self, expected: u8) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-
-        if self.src[self.current] != expected {
-            return false;
-        }
+    #[test]
+    fn chunk_limits() {
+        let few_constants = CompileLimits {
+            max_constants: 3,
+            ..CompileLimits::default()
+        };
+        let little_code = CompileLimits {
+            max_code: 16,
+            ..CompileLimits::default()
+        };
+        for (limits, src, expected) in [
+            (
+                few_constants,
+                "print 1;\nprint 2;\nprint 3;\nprint 4;\nprint 5;",
+                "[line 4:7] Error at '4': Too many constants, the limit is 3.",
+            ),
+            (
+                little_code,
+                // every `print a;` is 3 bytes
+                "var a = 1;\nprint a; print a; print a;\nprint a;\nprint a;",
+                "[line 4:7] Error at 'a': Too much code, the limit is 16 bytes.",
+            ),
+            (
+                // functions get the same limits
+                few_constants,
+                "fun f() {\nreturn \"a\" + \"b\" + \"c\" + \"d\";\n}\nprint f();",
+                "[line 2:26] Error at '\"d\"': Too many constants, the limit is 3.",
+            ),
+        ] {
+            let mut mem = Mem::new();
+            let err = compile_with_limits(src, &mut mem, limits).unwrap_err();
+            // the rest of the source is skipped rather than reporting more
+            assert_eq!(err.errors, [expected], "{src}");
+
+            let mut mem = Mem::new();
+            assert!(Parser::new(src, &mut mem).compile(), "{src}");
+        }
+    }
+
+    #[test]
+    fn nesting_limit() {
+        let nested = |depth| format!("print {}1{};", "(".repeat(depth), ")".repeat(depth));
+        let blocks = |depth| format!("{}print 1;{}", "{".repeat(depth), "}".repeat(depth));
+        let funs = |depth| {
+            format!(
+                "{}return 1;{}",
+                "fun f() {".repeat(depth),
+                "}".repeat(depth)
+            )
+        };
+        for src in [nested(200), blocks(200), funs(100)] {
+            let mut mem = Mem::new();
+            assert!(Parser::new(&src, &mut mem).compile(), "{src}");
+        }
+
+        for (src, expected) in [
+            (nested(300), "Expression nesting too deep."),
+            // deep enough to overflow the stack without the limit
+            (nested(20_000), "Expression nesting too deep."),
+            (blocks(20_000), "Statement nesting too deep."),
+            (funs(300), "Statement nesting too deep."),
+        ] {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(&src, &mut mem);
+            assert!(!parser.compile());
+            let errors = parser.errors();
+            assert_eq!(errors.len(), 1, "{errors:?}");
+            assert!(errors[0].ends_with(expected), "{errors:?}");
+        }
+
+        let shallow = CompileLimits {
+            max_nesting: 5,
+            ..CompileLimits::default()
+        };
+        let mut mem = Mem::new();
+        assert!(compile_with_limits("print (1 + (2));", &mut mem, shallow).is_ok());
+        let err = compile_with_limits("print ((1 + (2)));", &mut mem, shallow).unwrap_err();
+        assert_eq!(
+            err.errors,
+            ["[line 1:13] Error at '(': Expression nesting too deep."]
+        );
+    }
 
-        self.current += 1;
+    #[test]
+    fn compile_returns_the_script_function() {
+        let mut mem = Mem::new();
+        let function = compile("var a = 1;\nprint a + 2;", &mut mem).unwrap();
+        assert!(function.name.is_null());
+        assert_eq!(function.arity, 0);
+        assert_eq!(
+            function.chunk.disassemble("script"),
+            r#"== script ==
+0000    1 OP_CONSTANT      Number(1.0)
+0002    | OP_DEFINE_GLOBAL "a"
+0004    2 OP_GET_GLOBAL    "a"
+0006    | OP_CONSTANT      Number(2.0)
+0008    | OP_ADD
+0009    | OP_PRINT
+0010    | OP_NIL
+0011    | OP_RETURN
+"#
+        );
 
-        true
+        let mut vm = VM::new();
+        let function = compile("var b = 3;", &mut vm.mem).unwrap();
+        vm.init(function);
+        vm.run().unwrap();
+        let b = vm.get_string("b").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(b), Some(Value::Number(3.0)));
+
+        let err = compile("print ;\nvar = 1;\n1 +;", &mut mem).unwrap_err();
+        assert_eq!(err.count(), 3);
+        assert_eq!(err.first(), "[line 1:7] Error at ';': Expect expression");
+        assert_eq!(
+            err.to_string(),
+            "[line 1:7] Error at ';': Expect expression (and 2 more errors)"
+        );
     }
 
-    fn is_at_end(&self) -> bool {
-        self.current == self.src.len()
-        // self.src[self.current] == b'\0'
-    }
+    #[test]
+    fn print_and_expression_statements() {
+        let src = r#"print 1 + 2; print "hi"; 3;"#;
+        let mut mem = Mem::new();
+        let mut parser = Parser::new(src, &mut mem);
+        assert!(parser.compile());
+
+        let listing = parser.compiler.function.chunk.disassemble("script");
+        assert_eq!(
+            listing,
+            r#"== script ==
+0000    1 OP_CONSTANT      Number(3.0)
+0002    | OP_PRINT
+0003    | OP_CONSTANT      "hi"
+0005    | OP_PRINT
+0006    | OP_CONSTANT      Number(3.0)
+0008    | OP_POP
+0009    | OP_NIL
+0010    | OP_RETURN
+"#
+        );
 
-    fn make_token(&self, kind: TokenKind) -> Token<'src> {
-        Token {
-            kind,
-            // Safety:
-            // The input is guaranteed to be valid utf8 so this is safe
-            msg: unsafe { std::str::from_utf8_unchecked(&self.src[self.start..self.current]) },
-            line: self.line as u32,
-        }
-    }
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+    }
+
+    #[test]
+    fn constant_folding() {
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("2 + 3;", &mut mem);
+        assert!(parser.compile());
+        let chunk = &parser.compiler.function.chunk;
+        assert_eq!(
+            chunk.disassemble("script"),
+            r#"== script ==
+0000    1 OP_CONSTANT      Number(5.0)
+0002    | OP_POP
+0003    | OP_NIL
+0004    | OP_RETURN
+"#
+        );
+        assert_eq!(chunk.constants.len(), 1);
+
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("print 2 + 3 * 4 - 6 / 3; print 1 / 0;", &mut mem);
+        assert!(parser.compile());
+        assert_eq!(
+            parser.compiler.function.chunk.disassemble("script"),
+            r#"== script ==
+0000    1 OP_CONSTANT      Number(12.0)
+0002    | OP_PRINT
+0003    | OP_CONSTANT      Number(1.0)
+0005    | OP_CONSTANT      Number(0.0)
+0007    | OP_DIVIDE
+0008    | OP_PRINT
+0009    | OP_NIL
+0010    | OP_RETURN
+"#
+        );
 
-    fn error_token(&self, err: &'src str) -> Token<'src> {
-        Token {
-            kind: TokenKind::Error,
-            msg: err,
-            line: self.line as u32,
-        }
-    }
+        // Operands that aren't both number literals are left alone, the jump
+        // over `2` lands right before `+` so this must not fold to 5
+        let src = r#"
+        var x = false;
+        var a = (x and 2) + 3;"#;
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        assert!(matches!(err, Err(InterpretError::RuntimeError { .. })));
+
+        let mut vm = VM::new();
+        let src = r#"
+        var x = 4;
+        var a = (x and 2) + 3;
+        var b = -2 + 3;
+        var c = x * 2 + 1;"#;
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [("a", 5.0), ("b", 1.0), ("c", 9.0)] {
+            let name_str = vm.get_string(name).as_non_null_ptr();
+            let value = vm.mem.globals.get(name_str);
+            assert_eq!(value, Some(Value::Number(expected)), "{name}");
+        }
+    }
+
+    #[test]
+    fn ohshit() {
+        // let bytes = [0, 1, 2, 3];
+
+        println!("NOOB: {:?}", std::mem::size_of::<Token>());
+
+        let values = [0, 1, 2, 3, 4, 5];
+        println!(
+            "NICE: {:?}",
+            values.iter().take(3).rev().collect::<Vec<_>>()
+        );
+
+        // 0
+        // 1
+        // 2 ---
+        // 3 ---
+        // 4
+        // 5
+        //
+        // 6
+        // -2 to adjust for the 2 bytes for the jump offset
+        let mut chunk = [0, 1, 2, 3, 4, 5];
+        let offset = 2;
+        let jump = chunk.len() as u32 - offset - 2;
+
+        chunk[offset as usize] = (jump >> 8) as u8;
+        chunk[offset as usize + 1] = jump as u8;
+
+        let val = ((chunk[offset as usize] as u16) << 8) | (chunk[offset as usize + 1] as u16);
+
+        println!("{jump} NOOB: {chunk:?} JUMP: {val} {}", 2u16);
+    }
+
+    // #[test]
+    // fn miri_test() {
+    //     let mut obj = Box::into_raw(Box::new(69));
+    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
+    //     let foo = unsafe { *obj };
+    //     *obj2 = 9999;
+    // }
+    // #[test]
+    // fn miri_test2() {
+    //     let mut obj = Box::into_raw(Box::new(69));
+    //     let mut obj2 = unsafe { obj.as_mut().unwrap() };
+    //     unsafe {
+    //         *obj = 420;
+    //     };
+    //     *obj2 = 9999;
+    // }
 }
@@ -0,0 +1,105 @@
+use std::ptr::NonNull;
+
+use crate::{
+    mem::{Gc, Greystack},
+    obj::{Obj, ObjString},
+    value::Value,
+};
+
+/// Marks an `ObjString` that hasn't been given a global slot
+pub const NO_SLOT: u32 = u32::MAX;
+
+/// Global variables, stored in slots instead of a hash table.
+///
+/// A name's slot is cached on its interned `ObjString`, so once a name has
+/// one, getting or setting the global is an index instead of a lookup. The
+/// compiler hands out slots for the globals a script uses, anything compiled
+/// elsewhere (e.g. deserialized bytecode) gets one the first time it runs.
+/// Slots are never reused, an undefined global's slot just holds `None`.
+#[derive(Default)]
+pub struct Globals {
+    /// Keeps every name with a slot alive, a collected and re-interned name
+    /// would lose its cached slot
+    names: Vec<Gc<ObjString>>,
+    values: Vec<Option<Value>>,
+    /// Which slots were declared with `const`, the compiler rejects
+    /// assigning to them
+    consts: Vec<bool>,
+}
+
+impl Globals {
+    pub fn new() -> Self {
+        Self {
+            names: vec![],
+            values: vec![],
+            consts: vec![],
+        }
+    }
+
+    /// The slot for `name`, handing out a new (undefined) one if it has none
+    #[inline]
+    pub fn slot(&mut self, name: Gc<ObjString>) -> u32 {
+        let slot = unsafe { (*name.as_ptr()).global_slot };
+        if slot != NO_SLOT {
+            return slot;
+        }
+
+        let slot = self.values.len() as u32;
+        unsafe { (*name.as_ptr()).global_slot = slot };
+        self.names.push(name);
+        self.values.push(None);
+        self.consts.push(false);
+        slot
+    }
+
+    /// The value in `slot`, `None` if that global hasn't been defined
+    #[inline]
+    pub fn get_slot(&self, slot: u32) -> Option<Value> {
+        self.values[slot as usize]
+    }
+
+    /// Only for slots handed out by `slot`
+    #[inline]
+    pub fn set_slot(&mut self, slot: u32, value: Option<Value>) {
+        self.values[slot as usize] = value;
+    }
+
+    #[inline]
+    pub fn is_const(&self, slot: u32) -> bool {
+        self.consts[slot as usize]
+    }
+
+    pub fn set_const(&mut self, slot: u32, is_const: bool) {
+        self.consts[slot as usize] = is_const;
+    }
+
+    pub fn get(&self, name: NonNull<ObjString>) -> Option<Value> {
+        let slot = unsafe { name.as_ref() }.global_slot;
+        if slot == NO_SLOT {
+            return None;
+        }
+        self.get_slot(slot)
+    }
+
+    pub fn set(&mut self, name: NonNull<ObjString>, value: Value) {
+        let slot = self.slot(Gc::new(name));
+        self.set_slot(slot, Some(value));
+    }
+
+    /// Every defined global, in the order their slots were handed out
+    pub fn iter(&self) -> impl Iterator<Item = (Gc<ObjString>, Value)> + '_ {
+        self.names
+            .iter()
+            .zip(&self.values)
+            .filter_map(|(name, value)| value.map(|value| (*name, value)))
+    }
+
+    pub fn mark(&self, greystack: &mut Greystack) {
+        for (name, value) in self.names.iter().zip(&self.values) {
+            Obj::mark(name.as_ptr().cast(), greystack);
+            if let Some(value) = value {
+                value.mark(greystack);
+            }
+        }
+    }
+}
@@ -0,0 +1,379 @@
+#![feature(ptr_sub_ptr)]
+#![feature(allocator_api)]
+#![feature(slice_ptr_get)]
+#![feature(let_chains)]
+
+mod chunk;
+mod compile;
+mod globals;
+mod mem;
+mod native_fn;
+mod obj;
+mod scanner;
+mod table;
+mod value;
+mod vm;
+
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::{
+    io::{BufRead, Write},
+    time::{Duration, Instant},
+};
+
+use compile::Parser;
+use mem::Gc;
+use obj::ObjString;
+
+pub use chunk::{Chunk, Opcode};
+pub use compile::{compile, compile_with_limits, CompileError, CompileLimits, Compiler};
+pub use mem::Mem;
+pub use native_fn::NativeFn;
+pub use scanner::{tokens, Token, TokenKind};
+pub use value::Value;
+pub use vm::{DivByZero, InterpretError, InterpretResult, TraceFrame, VM};
+
+#[macro_export]
+macro_rules! debug_println {
+    () => {
+        #[cfg(debug_assertions)]
+        $std::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        #[cfg(debug_assertions)]
+        println!($($arg)*);
+    };
+}
+
+/// Interprets each line of `input` against the same VM, only stopping at EOF.
+/// A line that's just an expression with no `;` has its value printed.
+/// Unless the VM was given its own `input`, `readLine` reads the lines after
+/// it from `input` too, two readers of stdin would deadlock
+pub fn run_repl<R: BufRead + 'static>(vm: &mut VM, input: R) {
+    let mut own_input = match vm.input {
+        Some(_) => Some(input),
+        None => {
+            vm.input = Some(Box::new(input));
+            None
+        }
+    };
+
+    loop {
+        let mut line = String::new();
+        let read = match &mut own_input {
+            Some(input) => input.read_line(&mut line),
+            // `:reset` keeps the VM's input
+            None => vm.input.as_mut().unwrap().read_line(&mut line),
+        };
+        match read {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(err) => {
+                eprintln!("Failed to read line: {err}");
+                break;
+            }
+        }
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+
+        if let Some(command) = line.trim().strip_prefix(':') {
+            match command {
+                "globals" => print!("{}", format_globals(vm)),
+                "reset" => vm.clear(),
+                _ => eprintln!(
+                    "Unknown command ':{command}'. Commands: :globals, :reset. \
+                     An expression without a trailing ';' prints its value."
+                ),
+            }
+            continue;
+        }
+
+        // The compiler has already reported its errors to stderr, a runtime
+        // error also resets the stack so the next line starts clean
+        match eval_repl_line(vm, line) {
+            Ok(Some(value)) => {
+                if let Err(err) = writeln!(vm.output, "{value}") {
+                    eprintln!("Failed to print: {err}");
+                }
+            }
+            Ok(None) => (),
+            Err(InterpretError::CompileError) => eprintln!("Compile error, try again."),
+            Err(err @ InterpretError::RuntimeError { .. }) => {
+                eprintln!("{err}");
+                eprintln!("Runtime error, try again.");
+            }
+        }
+    }
+
+    if own_input.is_none() {
+        vm.input = None;
+    }
+}
+
+/// Runs a REPL line, returning its value if it was a bare expression to echo
+fn eval_repl_line(vm: &mut VM, src: &str) -> InterpretResult<Option<Value>> {
+    let (function, echo) = {
+        let mut parser = Parser::new(src, &mut vm.mem);
+        if !parser.compile_trailing_expr() {
+            return Err(InterpretError::CompileError);
+        }
+        (parser.compiler.function, parser.ends_with_bare_expr())
+    };
+    vm.init(function);
+
+    let value = vm.run()?;
+    Ok(echo.then_some(value))
+}
+
+/// Lists every global defined on `vm` as `name = value` lines, sorted by name
+pub fn format_globals(vm: &VM) -> String {
+    let mut globals: Vec<(Gc<ObjString>, Value)> = vm.mem.globals.iter().collect();
+    globals.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+
+    globals
+        .into_iter()
+        .map(|(name, value)| format!("{} = {value}\n", name.as_str()))
+        .collect()
+}
+
+/// Compiles `src` and disassembles the script along with every function
+/// nested in it, without running anything
+pub fn dump_bytecode(src: &str) -> InterpretResult<String> {
+    let mut mem = Mem::new();
+    let function = compile(src, &mut mem).map_err(|_| InterpretError::CompileError)?;
+    Ok(function.disassemble())
+}
+
+/// Compiles and runs `src` on `vm`, discarding the script's result.
+pub fn interpret(vm: &mut VM, src: &str) -> InterpretResult<()> {
+    let function = compile(src, &mut vm.mem).map_err(|_| InterpretError::CompileError)?;
+    vm.init(function);
+
+    vm.run().map(|_| ())
+}
+
+/// Wall-clock time `interpret_timed` spent in each phase
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    pub compile: Duration,
+    pub run: Duration,
+}
+
+/// Like [`interpret`], also measuring how long compiling and running took.
+/// When the script fails the timings cover everything up to the error, and
+/// `run` is zero if it didn't compile.
+pub fn interpret_timed(vm: &mut VM, src: &str) -> (InterpretResult<()>, Timings) {
+    let mut timings = Timings::default();
+
+    let start = Instant::now();
+    let compiled = compile(src, &mut vm.mem);
+    timings.compile = start.elapsed();
+    let function = match compiled {
+        Ok(function) => function,
+        Err(_) => return (Err(InterpretError::CompileError), timings),
+    };
+    vm.init(function);
+
+    let start = Instant::now();
+    let result = vm.run().map(|_| ());
+    timings.run = start.elapsed();
+    (result, timings)
+}
+
+/// Compiles and runs `src` on `vm`, returning the value of a trailing
+/// top-level expression statement (the `;` is optional), or `nil` if the
+/// script doesn't end with one.
+///
+/// The `vm` is taken by reference because object values point into its heap,
+/// they're valid until the next `eval`/`interpret` on the same `vm` (which may
+/// collect them) or until the `vm` is dropped.
+pub fn eval(vm: &mut VM, src: &str) -> InterpretResult<Value> {
+    let function = {
+        let mut parser = Parser::new(src, &mut vm.mem);
+        if !parser.compile_trailing_expr() {
+            return Err(InterpretError::CompileError);
+        }
+        parser.compiler.function
+    };
+    vm.init(function);
+
+    vm.run()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::Operand,
+        common::SharedBuf,
+        dump_bytecode, eval, format_globals, interpret, run_repl,
+        value::Value,
+        vm::{InterpretError, VM},
+    };
+
+    #[test]
+    fn print_to_output_sink() {
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(
+            &mut vm,
+            r#"print 1 + 2; print "hi"; print nil; print true;"#,
+        )
+        .unwrap();
+        assert_eq!(output.contents(), "3\nhi\nnil\ntrue\n");
+
+        // The REPL's :reset keeps the sink
+        run_repl(&mut vm, ":reset\nprint 4;\n".as_bytes());
+        assert_eq!(output.contents(), "3\nhi\nnil\ntrue\n4\n");
+    }
+
+    #[test]
+    fn repl_continues_after_error() {
+        let input = "var result = ;\nvar result = 420;\n";
+
+        let mut vm = VM::new();
+        run_repl(&mut vm, input.as_bytes());
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn repl_continues_after_runtime_error() {
+        let input = "var result = -nil;\nvar result = 420;\n";
+
+        let mut vm = VM::new();
+        run_repl(&mut vm, input.as_bytes());
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn repl_keeps_globals_between_lines() {
+        let input = "var a = 1;\nfun add(x) { return x + a; }\nvar result = add(41);\n";
+
+        let mut vm = VM::new();
+        // Make every later line collect, so the earlier lines' objects only
+        // survive by being reachable from the globals
+        vm.mem.next_gc = 0;
+        run_repl(&mut vm, input.as_bytes());
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(result_str), Some(Value::Number(42.0)));
+    }
+
+    #[test]
+    fn repl_echoes_bare_expressions() {
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        let input = "1 + 2\nvar x = 1;\nx\nx;\nprint x + 1;\nx = 5\nfun f() {}\nf()\nnil\n";
+        run_repl(&mut vm, input.as_bytes());
+        assert_eq!(output.contents(), "3\n1\n2\n5\nnil\nnil\n");
+    }
+
+    #[test]
+    fn repl_read_line_reads_the_next_lines() {
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        let input = "var a = readLine();\nfirst\nprint a;\n:reset\nprint readLine();\nlast\r\n";
+        run_repl(&mut vm, input.as_bytes());
+        assert_eq!(output.contents(), "first\nlast\n");
+        assert!(vm.input.is_none());
+
+        // a VM with its own input keeps reading from it
+        vm.input = Some(Box::new(std::io::Cursor::new("own\n")));
+        run_repl(&mut vm, "print readLine();\nprint readLine();\n".as_bytes());
+        assert_eq!(output.contents(), "first\nlast\nown\nnil\n");
+    }
+
+    #[test]
+    fn repl_meta_commands() {
+        let mut vm = VM::new();
+        run_repl(
+            &mut vm,
+            "var b = \"two\";\nvar a = 1;\n:globals\n".as_bytes(),
+        );
+        let listing = format_globals(&vm);
+        assert!(listing.contains("a = 1\nb = two\n"), "{listing}");
+
+        // Unknown commands aren't compiled as source, so this doesn't error out
+        run_repl(&mut vm, ":nope\n:reset\nvar c = 3;\n".as_bytes());
+        let a_str = vm.get_string("a").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(a_str), None);
+        let c_str = vm.get_string("c").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(c_str), Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn eval_trailing_expr() {
+        let mut vm = VM::new();
+        assert_eq!(eval(&mut vm, "1 + 2"), Ok(Value::Number(3.0)));
+        assert_eq!(eval(&mut vm, "var a = 1; a + 2;"), Ok(Value::Number(3.0)));
+        assert_eq!(eval(&mut vm, "var b = 1;"), Ok(Value::Nil));
+        assert_eq!(eval(&mut vm, "1 + 2; print 3;"), Ok(Value::Nil));
+        // only the script's own top level counts
+        assert_eq!(eval(&mut vm, "{ 1 + 2; }"), Ok(Value::Nil));
+
+        let value = eval(&mut vm, r#""foo" + "bar""#).unwrap();
+        assert_eq!(value.to_string(), "foobar");
+    }
+
+    #[test]
+    fn eval_errors() {
+        let mut vm = VM::new();
+        assert_eq!(eval(&mut vm, "1 +"), Err(InterpretError::CompileError));
+        assert_eq!(
+            eval(&mut vm, "{ 1 + 2 }"),
+            Err(InterpretError::CompileError)
+        );
+        assert_eq!(
+            eval(&mut vm, "-nil").unwrap_err().to_string(),
+            "Runtime error: Operand must be a number.\n[line 1] in script"
+        );
+        // the trailing expression is opt-in, `interpret` still wants the `;`
+        assert_eq!(
+            interpret(&mut vm, "1 + 2"),
+            Err(InterpretError::CompileError)
+        );
+    }
+
+    #[test]
+    fn dump_nested_bytecode() {
+        let src = r#"fun outer() {
+  fun inner() {}
+}
+class A {
+  init() {}
+}"#;
+        assert_eq!(
+            dump_bytecode(src).unwrap(),
+            r#"== script ==
+0000    3 OP_CLOSURE       Function { name: "outer", arity: 0 }
+0002    | OP_DEFINE_GLOBAL "outer"
+0004    4 OP_CLASS         "A"
+0006    | OP_DEFINE_GLOBAL "A"
+0008    | OP_GET_GLOBAL    "A"
+0010    5 OP_CLOSURE       Function { name: "init", arity: 0 }
+0012    | OP_METHOD        "init"
+0014    6 OP_POP
+0015    | OP_NIL
+0016    | OP_RETURN
+
+== outer ==
+0000    2 OP_CLOSURE       Function { name: "inner", arity: 0 }
+0002    3 OP_NIL
+0003    | OP_RETURN
+
+== inner ==
+0000    2 OP_NIL
+0001    | OP_RETURN
+
+== init ==
+0000    5 OP_GET_LOCAL_0
+0001    | OP_RETURN
+"#
+        );
+        assert_eq!(dump_bytecode("print ;"), Err(InterpretError::CompileError));
+    }
+}
@@ -5,7 +5,8 @@ use std::{
 };
 
 use crate::{
-    obj::{Obj, ObjList, ObjPunnable, ObjString},
+    globals::Globals,
+    obj::{Obj, ObjPunnable, ObjString, Objects},
     table::{ObjHash, Table},
     value::Value,
 };
@@ -44,8 +45,8 @@ pub type Greystack = Vec<NonNull<Obj>>;
 // pub static GLOBAL: GlobalAllocator = GlobalAllocator { bytes_allocated: 0 };
 
 pub struct Mem {
-    pub obj_list: ObjList,
-    pub globals: Table,
+    pub obj_list: Objects,
+    pub globals: Globals,
     pub interned_strings: Table,
     pub next_gc: usize,
     pub bytes_allocated: usize,
@@ -55,7 +56,7 @@ impl Mem {
     pub fn new() -> Self {
         Self {
             obj_list: Default::default(),
-            globals: Table::new(),
+            globals: Globals::new(),
             interned_strings: Table::new(),
             next_gc: 1024 * 1024,
             bytes_allocated: 0,
@@ -109,6 +110,9 @@ impl Mem {
         self.interned_strings.set(obj_string, Value::Nil);
     }
 
+    /// Returns the interned string with these contents, allocating it only if
+    /// it doesn't exist yet. All strings are interned so they can be compared
+    /// and used as table keys by pointer.
     pub fn copy_string(&mut self, string: &str) -> Gc<ObjString> {
         let hash = ObjHash::hash_string(string);
         match self.interned_strings.find_string(string, hash) {
@@ -143,11 +147,10 @@ impl Drop for Mem {
     fn drop(&mut self) {
         // free obj list
         for obj in self.obj_list.iter_mut() {
-            Obj::free(obj.as_non_null_ptr())
+            Obj::free(obj.as_non_null_ptr());
         }
 
         Table::free(&mut self.interned_strings);
-        Table::free(&mut self.globals);
     }
 }
 
@@ -182,6 +185,13 @@ impl<T> std::fmt::Debug for Gc<T> {
         f.debug_struct("Gc").field("inner", &self.inner).finish()
     }
 }
+/// Identity, two `Gc`s are equal when they point to the same object
+impl<T> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+impl<T> Eq for Gc<T> {}
 impl<T> Copy for Gc<T> {}
 impl<T> Clone for Gc<T> {
     fn clone(&self) -> Self {
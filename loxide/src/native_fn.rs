@@ -1,43 +1,42 @@
-use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::value::Value;
+use crate::{
+    value::Value,
+    vm::{InterpretResult, VM},
+};
 
-pub type NativeFn = fn(&[Value]) -> Value;
+/// A function Lox calls natively. It gets the VM, to allocate its result or
+/// report a `VM::runtime_error`, and the call's arguments
+pub type NativeFn = fn(&mut VM, &[Value]) -> InterpretResult<Value>;
 
-#[derive(Clone, Copy)]
-pub enum NativeFnKind {
-    Clock,
-    Dummy,
-    Custom(NativeFn),
-}
+/// SplitMix64, small and fast with no dependency, and good enough for
+/// scripts (it's not for cryptography)
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
 
-impl Debug for NativeFnKind {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Clock => write!(f, "Clock"),
-            Self::Dummy => write!(f, "Dummy"),
-            Self::Custom(arg0) => {
-                let fn_pointer: *const NativeFn = arg0;
-                f.debug_tuple("Custom").field(&fn_pointer).finish()
-            }
-        }
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
     }
-}
 
-impl NativeFnKind {
-    pub fn call(&self, values: &[Value]) -> Value {
-        match self {
-            NativeFnKind::Clock => Self::call_clock(values),
-            NativeFnKind::Dummy => Self::call_dummy(values),
-            NativeFnKind::Custom(native_fn) => native_fn(values),
-        }
+    /// Seeded from the time, so every run differs
+    pub fn from_time() -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self::new(now.as_nanos() as u64)
     }
 
-    fn call_clock(_values: &[Value]) -> Value {
-        Value::Number(420.0)
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
     }
 
-    fn call_dummy(_values: &[Value]) -> Value {
-        Value::Number(420.0)
+    /// In [0, 1), from the top 53 bits so every value is equally likely
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
     }
 }
@@ -1,19 +1,22 @@
 use std::{
     alloc::{self, Layout},
-    collections::VecDeque,
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
     ptr::NonNull,
     slice,
 };
 
 use crate::{
     chunk::Chunk,
+    globals::NO_SLOT,
     mem::{Gc, Greystack},
-    native_fn::NativeFnKind,
+    native_fn::NativeFn,
     table::{ObjHash, Table},
     value::Value,
 };
 
-pub type ObjList = VecDeque<Gc<Obj>>;
+/// Every object the heap has allocated
+pub type Objects = VecDeque<Gc<Obj>>;
 
 /// This is to enable type-safe functions generic over types that are type punnable to Obj
 pub trait ObjPunnable: Sized {
@@ -65,6 +68,16 @@ impl ObjPunnable for ObjBoundMethod {
         ObjKind::BoundMethod
     }
 }
+impl ObjPunnable for ObjList {
+    fn kind(&self) -> ObjKind {
+        ObjKind::List
+    }
+}
+impl ObjPunnable for ObjMap {
+    fn kind(&self) -> ObjKind {
+        ObjKind::Map
+    }
+}
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -77,6 +90,8 @@ pub enum ObjKind {
     Class,
     Instance,
     BoundMethod,
+    List,
+    Map,
 }
 
 #[repr(C)]
@@ -90,7 +105,7 @@ pub struct ObjPtrWrapper(pub *mut Obj);
 #[repr(C)]
 pub struct ObjNative {
     pub obj: Obj,
-    pub function: NativeFnKind,
+    pub function: NativeFn,
 }
 
 #[repr(C)]
@@ -131,6 +146,29 @@ pub struct ObjInstance {
     pub fields: Table,
 }
 
+#[repr(C)]
+pub struct ObjList {
+    pub obj: Obj,
+    pub items: Vec<Value>,
+}
+
+/// Maps keep their entries in insertion order, `index` finds a key's entry
+#[repr(C)]
+pub struct ObjMap {
+    pub obj: Obj,
+    index: HashMap<MapKey, usize>,
+    pub entries: Vec<(MapKey, Value)>,
+}
+
+/// What a map can be keyed by. Strings are interned, so comparing them by
+/// pointer is comparing their contents
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Str(*mut ObjString),
+    /// The bits of a number that isn't NaN, with -0 as 0
+    Number(u64),
+}
+
 #[repr(C)]
 pub struct ObjFunction {
     pub obj: Obj,
@@ -146,6 +184,8 @@ pub struct ObjString {
     pub len: u32,
     pub hash: ObjHash,
     pub chars: NonNull<u8>,
+    /// This name's slot in `Mem::globals`, or `globals::NO_SLOT`
+    pub global_slot: u32,
 }
 
 impl Obj {
@@ -192,6 +232,17 @@ impl Obj {
                 (*bound).receiver.mark(greystack);
                 Obj::mark((*bound).method.as_ptr() as *mut _, greystack);
             }
+            ObjKind::List => {
+                for item in obj.cast::<ObjList>().as_ref().items.iter() {
+                    item.mark(greystack);
+                }
+            }
+            ObjKind::Map => {
+                for (key, value) in obj.cast::<ObjMap>().as_ref().entries.iter() {
+                    key.to_value().mark(greystack);
+                    value.mark(greystack);
+                }
+            }
         }
     }
 
@@ -221,7 +272,9 @@ impl Obj {
         greystack.push(unsafe { NonNull::new_unchecked(obj.cast()) });
     }
 
-    pub fn free(obj_nonnull: NonNull<Obj>) {
+    /// Frees the object and returns how many bytes were freed, counted the
+    /// same way `Mem::alloc_obj` counts them
+    pub fn free(obj_nonnull: NonNull<Obj>) -> usize {
         unsafe {
             let obj = obj_nonnull.as_ptr();
             let kind = (*obj).kind;
@@ -248,12 +301,15 @@ impl Obj {
                     }
 
                     let _ = Box::from_raw(obj as *mut ObjString);
+                    std::mem::size_of::<ObjString>()
                 }
                 ObjKind::Fn => {
                     let _ = Box::from_raw(obj as *mut ObjFunction);
+                    std::mem::size_of::<ObjFunction>()
                 }
                 ObjKind::Native => {
                     let _ = Box::from_raw(obj as *mut ObjNative);
+                    std::mem::size_of::<ObjNative>()
                 }
                 ObjKind::Closure => {
                     let upvalues = (*obj.cast::<ObjClosure>()).upvalues;
@@ -269,21 +325,34 @@ impl Obj {
                     }
 
                     let _ = Box::from_raw(obj as *mut ObjClosure);
+                    std::mem::size_of::<ObjClosure>()
                 }
                 ObjKind::Upvalue => {
                     let _ = Box::from_raw(obj as *mut ObjUpvalue);
+                    std::mem::size_of::<ObjUpvalue>()
                 }
                 ObjKind::Class => {
                     let mut obj = Box::from_raw(obj as *mut ObjClass);
                     Table::free(&mut obj.methods);
+                    std::mem::size_of::<ObjClass>()
                 }
                 ObjKind::Instance => {
                     Table::free(&mut (*(obj as *mut ObjInstance)).fields);
 
                     let _ = Box::from_raw(obj as *mut ObjInstance);
+                    std::mem::size_of::<ObjInstance>()
                 }
                 ObjKind::BoundMethod => {
                     let _ = Box::from_raw(obj as *mut ObjBoundMethod);
+                    std::mem::size_of::<ObjBoundMethod>()
+                }
+                ObjKind::List => {
+                    let _ = Box::from_raw(obj as *mut ObjList);
+                    std::mem::size_of::<ObjList>()
+                }
+                ObjKind::Map => {
+                    let _ = Box::from_raw(obj as *mut ObjMap);
+                    std::mem::size_of::<ObjMap>()
                 }
             }
         }
@@ -313,7 +382,9 @@ impl std::fmt::Debug for ObjPtrWrapper {
             },
             ObjKind::Native => {
                 let function = unsafe { ptr.cast::<ObjNative>().as_ref().function };
-                write!(f, "{function:?}")
+                f.debug_tuple("Native")
+                    .field(&(function as *const ()))
+                    .finish()
             }
             ObjKind::Closure => unsafe {
                 let ptr: NonNull<ObjClosure> = ptr.cast();
@@ -366,18 +437,127 @@ impl std::fmt::Debug for ObjPtrWrapper {
                     .field("name", &ObjPtrWrapper(name.cast::<Obj>()))
                     .finish()
             },
+            ObjKind::List => format_once(self.0, "[...]", f, |f| {
+                let list = unsafe { ptr.cast::<ObjList>().as_ref() };
+                f.debug_list().entries(&list.items).finish()
+            }),
+            ObjKind::Map => format_once(self.0, "{...}", f, |f| {
+                let map = unsafe { ptr.cast::<ObjMap>().as_ref() };
+                f.debug_map()
+                    .entries(
+                        map.entries
+                            .iter()
+                            .map(|(key, value)| (key.to_value(), value)),
+                    )
+                    .finish()
+            }),
+        }
+    }
+}
+
+thread_local! {
+    /// Lists and maps being formatted further up the stack, so one that
+    /// contains itself is written as `[...]` or `{...}` instead of recursing
+    /// until it overflows
+    static FORMATTING: RefCell<Vec<*mut Obj>> = RefCell::new(Vec::new());
+}
+
+/// Formats a container with `fmt`, or writes `placeholder` if `ptr` is
+/// already being formatted, i.e. it's reachable from itself
+fn format_once(
+    ptr: *mut Obj,
+    placeholder: &str,
+    f: &mut std::fmt::Formatter<'_>,
+    fmt: impl FnOnce(&mut std::fmt::Formatter<'_>) -> std::fmt::Result,
+) -> std::fmt::Result {
+    if FORMATTING.with(|seen| seen.borrow().contains(&ptr)) {
+        return write!(f, "{placeholder}");
+    }
+
+    FORMATTING.with(|seen| seen.borrow_mut().push(ptr));
+    let result = fmt(f);
+    FORMATTING.with(|seen| seen.borrow_mut().pop());
+    result
+}
+
+/// How `print` shows objects, following clox: strings are their contents,
+/// functions are `<fn name>`, classes their name and instances `Name instance`
+impl std::fmt::Display for ObjPtrWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ptr = match NonNull::new(self.0) {
+            Some(ptr) => ptr,
+            None => return write!(f, "nil"),
+        };
+
+        let kind = unsafe { ptr.as_ref().kind };
+        match kind {
+            ObjKind::Str => write!(
+                f,
+                "{}",
+                unsafe { ptr.cast::<ObjString>().as_ref() }.as_str()
+            ),
+            ObjKind::Fn => match unsafe { ptr.cast::<ObjFunction>().as_ref().name.as_ref() } {
+                Some(name) => write!(f, "<fn {}>", name.as_str()),
+                None => write!(f, "<script>"),
+            },
+            ObjKind::Native => write!(f, "<native fn>"),
+            ObjKind::Closure => {
+                let function = unsafe { ptr.cast::<ObjClosure>().as_ref() }.function;
+                write!(f, "{}", ObjPtrWrapper(function.as_ptr().cast()))
+            }
+            ObjKind::Upvalue => write!(f, "upvalue"),
+            ObjKind::Class => {
+                let name = unsafe { ptr.cast::<ObjClass>().as_ref() }.name;
+                write!(f, "{}", ObjPtrWrapper(name.as_ptr().cast()))
+            }
+            ObjKind::Instance => {
+                let class = unsafe { ptr.cast::<ObjInstance>().as_ref() }.class;
+                write!(f, "{} instance", ObjPtrWrapper(class.as_ptr().cast()))
+            }
+            ObjKind::BoundMethod => {
+                let method = unsafe { ptr.cast::<ObjBoundMethod>().as_ref() }.method;
+                write!(f, "{}", ObjPtrWrapper(method.as_ptr().cast()))
+            }
+            ObjKind::List => format_once(self.0, "[...]", f, |f| {
+                write!(f, "[")?;
+                for (i, item) in unsafe { ptr.cast::<ObjList>().as_ref() }
+                    .items
+                    .iter()
+                    .enumerate()
+                {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }),
+            ObjKind::Map => format_once(self.0, "{...}", f, |f| {
+                write!(f, "{{")?;
+                for (i, (key, value)) in unsafe { ptr.cast::<ObjMap>().as_ref() }
+                    .entries
+                    .iter()
+                    .enumerate()
+                {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {value}", key.to_value())?;
+                }
+                write!(f, "}}")
+            }),
         }
     }
 }
 
 impl ObjNative {
-    pub fn new(kind: NativeFnKind) -> Self {
+    pub fn new(function: NativeFn) -> Self {
         Self {
             obj: Obj {
                 kind: ObjKind::Native,
                 is_marked: false,
             },
-            function: kind,
+            function,
         }
     }
 }
@@ -460,6 +640,74 @@ impl ObjInstance {
     }
 }
 
+impl ObjList {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::List,
+                is_marked: false,
+            },
+            items,
+        }
+    }
+}
+
+impl ObjMap {
+    pub fn new() -> Self {
+        Self {
+            obj: Obj {
+                kind: ObjKind::Map,
+                is_marked: false,
+            },
+            index: HashMap::new(),
+            entries: vec![],
+        }
+    }
+
+    pub fn get(&self, key: MapKey) -> Option<Value> {
+        self.index.get(&key).map(|&i| self.entries[i].1)
+    }
+
+    pub fn insert(&mut self, key: MapKey, value: Value) {
+        match self.index.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.index.insert(key, self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+}
+
+impl Default for ObjMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MapKey {
+    /// `None` if `value` can't be a map key
+    pub fn from_value(value: Value) -> Option<Self> {
+        match value {
+            Value::Number(num) if !num.is_nan() => {
+                let num = if num == 0.0 { 0.0f64 } else { num };
+                Some(MapKey::Number(num.to_bits()))
+            }
+            Value::Obj(_) => value
+                .as_obj_str()
+                .map(|string| MapKey::Str(string.as_ptr())),
+            _ => None,
+        }
+    }
+
+    pub fn to_value(self) -> Value {
+        match self {
+            MapKey::Str(string) => Value::Obj(Gc::new(NonNull::new(string).unwrap()).cast()),
+            MapKey::Number(bits) => Value::Number(f64::from_bits(bits)),
+        }
+    }
+}
+
 impl ObjBoundMethod {
     pub fn new(receiver: Value, method: Gc<ObjClosure>) -> Self {
         Self {
@@ -488,6 +736,22 @@ impl ObjFunction {
     }
 }
 
+impl ObjFunction {
+    /// Disassembly of this function's chunk followed by the chunks of the
+    /// functions nested in it, each headed by the function's name
+    pub fn disassemble(&self) -> String {
+        let name = unsafe { self.name.as_ref() }.map_or("script", |name| name.as_str());
+        let mut out = self.chunk.disassemble(name);
+        for constant in self.chunk.constants.iter() {
+            if let Some(function) = constant.as_fn() {
+                out.push('\n');
+                out.push_str(&function.disassemble());
+            }
+        }
+        out
+    }
+}
+
 impl ObjString {
     pub fn as_str(&self) -> &str {
         unsafe {
@@ -505,6 +769,7 @@ impl ObjString {
             len,
             hash,
             chars,
+            global_slot: NO_SLOT,
         }
     }
 }
@@ -0,0 +1,985 @@
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    // Single-character tokens.
+    LeftParen = 0,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Dot,
+    Minus,
+    Plus,
+    Semicolon,
+    Slash,
+    Star,
+    Percent,
+    Question,
+    Colon,
+    Ampersand,
+    Pipe,
+    Caret,
+
+    // One or two character tokens.
+    Bang,
+    BangEqual,
+    Equal,
+    EqualEqual,
+    Greater,
+    GreaterEqual,
+    GreaterGreater,
+    Less,
+    LessEqual,
+    LessLess,
+    PlusEqual,
+    MinusEqual,
+    StarEqual,
+    StarStar,
+    SlashEqual,
+
+    // Literals.
+    Identifier,
+    String,
+    Number,
+
+    // Keywords.
+    And,
+    Assert,
+    Break,
+    Case,
+    Class,
+    Const,
+    Continue,
+    Default,
+    Else,
+    False,
+    For,
+    Fun,
+    If,
+    In,
+    Nil,
+    Or,
+    Print,
+    Return,
+    Super,
+    Switch,
+    This,
+    True,
+    Var,
+    While,
+    Write,
+
+    Error,
+    Eof,
+
+    Synthetic,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Token<'src> {
+    pub kind: TokenKind,
+    /// Line and column (counted in chars, from 1) where the token starts
+    pub line: u32,
+    pub column: u32,
+    /// The lexeme, or the error message for `TokenKind::Error` tokens
+    pub msg: &'src str,
+    /// File name set by the last `#line` directive that gave one
+    pub file: Option<&'src str>,
+}
+
+impl<'src> Token<'src> {
+    /// Token for names the compiler refers to that don't appear in the source
+    pub(crate) fn synthetic(msg: &'src str) -> Self {
+        Self {
+            kind: TokenKind::Synthetic,
+            line: u32::MAX,
+            column: 0,
+            msg,
+            file: None,
+        }
+    }
+
+    /// The value of a `TokenKind::Number` token (already validated by the
+    /// scanner), which may be hex and have `_` separators
+    pub fn number_value(&self) -> f64 {
+        let digits = self.msg.replace('_', "");
+        match digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            Some(hex) => hex
+                .chars()
+                .fold(0.0, |acc, c| acc * 16.0 + c.to_digit(16).unwrap() as f64),
+            None => digits.parse().unwrap(),
+        }
+    }
+
+    /// The value of a `TokenKind::String` token, without the quotes and with
+    /// its escape sequences (already validated by the scanner) replaced
+    pub fn string_value(&self) -> Cow<'src, str> {
+        let raw = &self.msg[1..self.msg.len() - 1];
+        if !raw.contains('\\') {
+            return Cow::Borrowed(raw);
+        }
+
+        let mut value = String::with_capacity(raw.len());
+        let mut chars = raw.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            value.push(match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some(escaped @ ('\\' | '"')) => escaped,
+                _ => unreachable!("invalid escapes are rejected by the scanner"),
+            });
+        }
+        Cow::Owned(value)
+    }
+}
+
+/// Scans tokens on demand, iterating yields every token up to (not including)
+/// `TokenKind::Eof`
+#[derive(Clone)]
+pub struct Scanner<'src> {
+    src: &'src [u8],
+    start: usize,
+    current: usize,
+    line: usize,
+    /// Offset of the first byte of the current line
+    line_start: usize,
+    /// Line and column of the token being scanned
+    start_line: usize,
+    start_column: usize,
+    /// Offset `start_column` was counted up to, each token only counts the
+    /// chars since the last one on its line so long lines stay linear
+    column_counted_to: usize,
+    /// File name given by the last `#line` directive
+    file: Option<&'src str>,
+}
+
+impl<'src> Scanner<'src> {
+    pub fn new(src: &'src str) -> Self {
+        Self {
+            src: src.as_bytes(),
+            start: 0,
+            current: 0,
+            line: 1,
+            line_start: 0,
+            start_line: 1,
+            start_column: 1,
+            column_counted_to: 0,
+            file: None,
+        }
+    }
+
+    fn advance(&mut self) -> u8 {
+        let ret = self.src[self.current];
+        self.current += 1;
+        ret
+    }
+
+    fn peek(&mut self) -> u8 {
+        self.src.get(self.current).cloned().unwrap_or(b'\0')
+    }
+
+    fn peek_next(&mut self) -> u8 {
+        self.src.get(self.current + 1).cloned().unwrap_or(b'\0')
+    }
+
+    /// Skips whitespace and comments, erroring on an unterminated block comment
+    fn skip_whitespace(&mut self) -> Result<(), &'static str> {
+        loop {
+            let c = self.peek();
+
+            match c {
+                b' ' | b'\r' | b'\t' => {
+                    self.advance();
+                }
+                b'\n' => {
+                    self.advance();
+                    self.newline();
+                }
+                b'#' if self.current == self.line_start => self.line_directive()?,
+                b'/' => match self.peek_next() {
+                    b'/' => {
+                        while self.peek() != b'\n' && !self.is_at_end() {
+                            self.advance();
+                        }
+                    }
+                    b'*' => self.block_comment()?,
+                    _ => return Ok(()),
+                },
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Skips a `/* */` comment, these nest so `/* a /* b */ c */` is one comment
+    fn block_comment(&mut self) -> Result<(), &'static str> {
+        // an unterminated comment is reported where it starts
+        self.mark_start();
+        self.current += 2;
+
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return Err("Unterminated block comment.");
+            }
+
+            match self.advance() {
+                b'\n' => self.newline(),
+                b'/' if self.matches(b'*') => depth += 1,
+                b'*' if self.matches(b'/') => depth -= 1,
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `#line N` or `#line N "name"` at the start of a line makes the next
+    /// line line `N`, of the file `name` if one is given
+    fn line_directive(&mut self) -> Result<(), &'static str> {
+        const INVALID: &str = "Expect '#line N' or '#line N \"name\"'.";
+
+        self.mark_start();
+        let src: &'src [u8] = self.src;
+        let end = src[self.current..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map_or(src.len(), |len| self.current + len);
+        // both ends are ascii so this is still valid utf8
+        let text = std::str::from_utf8(&src[self.current..end]).unwrap();
+        self.current = end;
+
+        let rest = text
+            .strip_prefix("#line")
+            .filter(|rest| rest.starts_with([' ', '\t']))
+            .ok_or(INVALID)?
+            .trim();
+        let (number, name) = match rest.split_once([' ', '\t']) {
+            Some((number, name)) => (number, Some(name.trim_start())),
+            None => (rest, None),
+        };
+        if !number.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(INVALID);
+        }
+        let line = number
+            .parse::<u32>()
+            .ok()
+            .filter(|line| *line > 0)
+            .ok_or(INVALID)?;
+
+        if let Some(name) = name {
+            let name = name
+                .strip_prefix('"')
+                .and_then(|name| name.strip_suffix('"'))
+                .filter(|name| !name.contains('"'))
+                .ok_or(INVALID)?;
+            self.file = Some(name);
+        }
+        // the newline ending the directive moves on to line `N`
+        self.line = line as usize - 1;
+        Ok(())
+    }
+
+    fn mark_start(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        if self.column_counted_to < self.line_start {
+            self.column_counted_to = self.line_start;
+            self.start_column = 1;
+        }
+        // count chars rather than bytes, utf8 continuation bytes look like 0b10xxxxxx
+        self.start_column += self.src[self.column_counted_to..self.start]
+            .iter()
+            .filter(|byte| **byte & 0xC0 != 0x80)
+            .count();
+        self.column_counted_to = self.start;
+    }
+
+    /// Call after consuming a `\n`
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    pub fn token(&mut self) -> Token<'src> {
+        if let Err(err) = self.skip_whitespace() {
+            return self.error_token(err);
+        }
+        self.mark_start();
+
+        if self.is_at_end() {
+            return self.make_token(TokenKind::Eof);
+        }
+
+        let c = self.advance();
+
+        if Self::is_alpha(c) {
+            return self.identifier();
+        }
+
+        if !c.is_ascii() {
+            // back up to decode the whole char
+            self.current -= 1;
+            let c = self.advance_char();
+            if c.is_alphabetic() {
+                return self.identifier();
+            }
+            return self.error_token("Unexpected character.");
+        }
+
+        if Self::is_digit(c) {
+            return self.number();
+        }
+
+        match c {
+            b'(' => return self.make_token(TokenKind::LeftParen),
+            b')' => return self.make_token(TokenKind::RightParen),
+            b'{' => return self.make_token(TokenKind::LeftBrace),
+            b'}' => return self.make_token(TokenKind::RightBrace),
+            b'[' => return self.make_token(TokenKind::LeftBracket),
+            b']' => return self.make_token(TokenKind::RightBracket),
+            b';' => return self.make_token(TokenKind::Semicolon),
+            b',' => return self.make_token(TokenKind::Comma),
+            b'.' => return self.make_token(TokenKind::Dot),
+            b'-' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::MinusEqual
+                } else {
+                    TokenKind::Minus
+                };
+                return self.make_token(kind);
+            }
+            b'+' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::PlusEqual
+                } else {
+                    TokenKind::Plus
+                };
+                return self.make_token(kind);
+            }
+            b'/' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::SlashEqual
+                } else {
+                    TokenKind::Slash
+                };
+                return self.make_token(kind);
+            }
+            b'*' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::StarEqual
+                } else if self.matches(b'*') {
+                    TokenKind::StarStar
+                } else {
+                    TokenKind::Star
+                };
+                return self.make_token(kind);
+            }
+            b'%' => return self.make_token(TokenKind::Percent),
+            b'?' => return self.make_token(TokenKind::Question),
+            b':' => return self.make_token(TokenKind::Colon),
+            b'&' => return self.make_token(TokenKind::Ampersand),
+            b'|' => return self.make_token(TokenKind::Pipe),
+            b'^' => return self.make_token(TokenKind::Caret),
+            b'!' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::BangEqual
+                } else {
+                    TokenKind::Bang
+                };
+                return self.make_token(kind);
+            }
+            b'=' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::EqualEqual
+                } else {
+                    TokenKind::Equal
+                };
+                return self.make_token(kind);
+            }
+            b'<' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::LessEqual
+                } else if self.matches(b'<') {
+                    TokenKind::LessLess
+                } else {
+                    TokenKind::Less
+                };
+                return self.make_token(kind);
+            }
+            b'>' => {
+                let kind = if self.matches(b'=') {
+                    TokenKind::GreaterEqual
+                } else if self.matches(b'>') {
+                    TokenKind::GreaterGreater
+                } else {
+                    TokenKind::Greater
+                };
+                return self.make_token(kind);
+            }
+            b'"' => return self.string(),
+            _ => (),
+        }
+
+        self.error_token("Unexpected character.")
+    }
+
+    fn is_alpha(c: u8) -> bool {
+        (b'a'..=b'z').contains(&c) || (b'A'..=b'Z').contains(&c) || c == b'_'
+    }
+
+    /// Consumes the char at `current`, which may be several bytes
+    fn advance_char(&mut self) -> char {
+        // Safety: the source is valid utf8 and `current` is always on a char boundary
+        let rest = unsafe { std::str::from_utf8_unchecked(&self.src[self.current..]) };
+        let c = rest.chars().next().unwrap();
+        self.current += c.len_utf8();
+        c
+    }
+
+    /// Identifiers are ASCII letters, digits and `_`, or any other char
+    /// that's alphabetic (or alphanumeric after the first char) as a cheap
+    /// stand-in for Unicode's XID rules. So `café` is a name but `🦀` isn't.
+    /// Keywords are all ASCII
+    fn identifier(&mut self) -> Token<'src> {
+        loop {
+            let c = self.peek();
+            if Self::is_alpha(c) || Self::is_digit(c) {
+                self.advance();
+            } else if !c.is_ascii() {
+                let start = self.current;
+                if !self.advance_char().is_alphanumeric() {
+                    self.current = start;
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+
+        self.make_token(self.identifier_kind())
+    }
+
+    fn identifier_kind(&self) -> TokenKind {
+        match self.src[self.start] {
+            b'a' if self.current - self.start > 1 => match self.src[self.start + 1] {
+                b'n' => self.check_keyword(2, 1, "d", TokenKind::And),
+                b's' => self.check_keyword(2, 4, "sert", TokenKind::Assert),
+                _ => TokenKind::Identifier,
+            },
+            b'b' => self.check_keyword(1, 4, "reak", TokenKind::Break),
+            b'c' if self.current - self.start > 1 => match self.src[self.start + 1] {
+                b'a' => self.check_keyword(2, 2, "se", TokenKind::Case),
+                b'l' => self.check_keyword(2, 3, "ass", TokenKind::Class),
+                b'o' if self.current - self.start > 3 && self.src[self.start + 2] == b'n' => {
+                    match self.src[self.start + 3] {
+                        b's' => self.check_keyword(4, 1, "t", TokenKind::Const),
+                        b't' => self.check_keyword(4, 4, "inue", TokenKind::Continue),
+                        _ => TokenKind::Identifier,
+                    }
+                }
+                _ => TokenKind::Identifier,
+            },
+            b'd' => self.check_keyword(1, 6, "efault", TokenKind::Default),
+            b'e' => self.check_keyword(1, 3, "lse", TokenKind::Else),
+            b'f' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
+                b'a' => self.check_keyword(2, 3, "lse", TokenKind::False),
+                b'o' => self.check_keyword(2, 1, "r", TokenKind::For),
+                b'u' => self.check_keyword(2, 1, "n", TokenKind::Fun),
+                _ => TokenKind::Identifier,
+            },
+            b'i' if self.current - self.start > 1 => match self.src[self.start + 1] {
+                b'f' => self.check_keyword(2, 0, "", TokenKind::If),
+                b'n' => self.check_keyword(2, 0, "", TokenKind::In),
+                _ => TokenKind::Identifier,
+            },
+            b'n' => self.check_keyword(1, 2, "il", TokenKind::Nil),
+            b'o' => self.check_keyword(1, 1, "r", TokenKind::Or),
+            b'p' => self.check_keyword(1, 4, "rint", TokenKind::Print),
+            b'r' => self.check_keyword(1, 5, "eturn", TokenKind::Return),
+            b's' if self.current - self.start > 1 => match self.src[self.start + 1] {
+                b'u' => self.check_keyword(2, 3, "per", TokenKind::Super),
+                b'w' => self.check_keyword(2, 4, "itch", TokenKind::Switch),
+                _ => TokenKind::Identifier,
+            },
+            b't' if self.current as i64 - self.start as i64 > 1 => match self.src[self.start + 1] {
+                b'h' => self.check_keyword(2, 2, "is", TokenKind::This),
+                b'r' => self.check_keyword(2, 2, "ue", TokenKind::True),
+                _ => TokenKind::Identifier,
+            },
+            b'v' => self.check_keyword(1, 2, "ar", TokenKind::Var),
+            b'w' if self.current - self.start > 1 => match self.src[self.start + 1] {
+                b'h' => self.check_keyword(2, 3, "ile", TokenKind::While),
+                b'r' => self.check_keyword(2, 3, "ite", TokenKind::Write),
+                _ => TokenKind::Identifier,
+            },
+            _ => TokenKind::Identifier,
+        }
+    }
+
+    /// The source text from the start of `first` to the end of `last`, both
+    /// of which must have been scanned from this source
+    pub(crate) fn text_between(&self, first: Token<'src>, last: Token<'src>) -> &'src str {
+        let start = first.msg.as_ptr() as usize - self.src.as_ptr() as usize;
+        let end = last.msg.as_ptr() as usize + last.msg.len() - self.src.as_ptr() as usize;
+        std::str::from_utf8(&self.src[start..end]).unwrap()
+    }
+
+    fn check_keyword(&self, start: usize, len: usize, rest: &str, kind: TokenKind) -> TokenKind {
+        if self.current - self.start == start + len
+            && &self.src[(self.start + start)..(self.start + start + len)] == rest.as_bytes()
+        {
+            return kind;
+        }
+
+        TokenKind::Identifier
+    }
+
+    fn string(&mut self) -> Token<'src> {
+        // Keep going after a bad escape so the rest of the string isn't
+        // scanned as code
+        let mut bad_escape = false;
+        while self.peek() != b'"' && !self.is_at_end() {
+            match self.advance() {
+                b'\n' => self.newline(),
+                b'\\' if !self.is_at_end() => match self.advance() {
+                    b'n' | b't' | b'r' | b'\\' | b'"' => (),
+                    b'\n' => {
+                        self.newline();
+                        bad_escape = true;
+                    }
+                    _ => bad_escape = true,
+                },
+                _ => (),
+            }
+        }
+
+        if self.is_at_end() {
+            return self.error_token("Unterminated string.");
+        }
+
+        // closing quote
+        self.advance();
+        if bad_escape {
+            return self.error_token("Invalid escape sequence in string.");
+        }
+        self.make_token(TokenKind::String)
+    }
+
+    fn is_digit(c: u8) -> bool {
+        match c {
+            b'0' | b'1' | b'2' | b'3' | b'4' | b'5' | b'6' | b'7' | b'8' | b'9' => true,
+            _ => false,
+        }
+    }
+
+    fn is_hex_digit(c: u8) -> bool {
+        c.is_ascii_hexdigit()
+    }
+
+    /// Consumes a run of digits where `_` may only separate two digits,
+    /// returns false (still consuming the whole run) if one doesn't
+    fn digits(&mut self, is_digit: fn(u8) -> bool) -> bool {
+        let mut valid = true;
+        while is_digit(self.peek()) || self.peek() == b'_' {
+            if self.advance() == b'_' && !is_digit(self.peek()) {
+                valid = false;
+            }
+        }
+        valid
+    }
+
+    fn number(&mut self) -> Token<'src> {
+        if self.src[self.start] == b'0' && matches!(self.peek(), b'x' | b'X') {
+            self.advance();
+            let has_digit = Self::is_hex_digit(self.peek());
+            if !self.digits(Self::is_hex_digit) {
+                return self.error_token("Invalid digit separator in number.");
+            }
+            if !has_digit {
+                return self.error_token("Expect hex digits after '0x'.");
+            }
+            return self.make_token(TokenKind::Number);
+        }
+
+        let mut valid = self.digits(Self::is_digit);
+
+        if self.peek() == b'.' && Self::is_digit(self.peek_next()) {
+            // consume the '.'
+            self.advance();
+
+            valid &= self.digits(Self::is_digit);
+        }
+
+        if !valid {
+            return self.error_token("Invalid digit separator in number.");
+        }
+        self.make_token(TokenKind::Number)
+    }
+
+    fn matches(&mut self, expected: u8) -> bool {
+        if self.is_at_end() {
+            return false;
+        }
+
+        if self.src[self.current] != expected {
+            return false;
+        }
+
+        self.current += 1;
+
+        true
+    }
+
+    fn is_at_end(&self) -> bool {
+        self.current == self.src.len()
+        // self.src[self.current] == b'\0'
+    }
+
+    fn make_token(&self, kind: TokenKind) -> Token<'src> {
+        Token {
+            kind,
+            // Safety:
+            // The input is guaranteed to be valid utf8 so this is safe
+            msg: unsafe { std::str::from_utf8_unchecked(&self.src[self.start..self.current]) },
+            line: self.start_line as u32,
+            column: self.start_column as u32,
+            file: self.file,
+        }
+    }
+
+    fn error_token(&self, err: &'src str) -> Token<'src> {
+        Token {
+            kind: TokenKind::Error,
+            msg: err,
+            line: self.start_line as u32,
+            column: self.start_column as u32,
+            file: self.file,
+        }
+    }
+}
+
+impl<'src> Iterator for Scanner<'src> {
+    type Item = Token<'src>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let token = self.token();
+        (token.kind != TokenKind::Eof).then_some(token)
+    }
+}
+
+/// The tokens of `src` without running the compiler, lexical errors show up
+/// as `TokenKind::Error` tokens carrying the message
+pub fn tokens(src: &str) -> Scanner {
+    Scanner::new(src)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        common::SharedBuf,
+        compile::compile,
+        eval, interpret,
+        mem::Mem,
+        scanner::{tokens, Token, TokenKind},
+        value::Value,
+        vm::VM,
+    };
+
+    #[test]
+    fn tokenize() {
+        let kinds: Vec<TokenKind> = tokens("var x = 1;").map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                TokenKind::Var,
+                TokenKind::Identifier,
+                TokenKind::Equal,
+                TokenKind::Number,
+                TokenKind::Semicolon
+            ]
+        );
+
+        let tokens: Vec<Token> = tokens("print \"hi\";\n@ \"oops").collect();
+        assert_eq!(
+            tokens[1],
+            Token {
+                kind: TokenKind::String,
+                line: 1,
+                column: 7,
+                msg: "\"hi\"",
+                file: None
+            }
+        );
+        assert_eq!(
+            &tokens[3..],
+            [
+                Token {
+                    kind: TokenKind::Error,
+                    line: 2,
+                    column: 1,
+                    msg: "Unexpected character.",
+                    file: None
+                },
+                Token {
+                    kind: TokenKind::Error,
+                    line: 2,
+                    column: 3,
+                    msg: "Unterminated string.",
+                    file: None
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn columns_on_long_lines() {
+        // long enough to take minutes if every token counted from the line start
+        let line = "a + ".repeat(200_000);
+        let src = format!("x\n{line}b\n  y");
+        let tokens: Vec<Token> = tokens(&src).collect();
+        let columns = |msg| {
+            tokens
+                .iter()
+                .filter(|token| token.msg == msg)
+                .map(|token| (token.line, token.column))
+                .last()
+        };
+        assert_eq!(columns("x"), Some((1, 1)));
+        assert_eq!(columns("a"), Some((2, 799_997)));
+        assert_eq!(columns("b"), Some((2, 800_001)));
+        assert_eq!(columns("y"), Some((3, 3)));
+    }
+
+    #[test]
+    fn unicode_identifiers() {
+        let src = r#"
+var café = "au lait";
+var 名前 = 2;
+var naïve_1 = 名前 * 2;
+print café;
+print naïve_1;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "au lait\n4\n");
+
+        let tokens: Vec<Token> = tokens("é_1 + ünïcode").collect();
+        assert_eq!(
+            tokens.iter().map(|token| token.msg).collect::<Vec<_>>(),
+            ["é_1", "+", "ünïcode"]
+        );
+        assert_eq!(tokens[0].kind, TokenKind::Identifier);
+        assert_eq!(tokens[1].column, 5);
+        // keywords stay ASCII
+        assert_eq!(
+            crate::tokens("vär").next().unwrap().kind,
+            TokenKind::Identifier
+        );
+
+        // emoji aren't alphabetic, each is one error token
+        let tokens: Vec<Token> = crate::tokens("var x🦀 = 🦀;").collect();
+        assert_eq!(
+            tokens
+                .iter()
+                .map(|token| (token.kind, token.msg, token.column))
+                .collect::<Vec<_>>(),
+            [
+                (TokenKind::Var, "var", 1),
+                (TokenKind::Identifier, "x", 5),
+                (TokenKind::Error, "Unexpected character.", 6),
+                (TokenKind::Equal, "=", 8),
+                (TokenKind::Error, "Unexpected character.", 10),
+                (TokenKind::Semicolon, ";", 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn scanner_survives_adversarial_input() {
+        let inputs = [
+            "{",
+            "/",
+            "a/",
+            "\"unterminated é日🦀",
+            "\"\\",
+            "\"\\é",
+            "#line",
+            "#line 3 \"日",
+            "0x",
+            "1_",
+            "1.",
+            "é/",
+            "!=<>=&|^;,.?:[](){}%**",
+            "\u{0}\u{7}\u{1b}\u{7f}",
+            "\u{200b}\u{feff}",
+            "`@$~'",
+        ];
+        // and every prefix of a pseudo-random mix of the awkward characters
+        let pool: Vec<char> = "{}()\"\\#/*0x_.e\n\u{0}é日🦀line ".chars().collect();
+        let mut seed = 1u32;
+        let random: String = (0..400)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                pool[(seed >> 16) as usize % pool.len()]
+            })
+            .collect();
+        let prefixes = random.char_indices().map(|(i, _)| &random[..i]);
+
+        for src in inputs.into_iter().chain(prefixes) {
+            for token in tokens(src) {
+                // lexemes are whole chars of `src`, error messages are static
+                let offset = (token.msg.as_ptr() as usize).wrapping_sub(src.as_ptr() as usize);
+                assert!(
+                    token.kind == TokenKind::Error
+                        || src.get(offset..offset + token.msg.len()) == Some(token.msg),
+                    "{src:?}: {token:?}"
+                );
+            }
+            let mut mem = Mem::new();
+            let _ = compile(src, &mut mem);
+        }
+
+        let control: Vec<TokenKind> = tokens("\u{0}\u{1b}").map(|token| token.kind).collect();
+        assert_eq!(control, [TokenKind::Error, TokenKind::Error]);
+    }
+
+    #[test]
+    fn block_comments() {
+        let kinds: Vec<TokenKind> = tokens("1 /* outer /* inner */ still comment */ 2 /**/ 3")
+            .map(|token| token.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [TokenKind::Number, TokenKind::Number, TokenKind::Number]
+        );
+
+        let src = "/* one\ntwo /* three\n*/ four\n*/ var /* * / */ x";
+        let tokens: Vec<Token> = tokens(src).collect();
+        assert_eq!(
+            tokens,
+            [
+                Token {
+                    kind: TokenKind::Var,
+                    line: 4,
+                    column: 4,
+                    msg: "var",
+                    file: None
+                },
+                Token {
+                    kind: TokenKind::Identifier,
+                    line: 4,
+                    column: 18,
+                    msg: "x",
+                    file: None
+                }
+            ]
+        );
+
+        let tokens: Vec<Token> = crate::tokens("1\n  /* /* */ never closed\n").collect();
+        assert_eq!(
+            tokens[1],
+            Token {
+                kind: TokenKind::Error,
+                line: 2,
+                column: 3,
+                msg: "Unterminated block comment.",
+                file: None
+            }
+        );
+        assert_eq!(tokens.len(), 2);
+    }
+
+    #[test]
+    fn number_literal_formats() {
+        let mut vm = VM::new();
+        for (src, expected) in [
+            ("0xFF", 255.0),
+            ("0Xff", 255.0),
+            ("0x0", 0.0),
+            ("0xdead_beef", 3735928559.0),
+            ("1_000_000", 1000000.0),
+            ("1_0.2_5", 10.25),
+            ("007", 7.0),
+        ] {
+            assert_eq!(
+                eval(&mut vm, src).unwrap(),
+                Value::Number(expected),
+                "{src}"
+            );
+        }
+
+        for (src, expected) in [
+            ("0x", "Expect hex digits after '0x'."),
+            ("0x_1", "Expect hex digits after '0x'."),
+            ("0xF_", "Invalid digit separator in number."),
+            ("1__0", "Invalid digit separator in number."),
+            ("100_", "Invalid digit separator in number."),
+            ("1_.5", "Invalid digit separator in number."),
+            ("1.5_", "Invalid digit separator in number."),
+        ] {
+            let tokens: Vec<Token> = tokens(src).collect();
+            assert_eq!(
+                tokens,
+                [Token {
+                    kind: TokenKind::Error,
+                    line: 1,
+                    column: 1,
+                    msg: expected,
+                    file: None
+                }],
+                "{src}"
+            );
+        }
+
+        // A leading underscore makes an identifier, not a number
+        let kinds: Vec<TokenKind> = tokens("_1").map(|token| token.kind).collect();
+        assert_eq!(kinds, [TokenKind::Identifier]);
+    }
+
+    #[test]
+    fn string_escapes() {
+        let mut vm = VM::new();
+        for (src, expected) in [
+            (r#""a\nb""#, "a\nb"),
+            (r#""a\tb""#, "a\tb"),
+            (r#""a\rb""#, "a\rb"),
+            (r#""a\\b""#, "a\\b"),
+            (r#""say \"hi\"""#, "say \"hi\""),
+            (r#""no escapes""#, "no escapes"),
+        ] {
+            let value = eval(&mut vm, src).unwrap();
+            assert_eq!(value.as_str(), Some(expected), "{src}");
+        }
+
+        // The rest of the string is skipped, so `b` isn't scanned as code
+        let tokens: Vec<Token> = tokens(r#"x = "a\qb";"#).collect();
+        assert_eq!(
+            tokens[2],
+            Token {
+                kind: TokenKind::Error,
+                line: 1,
+                column: 5,
+                msg: "Invalid escape sequence in string.",
+                file: None
+            }
+        );
+        assert_eq!(tokens[3].kind, TokenKind::Semicolon);
+
+        for src in [r#""abc\"#, r#""abc\""#] {
+            let tokens: Vec<Token> = crate::tokens(src).collect();
+            assert_eq!(tokens.len(), 1, "{src}");
+            assert_eq!(tokens[0].msg, "Unterminated string.");
+        }
+    }
+}
@@ -402,3 +402,23 @@ impl Entry {
         self.key.is_null() && matches!(self.value, Value::Bool(true))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{mem::Mem, table::Table, value::Value};
+
+    #[test]
+    fn table() {
+        let mut mem = Mem::new();
+        let mut table = Table::new();
+
+        let key = mem.copy_string("bagel").as_non_null_ptr();
+        assert_eq!(table.set(key, Value::Number(420.0)), true);
+        assert_eq!(table.set(key, Value::Number(69.0)), false);
+        assert_eq!(table.get(key), Some(Value::Number(69.0)));
+        assert_eq!(table.delete(key), true);
+        assert_eq!(table.delete(key), false);
+
+        Table::free(&mut table);
+    }
+}
@@ -1,20 +1,53 @@
 use std::{
-    fmt::Debug,
-    ops::{Add, Div, Mul, Neg, Sub},
+    fmt::{Debug, Display},
+    ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Rem, Shl, Shr, Sub},
     ptr::NonNull,
 };
 
 use crate::{
     mem::{Gc, Greystack},
     obj::{
-        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjNative,
-        ObjPtrWrapper, ObjString,
+        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjList,
+        ObjMap, ObjNative, ObjPtrWrapper, ObjString,
     },
 };
 
 pub type ValueArray = Vec<Value>;
 
-#[derive(Copy, Clone)]
+/// Why an operator couldn't be applied to its operands, the VM turns this
+/// into a runtime error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    OperandNotNumber,
+    OperandsNotNumbers,
+    OperandsNotNumbersOrStrings,
+    OperandsNotIntegers,
+    ShiftOutOfRange,
+    DivisionByZero,
+}
+
+impl Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RuntimeErrorKind::OperandNotNumber => "Operand must be a number.",
+            RuntimeErrorKind::OperandsNotNumbers => "Operands must be numbers.",
+            RuntimeErrorKind::OperandsNotNumbersOrStrings => {
+                "Operands must be two numbers or two strings."
+            }
+            RuntimeErrorKind::OperandsNotIntegers => "Operands must be integers.",
+            RuntimeErrorKind::ShiftOutOfRange => "Shift amount must be between 0 and 63.",
+            RuntimeErrorKind::DivisionByZero => "Division by zero.",
+        })
+    }
+}
+
+/// Equality is Lox's `==`: values of different types are never equal and
+/// objects are compared by identity, which for interned strings is comparing
+/// their contents.
+///
+/// Heap values are a `Gc` pointer to an object owned by the collector, so
+/// copying a value onto the stack never copies the string or list behind it
+#[derive(Copy, Clone, PartialEq)]
 pub enum Value {
     Bool(bool),
     Number(f64),
@@ -68,6 +101,20 @@ impl Value {
         }
     }
 
+    pub fn as_list(&self) -> Option<Gc<ObjList>> {
+        match *self {
+            Value::Obj(obj) if obj.kind == ObjKind::List => Some(obj.cast()),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<Gc<ObjMap>> {
+        match *self {
+            Value::Obj(obj) if obj.kind == ObjKind::Map => Some(obj.cast()),
+            _ => None,
+        }
+    }
+
     pub fn as_instance_fn(&self) -> Option<Gc<ObjInstance>> {
         match *self {
             Value::Obj(obj) if obj.kind == ObjKind::Instance => Some(obj.cast()),
@@ -115,6 +162,27 @@ impl Value {
         Some(unsafe { (*noob.as_ptr()).as_str() })
     }
 
+    /// What `typeof` returns. Functions, closures, natives and bound methods
+    /// are all just "function"
+    pub fn type_name(self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::Nil => "nil",
+            Value::Obj(obj) => match obj.kind {
+                ObjKind::Str => "string",
+                ObjKind::Fn | ObjKind::Closure | ObjKind::Native | ObjKind::BoundMethod => {
+                    "function"
+                }
+                ObjKind::Class => "class",
+                ObjKind::Instance => "instance",
+                ObjKind::List => "list",
+                ObjKind::Map => "map",
+                ObjKind::Upvalue => "upvalue",
+            },
+        }
+    }
+
     #[inline]
     pub fn is_nil(self) -> bool {
         matches!(self, Value::Nil)
@@ -128,30 +196,20 @@ impl Value {
         }
     }
 
-    pub fn gt_owned(self, other: Self) -> Value {
-        self.gt(&other).into()
+    /// `self ** other`
+    pub fn pow(self, other: Self) -> Result<Value, RuntimeErrorKind> {
+        let (a, b) = numbers(self, other)?;
+        Ok(Value::Number(a.powf(b)))
     }
 
-    pub fn lt_owned(self, other: Self) -> Value {
-        self.lt(&other).into()
+    pub fn gt_owned(self, other: Self) -> Result<Value, RuntimeErrorKind> {
+        let (a, b) = numbers(self, other)?;
+        Ok(Value::Bool(a > b))
     }
 
-    fn objs_eq(a: *mut Obj, b: *mut Obj) -> bool {
-        a == b
-        // unsafe {
-        // if (*a).kind != (*b).kind {
-        //     return false;
-        // }
-
-        // match (*a).kind {
-        //     ObjKind::Str => {
-        //         let a_str = (&*(a as *mut ObjString)).as_str();
-        //         let b_str = (&*(b as *mut ObjString)).as_str();
-
-        //         a_str == b_str
-        //     }
-        // }
-        // }
+    pub fn lt_owned(self, other: Self) -> Result<Value, RuntimeErrorKind> {
+        let (a, b) = numbers(self, other)?;
+        Ok(Value::Bool(a < b))
     }
 }
 
@@ -168,6 +226,24 @@ impl Debug for Value {
     }
 }
 
+/// The user-facing rendering used by `print` and the REPL. Integral numbers
+/// have no trailing `.0` and strings are shown without quotes
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bool(b) => write!(f, "{b}"),
+            // spelled like the `NaN` and `Infinity` globals rather than Rust's `inf`
+            Self::Number(num) if num.is_nan() => write!(f, "NaN"),
+            Self::Number(num) if num.is_infinite() => {
+                write!(f, "{}Infinity", if *num < 0.0 { "-" } else { "" })
+            }
+            Self::Number(num) => write!(f, "{num}"),
+            Self::Nil => write!(f, "nil"),
+            Self::Obj(obj) => write!(f, "{}", ObjPtrWrapper(obj.as_ptr())),
+        }
+    }
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         match (self, other) {
@@ -177,17 +253,6 @@ impl PartialOrd for Value {
     }
 }
 
-impl PartialEq for Value {
-    fn eq(&self, other: &Self) -> bool {
-        match (*self, *other) {
-            (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
-            (Self::Number(l0), Self::Number(r0)) => l0 == r0,
-            (Self::Obj(a), Self::Obj(b)) => Self::objs_eq(a.as_ptr(), b.as_ptr()),
-            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
-        }
-    }
-}
-
 impl From<f64> for Value {
     fn from(val: f64) -> Self {
         Self::Number(val)
@@ -201,57 +266,190 @@ impl From<bool> for Value {
 }
 
 impl Neg for Value {
-    type Output = Value;
+    type Output = Result<Value, RuntimeErrorKind>;
 
     fn neg(self) -> Self::Output {
         match self {
-            Value::Bool(b) => Value::Bool(!b),
-            Value::Number(num) => Value::Number(num),
-            _ => unreachable!(),
+            Value::Number(num) => Ok(Value::Number(-num)),
+            _ => Err(RuntimeErrorKind::OperandNotNumber),
         }
     }
 }
 
+/// Only numbers, string concatenation allocates so the VM handles it before
+/// getting here
 impl Add for Value {
-    type Output = Self;
+    type Output = Result<Value, RuntimeErrorKind>;
 
     fn add(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
-            _ => unreachable!(),
+            (Value::Number(a), Value::Number(b)) => Ok(Value::Number(a + b)),
+            _ => Err(RuntimeErrorKind::OperandsNotNumbersOrStrings),
         }
     }
 }
 
 impl Sub for Value {
-    type Output = Self;
+    type Output = Result<Value, RuntimeErrorKind>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a - b),
-            _ => unreachable!(),
-        }
+        let (a, b) = numbers(self, rhs)?;
+        Ok(Value::Number(a - b))
     }
 }
 
 impl Mul for Value {
-    type Output = Self;
+    type Output = Result<Value, RuntimeErrorKind>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a * b),
-            _ => unreachable!(),
-        }
+        let (a, b) = numbers(self, rhs)?;
+        Ok(Value::Number(a * b))
+    }
+}
+
+/// Truncating like C's `fmod`, the result has the sign of the dividend
+impl Rem for Value {
+    type Output = Result<Value, RuntimeErrorKind>;
+
+    fn rem(self, rhs: Self) -> Self::Output {
+        let (a, b) = numbers(self, rhs)?;
+        Ok(Value::Number(a % b))
     }
 }
 
 impl Div for Value {
-    type Output = Self;
+    type Output = Result<Value, RuntimeErrorKind>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Number(a), Value::Number(b)) => Value::Number(a / b),
-            _ => unreachable!(),
+        let (a, b) = numbers(self, rhs)?;
+        Ok(Value::Number(a / b))
+    }
+}
+
+fn numbers(a: Value, b: Value) -> Result<(f64, f64), RuntimeErrorKind> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => Ok((a, b)),
+        _ => Err(RuntimeErrorKind::OperandsNotNumbers),
+    }
+}
+
+/// The bitwise operators work on numbers that are whole and fit in an `i64`
+fn integers(a: Value, b: Value) -> Result<(i64, i64), RuntimeErrorKind> {
+    fn integer(value: Value) -> Option<i64> {
+        // -2^63 fits in an i64, 2^63 doesn't
+        const RANGE: std::ops::Range<f64> = (i64::MIN as f64)..-(i64::MIN as f64);
+        match value {
+            Value::Number(num) if num.fract() == 0.0 && RANGE.contains(&num) => Some(num as i64),
+            _ => None,
         }
     }
+
+    match (integer(a), integer(b)) {
+        (Some(a), Some(b)) => Ok((a, b)),
+        _ => Err(RuntimeErrorKind::OperandsNotIntegers),
+    }
+}
+
+impl BitAnd for Value {
+    type Output = Result<Value, RuntimeErrorKind>;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        let (a, b) = integers(self, rhs)?;
+        Ok(Value::Number((a & b) as f64))
+    }
+}
+
+impl BitOr for Value {
+    type Output = Result<Value, RuntimeErrorKind>;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        let (a, b) = integers(self, rhs)?;
+        Ok(Value::Number((a | b) as f64))
+    }
+}
+
+impl BitXor for Value {
+    type Output = Result<Value, RuntimeErrorKind>;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        let (a, b) = integers(self, rhs)?;
+        Ok(Value::Number((a ^ b) as f64))
+    }
+}
+
+impl Shl for Value {
+    type Output = Result<Value, RuntimeErrorKind>;
+
+    fn shl(self, rhs: Self) -> Self::Output {
+        let (a, b) = integers(self, rhs)?;
+        let shifted = u32::try_from(b)
+            .ok()
+            .and_then(|b| a.checked_shl(b))
+            .ok_or(RuntimeErrorKind::ShiftOutOfRange)?;
+        Ok(Value::Number(shifted as f64))
+    }
+}
+
+/// Arithmetic, the sign bit is shifted in
+impl Shr for Value {
+    type Output = Result<Value, RuntimeErrorKind>;
+
+    fn shr(self, rhs: Self) -> Self::Output {
+        let (a, b) = integers(self, rhs)?;
+        let shifted = u32::try_from(b)
+            .ok()
+            .and_then(|b| a.checked_shr(b))
+            .ok_or(RuntimeErrorKind::ShiftOutOfRange)?;
+        Ok(Value::Number(shifted as f64))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        mem::Mem,
+        value::{RuntimeErrorKind, Value},
+    };
+
+    #[test]
+    fn value_operators() {
+        let (one, two, t) = (Value::Number(1.0), Value::Number(2.0), Value::Bool(true));
+        assert_eq!(one + two, Ok(Value::Number(3.0)));
+        assert_eq!(one - two, Ok(Value::Number(-1.0)));
+        assert_eq!(two * two, Ok(Value::Number(4.0)));
+        assert_eq!(one / two, Ok(Value::Number(0.5)));
+        assert_eq!(Value::Number(-7.0) % two, Ok(Value::Number(-1.0)));
+        assert_eq!(-one, Ok(Value::Number(-1.0)));
+        assert_eq!(one.lt_owned(two), Ok(Value::Bool(true)));
+        assert_eq!(one.gt_owned(two), Ok(Value::Bool(false)));
+
+        assert_eq!(one + t, Err(RuntimeErrorKind::OperandsNotNumbersOrStrings));
+        assert_eq!(Value::Nil - one, Err(RuntimeErrorKind::OperandsNotNumbers));
+        assert_eq!(one * Value::Nil, Err(RuntimeErrorKind::OperandsNotNumbers));
+        assert_eq!(t / t, Err(RuntimeErrorKind::OperandsNotNumbers));
+        assert_eq!(t % one, Err(RuntimeErrorKind::OperandsNotNumbers));
+        assert_eq!(t.lt_owned(one), Err(RuntimeErrorKind::OperandsNotNumbers));
+        assert_eq!(-Value::Nil, Err(RuntimeErrorKind::OperandNotNumber));
+        assert_eq!(
+            RuntimeErrorKind::OperandsNotNumbers.to_string(),
+            "Operands must be numbers."
+        );
+
+        assert_eq!(Value::Nil, Value::Nil);
+        assert_ne!(Value::Nil, Value::Bool(false));
+        assert_ne!(Value::Number(0.0), Value::Bool(false));
+        assert_ne!(Value::Number(f64::NAN), Value::Number(f64::NAN));
+
+        let mut mem = Mem::new();
+        let (a, b) = (mem.copy_string("a"), mem.copy_string("b"));
+        assert_eq!(
+            Value::Obj(a.cast()),
+            Value::Obj(mem.copy_string("a").cast())
+        );
+        assert_ne!(Value::Obj(a.cast()), Value::Obj(b.cast()));
+        assert_eq!(
+            Value::Obj(a.cast()) + Value::Obj(a.cast()),
+            Err(RuntimeErrorKind::OperandsNotNumbersOrStrings)
+        );
+    }
 }
@@ -1,21 +1,25 @@
 use std::{
     alloc::{self, handle_alloc_error, Layout},
     borrow::Cow,
+    collections::HashMap,
+    io::{self, BufRead, Write},
     mem::{transmute, MaybeUninit},
     num::NonZeroUsize,
     ptr::{self, addr_of_mut, null_mut, NonNull},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    chunk::{InstructionDebug, Opcode},
+    chunk::Opcode,
     mem::{Gc, Greystack, Mem},
-    native_fn::NativeFnKind,
+    native_fn::{NativeFn, Rng},
     obj::{
-        Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjNative,
-        ObjPtrWrapper, ObjPunnable, ObjString, ObjUpvalue,
+        MapKey, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind,
+        ObjList, ObjMap, ObjNative, ObjPunnable, ObjString, ObjUpvalue,
     },
+    scanner::{tokens, TokenKind},
     table::ObjHash,
-    value::Value,
+    value::{RuntimeErrorKind, Value},
 };
 
 const GC_HEAP_GROW_FACTOR: usize = 2;
@@ -24,16 +28,56 @@ pub type InterpretResult<T> = Result<T, InterpretError>;
 
 #[derive(Debug, PartialEq)]
 pub enum InterpretError {
-    RuntimeError,
+    /// `line` is where the error happened and `frames` are the call frames
+    /// that were active, innermost first. `Display` renders the clox-style
+    /// report with one `[line N] in ...` line per frame
+    RuntimeError {
+        line: u32,
+        message: String,
+        frames: Vec<TraceFrame>,
+    },
     CompileError,
 }
 
+/// A call frame in a runtime error's stack trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// `None` for the top-level script
+    pub function: Option<String>,
+    pub line: u32,
+    /// Set by a `#line` directive naming the file
+    pub file: Option<String>,
+}
+
+impl std::fmt::Display for InterpretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InterpretError::RuntimeError {
+                message, frames, ..
+            } => {
+                write!(f, "Runtime error: {message}")?;
+                for frame in frames {
+                    write!(f, "\n[line {}", frame.line)?;
+                    if let Some(file) = &frame.file {
+                        write!(f, " of {file}")?;
+                    }
+                    match &frame.function {
+                        Some(name) => write!(f, "] in {name}()")?,
+                        None => write!(f, "] in script")?,
+                    }
+                }
+                Ok(())
+            }
+            InterpretError::CompileError => write!(f, "Compile error."),
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct CallFrame {
-    /// PERF: Instruction pointer is faster to dereference than index
-    /// instruction index of caller, this is used for returns, function will jump back to this index
-    /// and resume from there
-    pub instr_offset: u32,
+    /// Next instruction to execute in this frame's chunk, while a call is in
+    /// progress this is where the caller resumes once the callee returns
+    pub ip: *const u8,
 
     /// ptr into VM's value stack at the first slot this function can use
     pub slots_ptr: *mut Value,
@@ -46,6 +90,11 @@ impl CallFrame {
     fn function(&self) -> &ObjFunction {
         self.closure.as_ref().function.as_ref()
     }
+    /// Index of `ip` in the chunk's code
+    #[inline]
+    fn instr_offset(&self) -> usize {
+        unsafe { self.ip.sub_ptr(self.function().chunk.code.as_ptr()) }
+    }
     #[inline]
     fn closure(&self) -> &ObjClosure {
         self.closure.as_ref()
@@ -87,9 +136,20 @@ impl Iterator for StackIter {
 pub struct Stack {
     pub stack: *mut Value,
     pub top: *mut Value,
+    /// One past the last slot the stack has room for
+    pub end: *mut Value,
 }
 
 impl Stack {
+    /// Number of values on the stack
+    fn len(&self) -> usize {
+        (self.top as usize - self.stack as usize) / std::mem::size_of::<Value>()
+    }
+
+    fn capacity(&self) -> usize {
+        (self.end as usize - self.stack as usize) / std::mem::size_of::<Value>()
+    }
+
     fn top(&self) -> Value {
         unsafe {
             let val = *(self.top.offset(1));
@@ -132,9 +192,37 @@ impl Stack {
 
 pub const U8_COUNT: usize = (u8::MAX) as usize + 1; // 256
 const FRAMES_MAX: usize = 64;
+/// How many values the stack may hold when a function is called, deeper than
+/// that is a stack overflow
 pub const STACK_MAX: usize = 64 * U8_COUNT;
-pub static mut STACK: [MaybeUninit<Value>; STACK_MAX] = [MaybeUninit::uninit(); STACK_MAX];
-pub type ValueStack = [MaybeUninit<Value>; STACK_MAX];
+/// Enough for a handful of frames, the stack grows from there
+pub const DEFAULT_STACK_CAPACITY: usize = 4 * U8_COUNT;
+
+/// What `/` and `%` do when the divisor is zero
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DivByZero {
+    /// IEEE 754 results, `1 / 0` is `Infinity` and `1 % 0` is `NaN`
+    #[default]
+    Ieee,
+    /// A `Division by zero.` runtime error
+    Error,
+}
+
+/// The natives every VM starts with
+const NATIVES: &[(&str, NativeFn)] = &[
+    ("clock", VM::native_clock),
+    ("__dummy", VM::native_dummy),
+    ("typeof", VM::native_typeof),
+    ("len", VM::native_len),
+    ("readLine", VM::native_read_line),
+    ("getField", VM::native_get_field),
+    ("setField", VM::native_set_field),
+    ("str", VM::native_str),
+    ("num", VM::native_num),
+    ("sleep", VM::native_sleep),
+    ("random", VM::native_random),
+    ("randomInt", VM::native_random_int),
+];
 
 pub struct VM {
     pub stack: Stack,
@@ -151,6 +239,37 @@ pub struct VM {
     pub grey_stack: Greystack,
 
     pub init_string: Gc<ObjString>,
+
+    /// Where `print` writes to, stdout unless the VM was made with
+    /// `VM::with_output`
+    pub output: Box<dyn Write>,
+
+    /// Where `readLine` reads from, stdin when `None`. Set it to feed a
+    /// script its input, e.g. a `Cursor` over a string
+    pub input: Option<Box<dyn BufRead>>,
+
+    /// Before running each instruction, write the stack and the disassembled
+    /// instruction to `output` (clox's `DEBUG_TRACE_EXECUTION`)
+    pub trace: bool,
+
+    /// Whether dividing by zero follows IEEE 754 (the default) or is an error
+    pub divide_by_zero: DivByZero,
+
+    /// Count every instruction run by its opcode, see [`VM::opcode_counts`]
+    pub profile: bool,
+    opcode_counts: Box<[u64; 256]>,
+
+    /// When the VM was made, what `clock` counts from
+    started: Instant,
+    /// Where `random` and `randomInt` get their numbers
+    rng: Rng,
+}
+
+impl Drop for VM {
+    fn drop(&mut self) {
+        // Values are `Copy`, freeing the buffer is all there is to do
+        drop(unsafe { Vec::from_raw_parts(self.stack.stack, 0, self.stack.capacity()) });
+    }
 }
 
 impl VM {
@@ -158,7 +277,7 @@ impl VM {
         let closure = self.mem.alloc_obj(ObjClosure::new(function));
 
         self.call_frames[0] = MaybeUninit::new(CallFrame {
-            instr_offset: 0,
+            ip: function.chunk.code.as_ptr(),
             slots_ptr: self.stack.stack,
             closure,
         });
@@ -167,41 +286,91 @@ impl VM {
             *self.stack.stack = Value::Obj(closure.cast());
             self.stack.top = self.stack.stack.add(1);
         }
-        self.define_native("clock", NativeFnKind::Clock);
-        self.define_native("__dummy", NativeFnKind::Dummy);
 
         self.call_frame_count = 1;
-
-        #[cfg(debug_assertions)]
-        {
-            println!("OBJECT LIST!");
-            for obj in &self.mem.obj_list {
-                println!("{:?}", ObjPtrWrapper(obj.as_ptr()));
-            }
-            println!("END OBJECT LIST");
-        }
     }
 
     pub fn new() -> Self {
+        Self::with_output(Box::new(io::stdout()))
+    }
+
+    /// Makes a VM whose program output goes to `output` instead of stdout,
+    /// e.g. a `Vec<u8>` to check what a script printed
+    pub fn with_output(output: Box<dyn Write>) -> Self {
+        Self::build(output, DEFAULT_STACK_CAPACITY)
+    }
+
+    /// Makes a VM whose stack starts with room for `capacity` values instead
+    /// of `DEFAULT_STACK_CAPACITY`, for scripts known to recurse deeply. The
+    /// capacity is kept between `U8_COUNT` and `STACK_MAX`, the stack still
+    /// grows past it when needed
+    pub fn with_stack_capacity(capacity: usize) -> Self {
+        Self::build(Box::new(io::stdout()), capacity)
+    }
+
+    fn build(output: Box<dyn Write>, capacity: usize) -> Self {
         let mut mem = Mem::new();
-        let mut stack = Vec::<Value>::with_capacity(STACK_MAX);
+        // defined before anything is compiled so the compiler knows they're const
+        for (name, value) in [("Infinity", f64::INFINITY), ("NaN", f64::NAN)] {
+            let name = mem.copy_string(name);
+            let slot = mem.globals.slot(name);
+            mem.globals.set_slot(slot, Some(Value::Number(value)));
+            mem.globals.set_const(slot, true);
+        }
+
+        let mut stack = Vec::<Value>::with_capacity(capacity.clamp(U8_COUNT, STACK_MAX));
         // let raw = Box::into_raw(stack.into_boxed_slice());
 
         let raw = stack.as_mut_ptr();
-        stack.leak();
+        let end = unsafe { raw.add(stack.capacity()) };
+        std::mem::forget(stack);
 
-        Self {
+        let mut vm = Self {
             init_string: mem.copy_string("init"),
             stack: Stack {
                 stack: raw,
-                top: null_mut(),
+                top: raw,
+                end,
             },
             open_upvalues: null_mut(),
             call_frames: [MaybeUninit::uninit(); FRAMES_MAX],
             call_frame_count: 0,
             mem,
             grey_stack: vec![],
+            output,
+            input: None,
+            trace: false,
+            divide_by_zero: DivByZero::default(),
+            profile: false,
+            opcode_counts: Box::new([0; 256]),
+            started: Instant::now(),
+            rng: Rng::from_time(),
+        };
+        // Once, not in `init`, so a script that redefines one keeps its own
+        // value in the next run
+        for &(name, function) in NATIVES {
+            vm.define_native(name, function);
         }
+        vm
+    }
+
+    /// Starts over like a new VM, forgetting the globals and everything in
+    /// `mem` and the opcode counts, but keeps `output`, `input`, whether to
+    /// `trace` and `profile`, `divide_by_zero`, the random generator and the
+    /// stack's capacity
+    pub fn clear(&mut self) {
+        let output = std::mem::replace(&mut self.output, Box::new(io::sink()));
+        let input = self.input.take();
+        let trace = self.trace;
+        let divide_by_zero = self.divide_by_zero;
+        let profile = self.profile;
+        let rng = self.rng.clone();
+        *self = Self::build(output, self.stack_capacity());
+        self.input = input;
+        self.trace = trace;
+        self.divide_by_zero = divide_by_zero;
+        self.profile = profile;
+        self.rng = rng;
     }
 
     fn iter_stack(&self) -> StackIter {
@@ -252,7 +421,7 @@ impl VM {
             }
 
             self.mem.obj_list.remove(i);
-            Obj::free(obj_ptr.as_non_null_ptr())
+            self.mem.bytes_allocated -= Obj::free(obj_ptr.as_non_null_ptr());
         }
     }
 
@@ -351,17 +520,78 @@ impl VM {
         self.mem.copy_string(string)
     }
 
+    /// How many times each opcode ran while `profile` was set, leaving out
+    /// the ones that never did
+    pub fn opcode_counts(&self) -> HashMap<Opcode, u64> {
+        self.opcode_counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .filter_map(|(byte, &count)| Some((Opcode::from_u8(byte as u8)?, count)))
+            .collect()
+    }
+
+    /// Restarts the generator behind `random` and `randomInt`, scripts then
+    /// get the same numbers every run
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    /// How many values the stack has room for before it has to grow
+    pub fn stack_capacity(&self) -> usize {
+        self.stack.capacity()
+    }
+
     #[inline]
     fn push(&mut self, val: Value) {
         // unsafe {
         //     *self.stack.as_mut_ptr().add(self.stack_top as usize) = MaybeUninit::new(val);
         // }
+        if self.stack.top == self.stack.end {
+            self.grow_stack();
+        }
         unsafe {
             *(self.stack.top) = val;
             self.stack.offset(1);
         }
     }
 
+    /// Doubles the stack's capacity. Moving it means every pointer into the
+    /// old stack, the call frames' slots and the open upvalues, has to be
+    /// moved along
+    #[cold]
+    #[inline(never)]
+    fn grow_stack(&mut self) {
+        let old = self.stack.stack as usize;
+        let len = self.stack.len();
+        let capacity = self.stack.capacity();
+
+        let mut values = unsafe { Vec::from_raw_parts(self.stack.stack, len, capacity) };
+        values.reserve_exact(capacity);
+        let new = values.as_mut_ptr();
+        let new_capacity = values.capacity();
+        std::mem::forget(values);
+
+        let relocate = |ptr: *mut Value| unsafe {
+            new.add((ptr as usize - old) / std::mem::size_of::<Value>())
+        };
+
+        self.stack = Stack {
+            stack: new,
+            top: unsafe { new.add(len) },
+            end: unsafe { new.add(new_capacity) },
+        };
+        for i in 0..self.call_frame_count as usize {
+            let frame = unsafe { self.call_frames[i].assume_init_mut() };
+            frame.slots_ptr = relocate(frame.slots_ptr);
+        }
+        let mut upvalue = self.open_upvalues;
+        while let Some(open) = unsafe { upvalue.as_mut() } {
+            open.location = unsafe { NonNull::new_unchecked(relocate(open.location.as_ptr())) };
+            upvalue = open.next;
+        }
+    }
+
     #[inline]
     fn pop(&mut self) -> Value {
         self.stack.sub(1);
@@ -369,57 +599,111 @@ impl VM {
     }
 
     #[inline]
-    fn binary_op<F: FnOnce(Value, Value) -> Value>(&mut self, f: F) -> InterpretResult<()> {
-        if !matches!(self.peek(0), Value::Number(_)) || !matches!(self.peek(1), Value::Number(_)) {
-            self.runtime_error("Operands must be two numbers or two strings.".into());
-            return Err(InterpretError::RuntimeError);
-        }
-
+    fn binary_op<F>(&mut self, f: F) -> InterpretResult<()>
+    where
+        F: FnOnce(Value, Value) -> Result<Value, RuntimeErrorKind>,
+    {
         let b = self.pop();
         let a = self.pop();
-        self.push(f(a, b));
-
-        Ok(())
+        match f(a, b) {
+            Ok(value) => {
+                self.push(value);
+                Ok(())
+            }
+            Err(kind) => Err(self.operator_error(kind)),
+        }
     }
 
+    /// Errors on a zero divisor when `divide_by_zero` asks for it, operands
+    /// that aren't numbers are left for the operator to report
     #[inline]
-    fn reset_stack(&mut self) {
-        self.stack.top = self.stack.stack;
-        self.call_frame_count = 0;
-        self.open_upvalues = null_mut();
+    fn check_divisor(&mut self) -> InterpretResult<()> {
+        match (self.divide_by_zero, self.peek(1), self.peek(0)) {
+            (DivByZero::Error, Value::Number(_), Value::Number(b)) if b == 0.0 => {
+                Err(self.operator_error(RuntimeErrorKind::DivisionByZero))
+            }
+            _ => Ok(()),
+        }
     }
 
-    fn runtime_error<'a>(&mut self, err: Cow<'a, str>) {
-        eprintln!("{err}");
-
-        let frame = self.top_call_frame();
-        let instr_idx = frame.instr_offset;
-        let line = frame.function().chunk.lines[instr_idx as usize];
+    #[cold]
+    fn operator_error(&mut self, kind: RuntimeErrorKind) -> InterpretError {
+        self.runtime_error(kind.to_string().into())
+    }
 
-        eprintln!("[line {line}] in script");
+    /// Clears the value stack, the call frames and the open upvalues so
+    /// another script can run from scratch, e.g. after one stopped with a
+    /// runtime error. Open upvalues are closed first, so closures that
+    /// outlive the run keep the values they captured.
+    ///
+    /// Everything in `mem` is kept: globals (natives included), interned
+    /// strings and any objects still reachable from them. So are the VM's
+    /// settings, I/O and counters: `output`, `input`, `trace`,
+    /// `divide_by_zero`, `profile` and the opcode counts so far, the random
+    /// generator and the stack's capacity
+    pub fn reset(&mut self) {
+        self.close_upvalues(self.stack.stack);
+        self.stack.top = self.stack.stack;
+        self.call_frame_count = 0;
+    }
 
-        for frame in self
-            .call_frames
-            .iter()
-            .take(self.call_frame_count as usize - 1)
+    /// Builds a runtime error with a trace of the active call frames, then
+    /// resets the stack so the VM can be reused. Printing it is up to the caller
+    pub fn runtime_error<'a>(&mut self, err: Cow<'a, str>) -> InterpretError {
+        let frames: Vec<TraceFrame> = self
+            .iter_frames()
+            .collect::<Vec<_>>()
+            .into_iter()
             .rev()
-        {
-            unsafe {
-                let frame = frame.assume_init();
+            .map(|frame| {
                 let function = frame.function();
-                let instruction = frame.instr_offset;
-                eprintln!(
-                    "[line {}] in {}",
-                    function.chunk.lines[instruction as usize],
-                    match function.name.as_ref() {
-                        Some(name) => name.as_str(),
-                        None => "script",
-                    }
+                // `ip` has already moved past the failing instruction's opcode
+                let offset = frame.instr_offset() - 1;
+                TraceFrame {
+                    function: unsafe { function.name.as_ref() }
+                        .map(|name| name.as_str().to_string()),
+                    line: function.chunk.line_at(offset),
+                    file: function.chunk.file_at(offset).map(str::to_string),
+                }
+            })
+            .collect();
+
+        self.reset();
+        InterpretError::RuntimeError {
+            line: frames.first().map_or(0, |frame| frame.line),
+            message: err.into_owned(),
+            frames,
+        }
+    }
+
+    /// Checks that `list` is a list and `index` a whole number in its bounds
+    fn list_index(&mut self, list: Value, index: Value) -> InterpretResult<(Gc<ObjList>, usize)> {
+        let list = match list.as_list() {
+            Some(list) => list,
+            None => return Err(self.runtime_error("Can only index into lists and maps.".into())),
+        };
+        let index = match index {
+            Value::Number(index) if index.fract() == 0.0 => index,
+            _ => return Err(self.runtime_error("List index must be an integer.".into())),
+        };
+        if index < 0.0 || index >= list.items.len() as f64 {
+            return Err(self.runtime_error(
+                format!(
+                    "List index {index} out of bounds for length {}.",
+                    list.items.len()
                 )
-            }
+                .into(),
+            ));
         }
 
-        self.reset_stack();
+        Ok((list, index as usize))
+    }
+
+    fn map_key(&mut self, key: Value) -> InterpretResult<MapKey> {
+        match MapKey::from_value(key) {
+            Some(key) => Ok(key),
+            None => Err(self.runtime_error("Map keys must be strings or numbers.".into())),
+        }
     }
 
     fn peek(&self, distance: u32) -> Value {
@@ -465,30 +749,56 @@ impl VM {
         self.push(Value::Obj(obj_str.cast()))
     }
 
-    fn call(&mut self, closure: Gc<ObjClosure>, arg_count: u8) -> bool {
+    fn call(&mut self, closure: Gc<ObjClosure>, arg_count: u8) -> InterpretResult<()> {
         let arity = closure.as_ref().function.as_ref().arity;
         if arg_count != arity {
-            self.runtime_error(format!("Expected {arg_count} arguments but got {arity}.").into());
-            return false;
+            return Err(self
+                .runtime_error(format!("Expected {arity} arguments but got {arg_count}.").into()));
         }
 
-        if self.call_frame_count as usize == FRAMES_MAX {
-            self.runtime_error("Stack overflow.".into());
-            return false;
+        // A frame's locals take at most `U8_COUNT` slots
+        if self.call_frame_count as usize == FRAMES_MAX || self.stack.len() + U8_COUNT > STACK_MAX {
+            return Err(self.runtime_error("Stack overflow.".into()));
         }
 
         self.next_call_frame(closure, arg_count);
 
-        true
+        Ok(())
+    }
+
+    /// Runs `closure` in the current frame instead of pushing a new one, the
+    /// callee and its arguments are moved down over the caller's slots
+    fn tail_call(&mut self, closure: Gc<ObjClosure>, arg_count: u8) -> InterpretResult<()> {
+        let arity = closure.as_ref().function.as_ref().arity;
+        if arg_count != arity {
+            return Err(self
+                .runtime_error(format!("Expected {arity} arguments but got {arg_count}.").into()));
+        }
+
+        let slots_ptr = self.top_call_frame().slots_ptr;
+        self.close_upvalues(slots_ptr);
+        let len = arg_count as usize + 1;
+        unsafe {
+            std::ptr::copy(self.stack.top.sub(len), slots_ptr, len);
+            self.stack.top = slots_ptr.add(len);
+        }
+
+        let frame = self.top_call_frame_mut();
+        frame.ip = closure.function.chunk.code.as_ptr();
+        frame.closure = closure;
+
+        Ok(())
     }
 
-    fn define_native(&mut self, name: &str, native_fn_kind: NativeFnKind) {
+    /// Registers a native function as a global, e.g.
+    /// `vm.define_native("double", double)`
+    pub fn define_native(&mut self, name: &str, function: NativeFn) {
         // We don't want/need to trigger GC here so directly call allocation
         // functions on `self.mem`
 
         let name = Value::Obj(self.mem.copy_string(name).cast());
 
-        let native_fn = Value::Obj(self.mem.alloc_obj(ObjNative::new(native_fn_kind)).cast());
+        let native_fn = Value::Obj(self.mem.alloc_obj(ObjNative::new(function)).cast());
 
         self.push(name);
         self.push(native_fn);
@@ -508,7 +818,195 @@ impl VM {
         self.pop();
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
+    fn native_dummy(&mut self, _args: &[Value]) -> InterpretResult<Value> {
+        Ok(Value::Number(420.0))
+    }
+
+    /// `typeof(x)`, the name of `x`'s type as a string
+    fn native_typeof(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let value = self.native_arg(args)?;
+        let name = self.mem.copy_string(value.type_name());
+        Ok(Value::Obj(name.cast()))
+    }
+
+    /// `len(x)`, how many characters (not bytes) a string has or how many
+    /// items a list or map has
+    fn native_len(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let value = self.native_arg(args)?;
+        let len = if let Some(string) = value.as_str() {
+            string.chars().count()
+        } else if let Some(list) = value.as_list() {
+            list.items.len()
+        } else if let Some(map) = value.as_map() {
+            map.entries.len()
+        } else {
+            let message = format!(
+                "Expected a string, list or map but got {}.",
+                value.type_name()
+            );
+            return Err(self.runtime_error(message.into()));
+        };
+        Ok(Value::Number(len as f64))
+    }
+
+    /// `readLine()`, the next line of `input` without its line ending, or nil
+    /// at the end of the input
+    fn native_read_line(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        if !args.is_empty() {
+            let message = format!("Expected 0 arguments but got {}.", args.len());
+            return Err(self.runtime_error(message.into()));
+        }
+
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Some(input) => input.read_line(&mut line),
+            None => io::stdin().lock().read_line(&mut line),
+        };
+        match read {
+            Ok(0) => Ok(Value::Nil),
+            Ok(_) => {
+                let len = line.trim_end_matches(['\n', '\r']).len();
+                line.truncate(len);
+                Ok(Value::Obj(self.mem.copy_string(&line).cast()))
+            }
+            Err(err) => Err(self.runtime_error(format!("Failed to read input: {err}").into())),
+        }
+    }
+
+    /// `getField(instance, name)`, the field with a computed name, or nil if the
+    /// instance doesn't have it
+    fn native_get_field(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let [instance, name] = self.native_args(args)?;
+        let (instance, name) = self.field_args(instance, name)?;
+        Ok(instance
+            .fields
+            .get(name.as_non_null_ptr())
+            .unwrap_or(Value::Nil))
+    }
+
+    /// `setField(instance, name, value)`, sets the field with a computed name
+    /// and returns the value like an assignment
+    fn native_set_field(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let [instance, name, value] = self.native_args(args)?;
+        let (mut instance, name) = self.field_args(instance, name)?;
+        instance.fields.set(name.as_non_null_ptr(), value);
+        Ok(value)
+    }
+
+    /// `str(x)`, any value as the string `print` would show
+    fn native_str(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let value = self.native_arg(args)?;
+        if value.as_obj_str().is_some() {
+            return Ok(value);
+        }
+        let string = self.mem.copy_string(&value.to_string());
+        Ok(Value::Obj(string.cast()))
+    }
+
+    /// `num(s)`, the number a string spells as a Lox number literal, with an
+    /// optional leading `-` and surrounding whitespace, or nil if it isn't one
+    fn native_num(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let value = self.native_arg(args)?;
+        let string = match value.as_str() {
+            Some(string) => string.trim(),
+            None => {
+                let message = format!("Expected a string but got {}.", value.type_name());
+                return Err(self.runtime_error(message.into()));
+            }
+        };
+
+        let (sign, digits) = match string.strip_prefix('-') {
+            Some(digits) => (-1.0, digits),
+            None => (1.0, string),
+        };
+        let mut tokens = tokens(digits);
+        Ok(match (tokens.next(), tokens.next()) {
+            (Some(token), None) if token.kind == TokenKind::Number && token.msg == digits => {
+                Value::Number(sign * token.number_value())
+            }
+            _ => Value::Nil,
+        })
+    }
+
+    /// `clock()`, seconds since the VM was made
+    fn native_clock(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let [] = self.native_args(args)?;
+        Ok(Value::Number(self.started.elapsed().as_secs_f64()))
+    }
+
+    /// `sleep(ms)`, pauses the script for `ms` milliseconds and returns nil
+    fn native_sleep(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let ms = match self.native_arg(args)? {
+            Value::Number(ms) if ms >= 0.0 => ms,
+            value => {
+                let message =
+                    format!("Expected a non-negative number of milliseconds but got {value}.");
+                return Err(self.runtime_error(message.into()));
+            }
+        };
+        let duration = match Duration::try_from_secs_f64(ms / 1000.0) {
+            Ok(duration) => duration,
+            Err(_) => {
+                let message = format!("Can't sleep for {ms} milliseconds, it's too long.");
+                return Err(self.runtime_error(message.into()));
+            }
+        };
+        std::thread::sleep(duration);
+        Ok(Value::Nil)
+    }
+
+    /// `random()`, a number in [0, 1) from the VM's generator, see
+    /// `VM::seed_rng`
+    fn native_random(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let [] = self.native_args(args)?;
+        Ok(Value::Number(self.rng.next_f64()))
+    }
+
+    /// `randomInt(n)`, a whole number in [0, n) from the same generator
+    fn native_random_int(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let n = match self.native_arg(args)? {
+            Value::Number(n) if n >= 1.0 && n.fract() == 0.0 && n.is_finite() => n,
+            value => {
+                let message = format!("Expected a positive whole number but got {value}.");
+                return Err(self.runtime_error(message.into()));
+            }
+        };
+        Ok(Value::Number((self.rng.next_f64() * n).floor()))
+    }
+
+    /// Checks the instance and field name passed to `getField`/`setField`.
+    /// Strings are interned so the name can be looked up as is
+    fn field_args(
+        &mut self,
+        instance: Value,
+        name: Value,
+    ) -> InterpretResult<(Gc<ObjInstance>, Gc<ObjString>)> {
+        let message = match (instance.as_instance_fn(), name.as_obj_str()) {
+            (Some(instance), Some(name)) => return Ok((instance, name)),
+            (None, _) => format!("Expected an instance but got {}.", instance.type_name()),
+            (_, None) => format!("Expected a string field name but got {}.", name.type_name()),
+        };
+        Err(self.runtime_error(message.into()))
+    }
+
+    /// The argument of a native that takes exactly one
+    fn native_arg(&mut self, args: &[Value]) -> InterpretResult<Value> {
+        let [value] = self.native_args(args)?;
+        Ok(value)
+    }
+
+    /// The arguments of a native that takes exactly `N`
+    fn native_args<const N: usize>(&mut self, args: &[Value]) -> InterpretResult<[Value; N]> {
+        match args.try_into() {
+            Ok(args) => Ok(args),
+            Err(_) => {
+                let message = format!("Expected {N} arguments but got {}.", args.len());
+                Err(self.runtime_error(message.into()))
+            }
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> InterpretResult<()> {
         match callee {
             Value::Obj(obj) => {
                 let kind = obj.as_ref().kind;
@@ -528,13 +1026,12 @@ impl VM {
                         }
 
                         if arg_count != 0 {
-                            self.runtime_error(
-                                format!("Expected 0 arguments but got {:?}", arg_count).into(),
-                            );
-                            return false;
+                            return Err(self.runtime_error(
+                                format!("Expected 0 arguments but got {arg_count}.").into(),
+                            ));
                         }
 
-                        return true;
+                        return Ok(());
                     }
                     ObjKind::Closure => return self.call(obj.cast(), arg_count),
                     ObjKind::Native => {
@@ -545,18 +1042,17 @@ impl VM {
                                 arg_count as usize,
                             )
                         };
-                        let result =
-                            unsafe { native.as_ref().function.call(std::mem::transmute(values)) };
+                        let result = (native.as_ref().function)(self, values)?;
 
                         self.stack.sub(arg_count as u32 + 1);
 
                         self.push(result);
-                        return true;
+                        return Ok(());
                     }
                     ObjKind::BoundMethod => {
                         let bound: Gc<ObjBoundMethod> = obj.cast();
-                        self.stack
-                            .set(arg_count as u32 + 1, bound.as_ref().receiver);
+                        // the receiver replaces the bound method in the callee's slot 0
+                        self.stack.set(arg_count as u32, bound.as_ref().receiver);
                         return self.call(bound.method, arg_count);
                     }
                     _ => (),
@@ -565,8 +1061,7 @@ impl VM {
             _ => {}
         }
 
-        self.runtime_error("Can only call functions and classes.".into());
-        false
+        Err(self.runtime_error("Can only call functions and classes.".into()))
     }
 
     fn capture_upvalue(&mut self, local: NonNull<Value>) -> Gc<ObjUpvalue> {
@@ -673,13 +1168,13 @@ impl VM {
         self.pop();
     }
 
-    fn bind_method(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> bool {
+    fn bind_method(&mut self, class: Gc<ObjClass>, name: Gc<ObjString>) -> InterpretResult<()> {
         let method = match class.methods.get(name.as_non_null_ptr()) {
             Some(method) => method,
             None => {
-                self.runtime_error(format!("Undefined property {}", name.as_str()).into());
-
-                return false;
+                return Err(
+                    self.runtime_error(format!("Undefined property: {}", name.as_str()).into())
+                );
             }
         };
 
@@ -690,16 +1185,15 @@ impl VM {
         self.pop();
         self.push(Value::Obj(bound.cast()));
 
-        true
+        Ok(())
     }
 
-    fn invoke(&mut self, name: Gc<ObjString>, arg_count: u8) -> bool {
+    fn invoke(&mut self, name: Gc<ObjString>, arg_count: u8) -> InterpretResult<()> {
         let receiver = self.peek(arg_count as u32);
         let instance = match receiver.as_instance_fn() {
             Some(inst) => inst,
             None => {
-                self.runtime_error("Only instances have methods.".into());
-                return false;
+                return Err(self.runtime_error("Only instances have methods.".into()));
             }
         };
 
@@ -716,57 +1210,61 @@ impl VM {
         class: Gc<ObjClass>,
         name: Gc<ObjString>,
         arg_count: u8,
-    ) -> bool {
+    ) -> InterpretResult<()> {
         let method = class.methods.get(name.as_non_null_ptr());
         match method {
-            Some(method) => {
-                self.call(method.as_obj_closure().unwrap(), arg_count);
-                true
-            }
+            Some(method) => self.call(method.as_obj_closure().unwrap(), arg_count),
             None => {
-                self.runtime_error(format!("Undefined property {}", name.as_str()).into());
-                false
+                Err(self.runtime_error(format!("Undefined property: {}", name.as_str()).into()))
             }
         }
     }
 
-    pub fn run(&mut self) -> InterpretResult<()> {
-        loop {
-            #[cfg(debug_assertions)]
-            {
-                // Debug frame window
-                let slot_addr = self.top_call_frame().slots_ptr as usize;
-                println!("          Frame slot addr: {}", slot_addr);
-                // Debug stack
-                let take_amount =
-                    (self.stack.top as usize - slot_addr) / std::mem::size_of::<Value>();
-                for (i, slot) in self.stack.iter().take(take_amount).enumerate() {
-                    let value = slot;
-                    println!("          {i}: {value:?}");
-                }
+    // Kept out of line so the untraced dispatch loop stays tight
+    #[cold]
+    #[inline(never)]
+    fn trace_instruction(&mut self) -> InterpretResult<()> {
+        use std::fmt::Write as _;
+
+        let mut stack = String::from("          ");
+        for value in self.stack.iter() {
+            let _ = write!(stack, "[ {value:?} ]");
+        }
+
+        let frame = self.top_call_frame();
+        let mut offset = frame.instr_offset();
+        let instruction = frame
+            .function()
+            .chunk
+            .disassemble_instruction_string(&mut offset);
+
+        if let Err(err) = writeln!(self.output, "{stack}\n{instruction}") {
+            return Err(self.runtime_error(format!("Failed to write trace: {err}").into()));
+        }
+        Ok(())
+    }
 
-                // Debug instruction
-                let frame = self.top_call_frame();
-                let mut duplicate_instruction_index = frame.instr_offset as usize;
-                let line = frame.function().chunk.lines[duplicate_instruction_index];
-                let inner = frame
-                    .function()
-                    .chunk
-                    .disassemble_instruction(&mut duplicate_instruction_index);
-                println!("{:?}", inner.map(|inner| InstructionDebug { line, inner }));
+    /// Runs until the script returns, yielding its return value
+    pub fn run(&mut self) -> InterpretResult<Value> {
+        loop {
+            if self.trace {
+                self.trace_instruction()?;
             }
 
             let byte = self.read_byte();
+            if self.profile {
+                self.opcode_counts[byte as usize] += 1;
+            }
 
+            // Opcodes are dense from 0 so this compiles down to a jump table,
+            // Rust has no computed goto to do better than that
             match Opcode::from_u8(byte) {
                 Some(Opcode::SuperInvoke) => {
                     let method = self.read_constant().as_obj_str().unwrap();
                     let arg_count = self.read_byte();
                     let superclass = self.pop().as_class().unwrap();
 
-                    if !self.invoke_from_class(superclass, method, arg_count) {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.invoke_from_class(superclass, method, arg_count)?;
                 }
                 Some(Opcode::GetSuper) => {
                     // The name of the class
@@ -774,17 +1272,14 @@ impl VM {
 
                     let superclass = self.pop().as_class().unwrap();
 
-                    if !self.bind_method(superclass, name) {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.bind_method(superclass, name)?;
                 }
                 Some(Opcode::Inherit) => {
                     let superclass = self.peek(1);
                     let superclass = match superclass.as_class() {
                         Some(class) => class,
                         None => {
-                            self.runtime_error("Superclass must be a class.".into());
-                            return Err(InterpretError::RuntimeError);
+                            return Err(self.runtime_error("Superclass must be a class.".into()));
                         }
                     };
 
@@ -798,9 +1293,7 @@ impl VM {
                 Some(Opcode::Invoke) => {
                     let method = self.read_constant().as_obj_str().unwrap();
                     let arg_count = self.read_byte();
-                    if !self.invoke(method, arg_count) {
-                        return Err(InterpretError::RuntimeError);
-                    }
+                    self.invoke(method, arg_count)?;
                 }
                 Some(Opcode::Method) => {
                     let obj_str = self.read_constant().as_obj_str().unwrap();
@@ -811,8 +1304,9 @@ impl VM {
                     let instance = match top.as_instance_fn() {
                         Some(instance) => instance,
                         None => {
-                            self.runtime_error("Only instances have properties.".into());
-                            return Err(InterpretError::RuntimeError);
+                            return Err(
+                                self.runtime_error("Only instances have properties.".into())
+                            );
                         }
                     };
 
@@ -827,9 +1321,7 @@ impl VM {
                             self.push(val);
                         }
                         None => {
-                            if !self.bind_method(unsafe { &*instance.as_ptr() }.class, name) {
-                                return Err(InterpretError::RuntimeError);
-                            }
+                            self.bind_method(unsafe { &*instance.as_ptr() }.class, name)?;
                         }
                     }
                 }
@@ -838,8 +1330,7 @@ impl VM {
                     let mut instance = match top.as_instance_fn() {
                         Some(instance) => instance,
                         None => {
-                            self.runtime_error("Only instances have fields.".into());
-                            return Err(InterpretError::RuntimeError);
+                            return Err(self.runtime_error("Only instances have fields.".into()));
                         }
                     };
 
@@ -914,22 +1405,31 @@ impl VM {
                 }
                 Some(Opcode::Call) => {
                     let arg_count = self.read_byte();
-                    if !self.call_value(self.peek(arg_count as u32), arg_count) {
-                        return Err(InterpretError::RuntimeError);
+                    self.call_value(self.peek(arg_count as u32), arg_count)?;
+                }
+                Some(Opcode::TailCall) => {
+                    let arg_count = self.read_byte();
+                    let callee = self.peek(arg_count as u32);
+                    match callee.as_obj_closure() {
+                        Some(closure) => self.tail_call(closure, arg_count)?,
+                        None => self.call_value(callee, arg_count)?,
                     }
                 }
                 Some(Opcode::Loop) => {
                     let offset = self.read_u16();
-                    self.top_call_frame_mut().instr_offset -= offset as u32;
+                    let frame = self.top_call_frame_mut();
+                    frame.ip = unsafe { frame.ip.sub(offset as usize) };
                 }
                 Some(Opcode::Jump) => {
                     let offset = self.read_u16();
-                    self.top_call_frame_mut().instr_offset += offset as u32;
+                    let frame = self.top_call_frame_mut();
+                    frame.ip = unsafe { frame.ip.add(offset as usize) };
                 }
                 Some(Opcode::JumpIfFalse) => {
                     let offset = self.read_u16();
                     if self.peek(0).is_falsey() {
-                        self.top_call_frame_mut().instr_offset += offset as u32;
+                        let frame = self.top_call_frame_mut();
+                        frame.ip = unsafe { frame.ip.add(offset as usize) };
                     }
                 }
                 Some(Opcode::GetLocal) => {
@@ -938,6 +1438,10 @@ impl VM {
 
                     self.push(val);
                 }
+                Some(Opcode::GetLocal0) => self.push(self.top_call_frame().index(0)),
+                Some(Opcode::GetLocal1) => self.push(self.top_call_frame().index(1)),
+                Some(Opcode::GetLocal2) => self.push(self.top_call_frame().index(2)),
+                Some(Opcode::GetLocal3) => self.push(self.top_call_frame().index(3)),
                 Some(Opcode::SetLocal) => {
                     let slot = self.read_byte();
                     let val = self.peek(0);
@@ -949,15 +1453,19 @@ impl VM {
                         .as_obj_str()
                         .expect("Expect string constant for global variable name.");
 
-                    let new_val = self.peek(0);
-                    // println!("{} = {:?}", unsafe { name.as_ref() }.as_str(), self.peek(0));
-
-                    if self.mem.globals.set(name.as_non_null_ptr(), new_val) {
-                        self.mem.globals.delete(name.as_non_null_ptr());
-                        self.runtime_error(format!("Undefined variable: {}", name.as_str()).into());
-
-                        return Err(InterpretError::RuntimeError);
+                    let slot = self.mem.globals.slot(name);
+                    if self.mem.globals.get_slot(slot).is_none() {
+                        return Err(self.runtime_error(
+                            format!("Undefined variable: {}", name.as_str()).into(),
+                        ));
                     }
+                    // the compiler only catches assignments compiled after the `const`
+                    if self.mem.globals.is_const(slot) {
+                        return Err(self.runtime_error(
+                            format!("Cannot assign to const '{}'.", name.as_str()).into(),
+                        ));
+                    }
+                    self.mem.globals.set_slot(slot, Some(self.peek(0)));
                 }
                 Some(Opcode::GetGlobal) => {
                     let name = self
@@ -965,14 +1473,13 @@ impl VM {
                         .as_obj_str()
                         .expect("Expect string constant for global variable name.");
 
-                    let val = match self.mem.globals.get(name.as_non_null_ptr()) {
+                    let slot = self.mem.globals.slot(name);
+                    let val = match self.mem.globals.get_slot(slot) {
                         Some(global) => global,
                         None => {
-                            self.runtime_error(
+                            return Err(self.runtime_error(
                                 format!("Undefined variable: {}", name.as_str()).into(),
-                            );
-
-                            return Err(InterpretError::RuntimeError);
+                            ));
                         }
                     };
 
@@ -984,7 +1491,8 @@ impl VM {
                         .as_obj_str()
                         .expect("Expect string constant for global variable name.");
 
-                    self.mem.globals.set(name.as_non_null_ptr(), self.peek(0));
+                    let slot = self.mem.globals.slot(name);
+                    self.mem.globals.set_slot(slot, Some(self.peek(0)));
                     self.pop();
                 }
                 Some(Opcode::Nil) => {
@@ -999,9 +1507,132 @@ impl VM {
                 Some(Opcode::Pop) => {
                     self.pop();
                 }
+                Some(Opcode::Assert) => {
+                    let text = self.read_constant();
+                    if self.pop().is_falsey() {
+                        return Err(self.runtime_error(format!("Assertion failed: {text}").into()));
+                    }
+                }
+                Some(Opcode::BuildList) => {
+                    let count = self.read_byte() as usize;
+                    // The items stay on the stack, and so reachable, until
+                    // the list holding them is
+                    let items =
+                        unsafe { std::slice::from_raw_parts(self.stack.top.sub(count), count) }
+                            .to_vec();
+                    let list = self.alloc_obj(ObjList::new(items));
+                    self.stack.sub(count as u32);
+                    self.push(Value::Obj(list.cast()));
+                }
+                Some(Opcode::Iter) => {
+                    let iterable = self.peek(0);
+                    if let Some(map) = iterable.as_map() {
+                        let keys = map.entries.iter().map(|(key, _)| key.to_value()).collect();
+                        // the map stays on the stack, keeping its keys alive
+                        let keys = self.alloc_obj(ObjList::new(keys));
+                        self.stack.set(0, Value::Obj(keys.cast()));
+                    } else if iterable.as_list().is_none() {
+                        let message = format!(
+                            "Can only iterate over lists and maps, not {}.",
+                            iterable.type_name()
+                        );
+                        return Err(self.runtime_error(message.into()));
+                    }
+                }
+                Some(Opcode::Len) => {
+                    let list = self.pop().as_list().expect("Expect a list from `Iter`.");
+                    self.push(Value::Number(list.items.len() as f64));
+                }
+                Some(Opcode::Unpack) => {
+                    let count = self.read_byte() as usize;
+                    let value = self.pop();
+                    let list = match value.as_list() {
+                        Some(list) => list,
+                        None => {
+                            let message =
+                                format!("Can only destructure lists, not {}.", value.type_name());
+                            return Err(self.runtime_error(message.into()));
+                        }
+                    };
+                    if list.items.len() < count {
+                        let message = format!(
+                            "Expected at least {count} items to destructure but got {}.",
+                            list.items.len()
+                        );
+                        return Err(self.runtime_error(message.into()));
+                    }
+                    // nothing allocates, the list can't be freed before its
+                    // items are pushed
+                    for &item in &list.items[..count] {
+                        self.push(item);
+                    }
+                }
+                Some(Opcode::BuildMap) => {
+                    let count = self.read_byte() as usize;
+                    let entries = unsafe {
+                        std::slice::from_raw_parts(self.stack.top.sub(count * 2), count * 2)
+                    };
+                    let mut map = ObjMap::new();
+                    for entry in entries.chunks_exact(2) {
+                        let key = self.map_key(entry[0])?;
+                        map.insert(key, entry[1]);
+                    }
+                    // Like lists, the entries stay reachable on the stack
+                    // until the map is
+                    let map = self.alloc_obj(map);
+                    self.stack.sub(count as u32 * 2);
+                    self.push(Value::Obj(map.cast()));
+                }
+                Some(Opcode::IndexGet) => {
+                    let index = self.pop();
+                    let target = self.pop();
+                    // a missing key reads as nil
+                    let value = match target.as_map() {
+                        Some(map) => map.get(self.map_key(index)?).unwrap_or(Value::Nil),
+                        None => {
+                            let (list, index) = self.list_index(target, index)?;
+                            list.items[index]
+                        }
+                    };
+                    self.push(value);
+                }
+                Some(Opcode::IndexSet) => {
+                    let value = self.pop();
+                    let index = self.pop();
+                    let target = self.pop();
+                    match target.as_map() {
+                        Some(mut map) => {
+                            let key = self.map_key(index)?;
+                            map.insert(key, value);
+                        }
+                        None => {
+                            let (mut list, index) = self.list_index(target, index)?;
+                            list.items[index] = value;
+                        }
+                    }
+                    self.push(value);
+                }
+                Some(Opcode::Dup) => {
+                    self.push(self.peek(0));
+                }
+                Some(Opcode::PopN) => {
+                    let count = self.read_byte();
+                    self.stack.sub(count as u32);
+                }
                 Some(Opcode::Print) => {
                     let value = self.pop();
-                    println!("{value:?}");
+                    if let Err(err) = writeln!(self.output, "{value}") {
+                        return Err(self.runtime_error(format!("Failed to print: {err}").into()));
+                    }
+                }
+                Some(Opcode::Write) => {
+                    let value = self.pop();
+                    // no newline to flush a line buffered `output`, e.g. a
+                    // prompt written before `readLine`
+                    let written = write!(self.output, "{value}").and_then(|()| self.output.flush());
+                    if let Err(err) = written {
+                        return Err(self.runtime_error(format!("Failed to print: {err}").into()));
+                    }
                 }
                 Some(Opcode::Equal) => {
                     let b = self.pop();
@@ -1013,22 +1644,16 @@ impl VM {
                     let top = self.pop();
                     self.push(Value::Bool(top.is_falsey()))
                 }
-                Some(Opcode::Negate) => {
-                    if !matches!(self.peek(0), Value::Bool(_) | Value::Number(_)) {
-                        self.runtime_error("Operand must be a number.".into());
-                        return Err(InterpretError::RuntimeError);
-                    }
-
-                    let negated = -self.pop();
-                    self.push(negated)
-                }
+                Some(Opcode::Negate) => match -self.pop() {
+                    Ok(negated) => self.push(negated),
+                    Err(kind) => return Err(self.operator_error(kind)),
+                },
                 Some(Opcode::Return) => {
+                    let result = self.pop();
                     if self.call_frame_count == 1 {
-                        self.pop();
-                        return Ok(());
+                        return Ok(result);
                     }
 
-                    let result = self.pop();
                     self.close_upvalues(self.top_call_frame().slots_ptr);
 
                     self.stack.top = self.top_call_frame().slots_ptr;
@@ -1039,9 +1664,26 @@ impl VM {
                     let constant = self.read_constant();
                     self.push(constant);
                 }
+                Some(Opcode::ConstantLong) => {
+                    let constant = self.read_constant_long();
+                    self.push(constant);
+                }
                 Some(Opcode::Subtract) => self.binary_op(std::ops::Sub::sub)?,
                 Some(Opcode::Multiply) => self.binary_op(std::ops::Mul::mul)?,
-                Some(Opcode::Divide) => self.binary_op(std::ops::Div::div)?,
+                Some(Opcode::Divide) => {
+                    self.check_divisor()?;
+                    self.binary_op(std::ops::Div::div)?
+                }
+                Some(Opcode::Modulo) => {
+                    self.check_divisor()?;
+                    self.binary_op(std::ops::Rem::rem)?
+                }
+                Some(Opcode::BitAnd) => self.binary_op(std::ops::BitAnd::bitand)?,
+                Some(Opcode::BitOr) => self.binary_op(std::ops::BitOr::bitor)?,
+                Some(Opcode::BitXor) => self.binary_op(std::ops::BitXor::bitxor)?,
+                Some(Opcode::ShiftLeft) => self.binary_op(std::ops::Shl::shl)?,
+                Some(Opcode::ShiftRight) => self.binary_op(std::ops::Shr::shr)?,
+                Some(Opcode::Power) => self.binary_op(Value::pow)?,
                 Some(Opcode::Greater) => {
                     self.binary_op(Value::gt_owned)?;
                 }
@@ -1058,15 +1700,22 @@ impl VM {
         }
     }
 
-    #[inline]
-    fn read_byte(&mut self) -> u8 {
-        let frame =
-            unsafe { self.call_frames[self.call_frame_count as usize - 1].assume_init_mut() };
+    // Safety of the `read_*` functions:
+    // The compiler ends every chunk with `Return` and only emits jumps that
+    // land inside the chunk, so `ip` never moves past the end of the code
+    // and operands are always there to read. `Chunk::from_bytes` checks the
+    // same of loaded bytecode before it gets here, so nothing is checked per
+    // instruction
 
-        let ret = frame.function().chunk.code[frame.instr_offset as usize];
-        frame.instr_offset += 1;
+    #[inline(always)]
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.top_call_frame_mut();
 
-        ret
+        unsafe {
+            let ret = *frame.ip;
+            frame.ip = frame.ip.add(1);
+            ret
+        }
     }
 
     #[inline]
@@ -1074,7 +1723,7 @@ impl VM {
         let call_frame = self.call_frames[self.call_frame_count as usize].as_mut_ptr();
         self.call_frame_count += 1;
         unsafe {
-            (*call_frame).instr_offset = 0;
+            (*call_frame).ip = closure.function.chunk.code.as_ptr();
             (*call_frame).slots_ptr = self.stack.top.offset(-(arg_count as isize) - 1);
             (*call_frame).closure = closure;
         }
@@ -1097,23 +1746,2839 @@ impl VM {
         unsafe { self.call_frames[self.call_frame_count as usize - 1].assume_init_ref() }
     }
 
-    #[inline]
+    #[inline(always)]
     fn read_u16(&mut self) -> u16 {
         let frame = self.top_call_frame_mut();
 
-        let (byte1, byte2) = (
-            frame.function().chunk[frame.instr_offset as usize],
-            frame.function().chunk[frame.instr_offset as usize + 1],
-        );
-
-        frame.instr_offset += 2;
-
-        ((byte1 as u16) << 8) | (byte2 as u16)
+        unsafe {
+            let (byte1, byte2) = (*frame.ip, *frame.ip.add(1));
+            frame.ip = frame.ip.add(2);
+            ((byte1 as u16) << 8) | (byte2 as u16)
+        }
     }
 
-    #[inline]
+    #[inline(always)]
     fn read_constant(&mut self) -> Value {
         let idx = self.read_byte();
         self.top_call_frame().function().chunk.constants[idx as usize]
     }
+
+    #[inline]
+    fn read_constant_long(&mut self) -> Value {
+        let idx = ((self.read_byte() as usize) << 16)
+            | ((self.read_byte() as usize) << 8)
+            | (self.read_byte() as usize);
+        self.top_call_frame().function().chunk.constants[idx]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunk::{Chunk, Opcode, Operand},
+        common::SharedBuf,
+        compile::{compile, Parser},
+        dump_bytecode, eval, format_globals, interpret,
+        mem::Mem,
+        obj::ObjFunction,
+        value::Value,
+        vm::{
+            DivByZero, InterpretError, InterpretResult, TraceFrame, DEFAULT_STACK_CAPACITY,
+            STACK_MAX, U8_COUNT, VM,
+        },
+    };
+
+    #[test]
+    fn write_statement() {
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, r#"write "a"; write "b"; print "";"#).unwrap();
+        assert_eq!(output.contents(), "ab\n");
+
+        let src = r#"
+for (var i = 0; i < 3; i += 1) { write i; write ","; }
+write nil;
+var writer = "still a name";
+print writer;"#;
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "ab\n0,1,2,nilstill a name\n");
+
+        for src in ["write;", "write 1", "var write = 1;"] {
+            assert_eq!(
+                interpret(&mut vm, src),
+                Err(InterpretError::CompileError),
+                "{src}"
+            );
+        }
+
+        /// Marks each flush with a `|` among what was written
+        #[derive(Clone, Default)]
+        struct FlushLog(std::rc::Rc<std::cell::RefCell<String>>);
+
+        impl std::io::Write for FlushLog {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0
+                    .borrow_mut()
+                    .push_str(std::str::from_utf8(buf).unwrap());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.0.borrow_mut().push('|');
+                Ok(())
+            }
+        }
+
+        let log = FlushLog::default();
+        let mut vm = VM::with_output(Box::new(log.clone()));
+        interpret(&mut vm, r#"write "name? "; print "x"; write 1;"#).unwrap();
+        assert_eq!(*log.0.borrow(), "name? |x\n1|");
+    }
+
+    #[test]
+    fn assert_statement() {
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(
+            &mut vm,
+            "var x = 2;\nassert x > 1;\nassert x;\nassert \"\";",
+        )
+        .unwrap();
+        assert_eq!(output.contents(), "");
+
+        let src = "var x = 1;\nfun check() {\n  assert x  >  1;\n}\ncheck();";
+        assert_eq!(
+            interpret(&mut vm, src).unwrap_err().to_string(),
+            "Runtime error: Assertion failed: x  >  1\n[line 3] in check()\n[line 5] in script"
+        );
+        assert_eq!(
+            interpret(&mut vm, "assert nil;"),
+            Err(InterpretError::RuntimeError {
+                line: 1,
+                message: "Assertion failed: nil".to_string(),
+                frames: vec![TraceFrame {
+                    function: None,
+                    line: 1,
+                    file: None
+                }],
+            })
+        );
+        assert_eq!(
+            interpret(&mut vm, "assert;"),
+            Err(InterpretError::CompileError)
+        );
+    }
+
+    #[test]
+    fn fib() {
+        let src = r#"
+fun fib(x) {
+    if (x <= 1) {
+        return x;
+    }
+    return fib(x - 1) + fib(x - 2);
+}
+
+var result = fib(2);
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_var_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_var_str);
+        assert_eq!(value.unwrap(), Value::Number(1.0));
+    }
+
+    #[test]
+    fn superclasses() {
+        let src = r#"
+class Doughnut {
+  cook() {
+    print "Dunk in the fryer.";
+    this.finish("sprinkles");
+  }
+
+  finish(ingredient) {
+    return "Finish with " + ingredient;
+  }
+}
+
+class Cruller < Doughnut {
+  finish(ingredient) {
+    // No sprinkles, always icing.
+    return super.finish("icing");
+  }
+}
+
+var cruller = Cruller();
+var result = cruller.finish("noice");
+"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_var_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_var_str);
+        assert_eq!(value.unwrap().as_str().unwrap(), "Finish with icing");
+    }
+
+    #[test]
+    fn super_calls() {
+        let src = r#"
+class A {
+    method() {
+        return "A";
+    }
+
+    describe() {
+        return "A.describe " + this.method();
+    }
+}
+
+class B < A {
+    method() {
+        return "B then " + super.method();
+    }
+
+    describe() {
+        var describe = super.describe;
+        return "B.describe, " + describe();
+    }
+}
+
+class C < B {}
+
+var result = C().describe();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(
+            value.unwrap().as_str().unwrap(),
+            "B.describe, A.describe B then A"
+        );
+
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, "var NotClass = 1;\nclass A < NotClass {}")
+                .unwrap_err()
+                .to_string(),
+            "Runtime error: Superclass must be a class.\n[line 2] in script"
+        );
+
+        for src in [
+            "class A < A {}",
+            "print super.method;",
+            "class A { method() { return super.method(); } }",
+        ] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src),
+                Err(InterpretError::CompileError),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn invoking_fields() {
+        let src = r#"
+        class Oops {
+            init() {
+                fun f() {
+                    return 420;
+                }
+
+                this.field = f;
+            }
+        }
+
+        var oops = Oops();
+        var result = oops.field();
+        "#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn misusing_this() {
+        let src = r#"
+        fun notMethod() {
+            print this;
+        }
+        "#;
+
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        if let Err(InterpretError::CompileError) = err {
+        } else {
+            panic!()
+        }
+    }
+
+    #[test]
+    fn nested_this() {
+        let src = r#"
+        class Nested {
+            method() {
+              fun function() {
+                return this.lol;
+              }
+              return function();
+            }
+        }
+          
+        var nested = Nested();
+        nested.lol = 420;
+        var result = nested.method();"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn this() {
+        let src = r#"
+        class Nested {
+            method() {
+              return this.lol;
+            }
+          }
+          
+        var nested = Nested();
+        nested.lol = 420;
+        var result = nested.method();"#;
+
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn bound_method_keeps_receiver() {
+        let src = r#"
+class Circle {
+    init(radius) {
+        this.radius = radius;
+    }
+
+    area() {
+        return 3 * this.radius * this.radius;
+    }
+}
+
+var small = Circle(1);
+var big = Circle(2);
+var area = big.area;
+big = nil;
+small.radius = 10;
+var result = area();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(12.0)));
+
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, "print this;"),
+            Err(InterpretError::CompileError)
+        );
+    }
+
+    #[test]
+    fn methods() {
+        let src = r#"
+        class Scone {
+            topping(first, second) {
+              return "scone with " + first + " and " + second;
+            }
+          }
+          
+          var scone = Scone();
+          var result = scone.topping("berries", "cream");"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result").as_non_null_ptr();
+
+        let value = vm.mem.globals.get(result_str);
+
+        let expected_str = vm.get_string("scone with berries and cream");
+        println!("VAL: {:?}", value);
+        assert_eq!(value, Some(Value::Obj(expected_str.cast())));
+    }
+
+    #[test]
+    fn instance_get_set() {
+        let src = r#"
+class Pair {}
+
+var pair = Pair();
+pair.first = 1;
+pair.second = 2;
+var result = pair.first + pair.second;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result").as_non_null_ptr();
+
+        let value = vm.mem.globals.get(result_str);
+
+        assert_eq!(value, Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn instance_fields_and_methods() {
+        let src = r#"
+class Pair {
+    sum() {
+        return this.first + this.second;
+    }
+}
+
+var pair = Pair();
+pair.first = 1;
+pair.second = 2;
+pair.first = pair.first + 10;
+var result = pair.sum();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(13.0)));
+
+        let cases = [
+            (
+                "class Pair {} var pair = Pair(); print pair.missing;",
+                "Runtime error: Undefined property: missing\n[line 1] in script",
+            ),
+            (
+                "class Pair {} var pair = Pair(); pair.missing();",
+                "Runtime error: Undefined property: missing\n[line 1] in script",
+            ),
+            (
+                "var num = 1; print num.field;",
+                "Runtime error: Only instances have properties.\n[line 1] in script",
+            ),
+            (
+                "var num = 1; num.field = 2;",
+                "Runtime error: Only instances have fields.\n[line 1] in script",
+            ),
+        ];
+        for (src, expected) in cases {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src).unwrap_err().to_string(),
+                expected,
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn upvalue_closed() {
+        let src = r#"
+    fun makeClosure() {
+      var a = 1;
+      fun f() {
+        a = a + 1;
+        return a;
+      }
+      return f;
+    }
+
+    var closure = makeClosure();
+    var first = closure();
+    var anotherClosure = makeClosure();
+    var second = anotherClosure();
+    var third = closure();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let first_str = vm.get_string("first").as_non_null_ptr();
+        let second_str = vm.get_string("second").as_non_null_ptr();
+        let third_str = vm.get_string("third").as_non_null_ptr();
+
+        let value1 = vm.mem.globals.get(first_str);
+        let value2 = vm.mem.globals.get(second_str);
+        let value3 = vm.mem.globals.get(third_str);
+
+        assert_eq!(value1, Some(Value::Number(2.0)));
+        assert_eq!(value2, Some(Value::Number(2.0)));
+        assert_eq!(value3, Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn set_immediate_upvalue() {
+        let src = r#"
+    fun outer() {
+      var x = 420;
+      fun inner() {
+        x = x + 1;
+        return x;
+      }
+      return inner();
+    }
+    var value = outer();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let value_str = vm.get_string("value").as_non_null_ptr();
+
+        let value = vm.mem.globals.get(value_str);
+
+        assert_eq!(value, Some(Value::Number(421.0)));
+    }
+
+    #[test]
+    fn immediate_upvalue() {
+        let src = r#"
+var result = "nothing";
+fun outer() {
+  var x = 420;
+  fun inner() {
+    result = x;
+  }
+  inner();
+}
+outer();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn call_native_fn() {
+        let src = r#"
+        var num = __dummy();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let num_str = vm.get_string("num").as_non_null_ptr();
+        let value = vm.mem.globals.get(num_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+    }
+
+    #[test]
+    fn typeof_native() {
+        let src = r#"
+class Point { get() { return 1; } }
+fun f() {}
+var p = Point();
+print typeof(1.5);
+print typeof(true);
+print typeof(nil);
+print typeof("s");
+print typeof(f);
+print typeof(fun () {});
+print typeof(clock);
+print typeof(p.get);
+print typeof(Point);
+print typeof(p);
+print typeof([]);
+print typeof({});
+print typeof(typeof(1));"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "number\nbool\nnil\nstring\nfunction\nfunction\nfunction\nfunction\nclass\n\
+             instance\nlist\nmap\nstring\n"
+        );
+
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, "typeof(1, 2);").unwrap_err().to_string(),
+            "Runtime error: Expected 1 arguments but got 2.\n[line 1] in script"
+        );
+    }
+
+    #[test]
+    fn len_native() {
+        let src = r#"
+var list = [1, 2, 3];
+list[0] = [];
+print len("");
+print len("héllo");
+print len(list);
+print len(list[0]);
+print len({"a": 1, 2: 3});
+print len({});"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "0\n5\n3\n0\n2\n0\n");
+
+        for (src, expected) in [
+            ("len(1);", "Expected a string, list or map but got number."),
+            ("len(true);", "Expected a string, list or map but got bool."),
+            ("len(nil);", "Expected a string, list or map but got nil."),
+            ("len();", "Expected 1 arguments but got 0."),
+        ] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src).unwrap_err().to_string(),
+                format!("Runtime error: {expected}\n[line 1] in script"),
+            );
+        }
+
+        // A script's own `len` isn't replaced by the native on the next run
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, "var len = 5;").unwrap();
+        interpret(&mut vm, "print len;").unwrap();
+        interpret(&mut vm, "fun len(x) { return -1; }").unwrap();
+        interpret(&mut vm, "print len([1, 2]);").unwrap();
+        assert_eq!(output.contents(), "5\n-1\n");
+    }
+
+    #[test]
+    fn closure_counter() {
+        let src = r#"
+        fun makeCounter() {
+            var count = 0;
+            fun counter() {
+                count = count + 1;
+                return count;
+            }
+            return counter;
+        }
+        var counter = makeCounter();
+        counter();
+        counter();
+        var count = counter();
+        var other = makeCounter()();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let count_str = vm.get_string("count").as_non_null_ptr();
+        let value = vm.mem.globals.get(count_str);
+        assert_eq!(value, Some(Value::Number(3.0)));
+
+        let other_str = vm.get_string("other").as_non_null_ptr();
+        let value = vm.mem.globals.get(other_str);
+        assert_eq!(value, Some(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn lambdas() {
+        let src = r#"
+var double = fun (x) { return x * 2; };
+print double(4);
+print (fun (a, b) { return a + b; })(1, 2);
+print fun () {};
+
+fun makeCounter() {
+    var count = 0;
+    return fun () {
+        count = count + 1;
+        return count;
+    };
+}
+var counter = makeCounter();
+counter();
+print counter();
+
+var calls = [fun () { return "first"; }, fun () { return "second"; }];
+print calls[1]();"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "8\n3\n<fn <anonymous>>\n2\nsecond\n");
+
+        // a statement starting with `fun` is a declaration
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, "fun (x) { return x; }(1);"),
+            Err(InterpretError::CompileError)
+        );
+    }
+
+    #[test]
+    fn invoke_falls_back_to_fields() {
+        let src = r#"
+class Greeter {
+    init(name) { this.name = name; }
+    greet() { return "hi " + this.name; }
+    self() { return this; }
+}
+var g = Greeter("bob");
+print g.greet();
+print g.self().self().greet();
+
+g.shout = fun (s) { return s + "!"; };
+print g.shout("hey");
+
+// a field shadows the method of the same name
+g.greet = fun () { return "field"; };
+print g.greet();
+g.greet = Greeter;
+print g.greet("ann").self().name;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "hi bob\nhi bob\nhey!\nfield\nann\n");
+
+        let mut vm = VM::new();
+        match interpret(&mut vm, "class A {} var a = A(); a.b = 1; a.b();") {
+            Err(InterpretError::RuntimeError { message, .. }) => {
+                assert_eq!(message, "Can only call functions and classes.")
+            }
+            res => panic!("expected a runtime error, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn gc_frees_garbage() {
+        let src = r#"
+        fun makeCounter() {
+            var count = 0;
+            fun counter() {
+                count = count + 1;
+                return count;
+            }
+            return counter;
+        }
+
+        var total = 0;
+        for (var i = 0; i < 5000; i = i + 1) {
+            var counter = makeCounter();
+            counter();
+            total = total + counter();
+        }"#;
+        let mut vm = VM::new();
+        // collect on the first allocation, after that the threshold follows
+        // the live heap (with `always_gc` every allocation collects anyway)
+        vm.mem.next_gc = 0;
+        interpret(&mut vm, src).unwrap();
+
+        let total_str = vm.get_string("total").as_non_null_ptr();
+        let value = vm.mem.globals.get(total_str);
+        assert_eq!(value, Some(Value::Number(10000.0)));
+
+        // every iteration allocates a closure and an upvalue, without
+        // collecting there would be well over 10000 objects
+        assert!(vm.mem.obj_list.len() < 1000, "{}", vm.mem.obj_list.len());
+        // and freed objects are no longer counted
+        assert!(vm.mem.bytes_allocated() < 16 * 1024);
+    }
+
+    #[test]
+    fn closure_upvalue_dedup() {
+        // `inner` captures `z` as a local upvalue and `y` as a non-local one
+        // that happen to share the same index, they must stay distinct
+        let src = r#"
+        fun outer() {
+            var x = 1;
+            var y = 2;
+            fun middle() {
+                var z = 3;
+                x;
+                fun inner() {
+                    return z + y;
+                }
+                return inner;
+            }
+            return middle();
+        }
+        var result = outer()();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let result_str = vm.get_string("result").as_non_null_ptr();
+        let value = vm.mem.globals.get(result_str);
+        assert_eq!(value, Some(Value::Number(5.0)));
+    }
+
+    #[test]
+    fn call_custom_native_fn() {
+        fn sum(_vm: &mut VM, values: &[Value]) -> InterpretResult<Value> {
+            let sum = values
+                .iter()
+                .map(|value| match value {
+                    Value::Number(num) => *num,
+                    _ => 0.0,
+                })
+                .sum();
+            Ok(Value::Number(sum))
+        }
+
+        let src = r#"
+        var num = sum(400, 20);
+        var now = clock();"#;
+        let mut vm = VM::new();
+        vm.define_native("sum", sum);
+        interpret(&mut vm, src).unwrap();
+
+        let num_str = vm.get_string("num").as_non_null_ptr();
+        let value = vm.mem.globals.get(num_str);
+        assert_eq!(value, Some(Value::Number(420.0)));
+
+        let now_str = vm.get_string("now").as_non_null_ptr();
+        match vm.mem.globals.get(now_str) {
+            Some(Value::Number(now)) => assert!(now > 0.0),
+            other => panic!("Expected number, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn redefined_native_survives_next_run() {
+        let mut vm = VM::new();
+        interpret(&mut vm, "var clock = 5;").unwrap();
+        interpret(&mut vm, "var now = clock;").unwrap();
+
+        let now_str = vm.get_string("now").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(now_str), Some(Value::Number(5.0)));
+
+        interpret(&mut vm, r#"fun typeof(x) { return "mine"; }"#).unwrap();
+        assert_eq!(eval(&mut vm, "typeof(1)").unwrap().as_str(), Some("mine"));
+    }
+
+    #[test]
+    fn call_fn() {
+        let src = r#"
+            fun add420(num) {
+              return num + 420;
+            }
+
+            fun add69(num) {
+              return num + 69;
+            }
+
+            var num = add420(1);
+            num = add69(num);
+            num = add420(num);"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let num_str = vm.get_string("num").as_non_null_ptr();
+        let value = vm.mem.globals.get(num_str);
+        assert_eq!(value, Some(Value::Number(910.0)));
+    }
+
+    #[test]
+    fn recursive_fn() {
+        let src = r#"
+fun factorial(n) {
+    if (n <= 1) return 1;
+    return n * factorial(n - 1);
+}
+
+var result = factorial(10);
+var after = "unwound";"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        let result = vm.get_string("result").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(result), Some(Value::Number(3628800.0)));
+        let after = vm.get_string("after").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(after).unwrap().as_str(), Some("unwound"));
+    }
+
+    #[test]
+    fn stack_grows_until_overflow() {
+        // 200 locals per frame, and a closure over one of them that is only
+        // read after the deeper calls have grown (and moved) the stack
+        let locals: String = (0..200).map(|i| format!("var l{i} = n; ")).collect();
+        let src = format!(
+            r#"fun deep(n) {{
+    {locals}
+    fun get() {{ return l199; }}
+    var below = 0;
+    if (n > 0) below = deep(n - 1);
+    return below + get();
+}}"#
+        );
+
+        let mut vm = VM::with_stack_capacity(0);
+        assert_eq!(vm.stack_capacity(), U8_COUNT);
+        // the script's frame, 62 `deep` frames and the innermost `get` hit
+        // the frame limit exactly
+        let sum = eval(&mut vm, &format!("{src}\ndeep(61);")).unwrap();
+        assert_eq!(sum, Value::Number((0..=61).sum::<i32>() as f64));
+        assert!(vm.stack_capacity() > 62 * 200, "{}", vm.stack_capacity());
+
+        let mut vm = VM::with_stack_capacity(0);
+        let err = interpret(&mut vm, &format!("{src}\ndeep(63);")).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Runtime error: Stack overflow.\n[line 5] in deep()"),
+            "{err}"
+        );
+
+        assert_eq!(VM::new().stack_capacity(), DEFAULT_STACK_CAPACITY);
+        assert_eq!(
+            VM::with_stack_capacity(usize::MAX).stack_capacity(),
+            STACK_MAX
+        );
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let src = r#"fun recurse() {
+    recurse();
+}
+recurse();"#;
+        let mut vm = VM::new();
+        let report = match interpret(&mut vm, src) {
+            Err(err @ InterpretError::RuntimeError { .. }) => err.to_string(),
+            other => panic!("Expected runtime error, got {other:?}"),
+        };
+
+        let mut lines = report.lines();
+        assert_eq!(lines.next(), Some("Runtime error: Stack overflow."));
+        // one line per frame, the script's frame followed by 63 `recurse` frames
+        let frames: Vec<&str> = lines.collect();
+        assert_eq!(frames.len(), 64);
+        assert!(frames[..63]
+            .iter()
+            .all(|line| *line == "[line 2] in recurse()"));
+        assert_eq!(frames[63], "[line 4] in script");
+
+        // the stack was reset so the VM is still usable
+        assert_eq!(eval(&mut vm, "1 + 2"), Ok(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn reset_keeps_globals() {
+        let mut vm = VM::new();
+        let function = compile("var a = 1;\nfun add(x) { return a + x; }", &mut vm.mem).unwrap();
+        vm.init(function);
+        vm.run().unwrap();
+        // the script's closure is still in slot 0
+        assert!(vm.stack.top > vm.stack.stack);
+
+        vm.reset();
+        assert_eq!(vm.stack.top, vm.stack.stack);
+        assert_eq!(vm.call_frame_count, 0);
+        assert!(vm.open_upvalues.is_null());
+
+        let function = compile("var b = add(a + 1);", &mut vm.mem).unwrap();
+        vm.init(function);
+        // `init` starts the new script on an empty stack, only its closure is in it
+        assert_eq!(unsafe { vm.stack.top.offset_from(vm.stack.stack) }, 1);
+        vm.run().unwrap();
+        let b = vm.get_string("b").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(b), Some(Value::Number(3.0)));
+
+        // a run cut short by an error leaves a local captured, the reset that
+        // comes with the error closes it rather than dropping it
+        let src = r#"var get;
+{
+    var x = "captured";
+    fun f() { return x; }
+    get = f;
+    nil();
+}"#;
+        assert!(interpret(&mut vm, src).is_err());
+        // reuses the stack slot `x` was in
+        interpret(&mut vm, "var y = [1, 2, 3, 4];").unwrap();
+        assert_eq!(eval(&mut vm, "get()").unwrap().to_string(), "captured");
+        assert_eq!(
+            eval(&mut vm, "typeof(clock)").unwrap().to_string(),
+            "function"
+        );
+    }
+
+    #[test]
+    fn return_from_nested_blocks() {
+        let src = r#"
+fun find(list, wanted) {
+    var checked = 0;
+    for (var i = 0; i < len(list); i += 1) {
+        var item = list[i];
+        while (true) {
+            var doubled = item * 2;
+            fun report() { return str(doubled) + " after " + str(checked); }
+            if (item == wanted) return report;
+            break;
+        }
+        checked += 1;
+    }
+    return nil;
+}
+var before = "below";
+{
+    var a = 1;
+    var found = find([5, 6, 7], 7);
+    var missing = find([5], 1);
+    var b = 2;
+    print str(a) + " " + found() + " " + str(missing) + " " + str(b);
+}
+print before;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        let function = compile(src, &mut vm.mem).unwrap();
+        vm.init(function);
+        vm.run().unwrap();
+        assert_eq!(output.contents(), "1 14 after 2 nil 2\nbelow\n");
+        // only the script's closure is left, the returns didn't leave any
+        // of `find`'s locals behind
+        assert_eq!(unsafe { vm.stack.top.offset_from(vm.stack.stack) }, 1);
+        assert!(vm.open_upvalues.is_null());
+    }
+
+    #[test]
+    fn tail_calls() {
+        let mut vm = VM::new();
+        let src = r#"fun countdown(n) {
+    if (n == 0) return "done";
+    return countdown(n - 1);
+}
+countdown(100000);"#;
+        assert_eq!(eval(&mut vm, src).unwrap().to_string(), "done");
+
+        let src = r#"fun isEven(n) {
+    if (n == 0) return true;
+    return isOdd(n - 1);
+}
+fun isOdd(n) {
+    if (n == 0) return false;
+    return isEven(n - 1);
+}
+isEven(10001);"#;
+        assert_eq!(eval(&mut vm, src), Ok(Value::Bool(false)));
+
+        // a closure capturing the caller's locals keeps their values after
+        // the frame is reused
+        let src = r#"fun last(n, f) {
+    if (n == 0) return f();
+    var captured = n;
+    fun get() { return captured; }
+    return last(n - 1, get);
+}
+last(1000, nil);"#;
+        assert_eq!(eval(&mut vm, src), Ok(Value::Number(1.0)));
+
+        // the right operand of `and` and `or` is a tail call too, like each
+        // branch of an `if` with its own `return`
+        let src = r#"fun anyZero(n) { return n == 0 or anyZero(n - 1); }
+fun allPositive(n) { return n == 0 or (n > 0 and allPositive(n - 1)); }
+fun branches(n) {
+    if (n > 0) return branches(n - 1); else return "done";
+}
+anyZero(100000) and allPositive(100000) and branches(100000) == "done";"#;
+        assert_eq!(eval(&mut vm, src), Ok(Value::Bool(true)));
+
+        // so are both branches of `?:`
+        let src = r#"fun f(n) { return n > 0 ? f(n - 1) : "done"; }
+fun g(n) { return n <= 0 ? "done" : g(n - 1); }
+fun h(n) { return n <= 0 ? "done" : n % 2 == 0 ? h(n - 1) : h(n - 1); }
+f(100000) + g(100000) + h(100000) == "donedonedone";"#;
+        assert_eq!(eval(&mut vm, src), Ok(Value::Bool(true)));
+
+        // natives, classes and skipped calls still go through `Return`
+        let src = r#"class A {}
+fun native() { return len("abc"); }
+fun make() { return A(); }
+fun skipped(a) { return a and native(); }
+native() == 3 and skipped(false) == false and make() != nil;"#;
+        assert_eq!(eval(&mut vm, src), Ok(Value::Bool(true)));
+
+        let err = interpret(
+            &mut vm,
+            r#"fun count(n) {
+    if (n == 0) return 0;
+    return 1 + count(n - 1);
+}
+count(100000);"#,
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Runtime error: Stack overflow.\n[line 3] in count()"),
+            "{err}"
+        );
+
+        let err = interpret(&mut vm, "fun f(a) { return f(); } f(1);").unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Runtime error: Expected 1 arguments but got 0.\n[line 1] in f()"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn fn_arity_mismatch() {
+        for (src, msg) in [
+            ("fun f(a, b) {} f(1);", "Expected 2 arguments but got 1."),
+            (
+                "fun f(a, b) {} f(1, 2, 3);",
+                "Expected 2 arguments but got 3.",
+            ),
+            ("class A {} A(1);", "Expected 0 arguments but got 1."),
+        ] {
+            let mut vm = VM::new();
+            match interpret(&mut vm, src) {
+                Err(err @ InterpretError::RuntimeError { .. }) => {
+                    let report = err.to_string();
+                    assert!(report.contains(msg), "{report}")
+                }
+                other => panic!("Expected runtime error, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn print_fn() {
+        let src = r#"
+            fun bigNoob() {
+              print "OH YEAH";
+            }
+
+            print bigNoob;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "<fn bigNoob>\n");
+    }
+
+    #[test]
+    fn value_display() {
+        let src = r#"
+class Point { method() {} }
+var point = Point();
+print 5;
+print 5.5;
+print -0.25;
+print 1 / 3;
+print true;
+print false;
+print nil;
+print "a string";
+print Point;
+print point;
+print point.method;
+print clock;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "5\n5.5\n-0.25\n0.3333333333333333\ntrue\nfalse\nnil\na string\nPoint\n\
+             Point instance\n<fn method>\n<native fn>\n"
+        );
+
+        for (value, expected) in [
+            (Value::Number(5.0), "5"),
+            (Value::Number(5.5), "5.5"),
+            (Value::Bool(true), "true"),
+            (Value::Nil, "nil"),
+        ] {
+            assert_eq!(value.to_string(), expected);
+        }
+        let string = Value::Obj(vm.get_string("quoted?").cast());
+        assert_eq!(string.to_string(), "quoted?");
+    }
+
+    #[test]
+    fn if_stmt() {
+        let src = r#"
+            var noob = 420;
+            if (420 > 69) { noob = "NICE"; } else { noob = "NOT NICE"; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn switch_stmt() {
+        let src = r#"
+fun describe(x) {
+    var prefix = "got ";
+    switch (x) {
+        case 1:
+            var one = "one";
+            return prefix + one;
+        case "two":
+        case 1 + 2:
+            return prefix + "three";
+        default:
+            return prefix + "other";
+    }
+}
+var matched = describe(1);
+var fell_to_next = describe("two");
+var computed = describe(3);
+var defaulted = describe(nil);
+
+var hits = 0;
+for (var i = 0; i < 4; i = i + 1) {
+    switch (i % 2) {
+        case 0: hits = hits + 1;
+    }
+}
+switch ("no match") { case 1: hits = -1; }
+
+var stopped_at;
+for (var i = 0; i < 10; i = i + 1) {
+    var doubled = i * 2;
+    switch (doubled) {
+        case 6: stopped_at = i; break;
+    }
+}"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        for (name, expected) in [
+            ("matched", "got one"),
+            ("computed", "got three"),
+            ("defaulted", "got other"),
+        ] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name).unwrap().as_str(), Some(expected));
+        }
+        // a case with no statements does nothing, it doesn't fall through
+        let fell_to_next = vm.get_string("fell_to_next").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(fell_to_next), Some(Value::Nil));
+        let hits = vm.get_string("hits").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(hits), Some(Value::Number(2.0)));
+        // break leaves the loop, popping the switch's locals on the way
+        let stopped_at = vm.get_string("stopped_at").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(stopped_at), Some(Value::Number(3.0)));
+
+        for (src, expected) in [
+            (
+                "switch (1) { default: print 1; default: print 2; }",
+                "[line 1:32] Error at 'default': Can't have more than one default case.",
+            ),
+            (
+                r#"switch (1) { case "a": case 2: case "a": }"#,
+                "[line 1:37] Error at '\"a\"': Duplicate case value.",
+            ),
+            (
+                "switch (1) { default: case 1: }",
+                "[line 1:23] Error at 'case': Can't have a case after the default case.",
+            ),
+            (
+                "switch (1) { print 1; }",
+                "[line 1:14] Error at 'print': Expect 'case' or 'default'.",
+            ),
+        ] {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            assert!(!parser.compile());
+            assert_eq!(parser.errors().first().map(String::as_str), Some(expected));
+        }
+    }
+
+    #[test]
+    fn if_else_stmt() {
+        let src = r#"
+            var noob = 420;
+            if (69 > 420) { noob = "wtf"; } else { noob = "NICE"; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn if_branch_selection() {
+        let src = r#"
+var a;
+var b;
+var c = "untouched";
+if (true) a = 1; else a = 2;
+if (false) b = 1; else b = 2;
+if (nil) c = "touched";"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [("a", Value::Number(1.0)), ("b", Value::Number(2.0))] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(expected));
+        }
+        let c = vm.get_string("c").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(c).unwrap().as_str(), Some("untouched"));
+    }
+
+    #[test]
+    fn while_loop() {
+        let src = r#"
+            var noob = 0;
+            while (noob < 10) {
+              noob = noob + 1;
+            }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top, Some(Value::Number(10.0)));
+    }
+
+    #[test]
+    fn while_loop_accumulates() {
+        let src = r#"
+var sum = 0;
+var i = 0;
+while (i < 5) {
+    i = i + 1;
+    sum = sum + i;
+}"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let sum = vm.get_string("sum").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(sum), Some(Value::Number(15.0)));
+
+        // every condition was popped, only the script's closure is left in slot 0
+        let stack_len = unsafe { vm.stack.top.offset_from(vm.stack.stack) };
+        assert_eq!(stack_len, 1);
+    }
+
+    #[test]
+    fn for_loop() {
+        let src = r#"
+            var noob = 420;
+            for (var x = 0; x < 10; x = x + 1) {
+              noob = x;
+            }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top, Some(Value::Number(9.0)));
+
+        // the loop variable is scoped to the loop
+        let x = vm.get_string("x").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(x), None);
+    }
+
+    #[test]
+    fn for_in_loops() {
+        let src = r#"
+for (item in [1, "two", nil]) print item;
+var m = {"b": 1, "a": 2, 3: 4};
+for (var key in m) print str(key) + " -> " + str(m[key]);
+
+// every iteration gets its own `x`
+var getters = [nil, nil];
+var i = 0;
+for (x in [10, 20, 30]) {
+    fun get() { return x; }
+    getters[i] = get;
+    i += 1;
+    if (i == 2) break;
+}
+print getters[0]() + getters[1]();
+
+fun sumOdd(list) {
+    var sum = 0;
+    for (n in list) {
+        if (n % 2 == 0) continue;
+        for (unused in []) sum = -1;
+        sum += n;
+    }
+    return sum;
+}
+print sumOdd([1, 2, 3, 4, 5]);
+{
+    var outer = "kept";
+    for (outer in [1]) {}
+    print outer;
+}"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "1\ntwo\nnil\nb -> 1\na -> 2\n3 -> 4\n30\n9\nkept\n"
+        );
+
+        for (src, expected) in [
+            (
+                "for (x in 3) print x;",
+                "Can only iterate over lists and maps, not number.",
+            ),
+            (
+                "for (x in \"ab\") print x;",
+                "Can only iterate over lists and maps, not string.",
+            ),
+        ] {
+            let mut vm = VM::new();
+            let err = interpret(&mut vm, src).unwrap_err().to_string();
+            assert!(
+                err.starts_with(&format!("Runtime error: {expected}")),
+                "{err}"
+            );
+        }
+
+        for src in [
+            "for (x in [1] print x;",
+            "for (x in) {}",
+            "for (var in [1]) {}",
+        ] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src),
+                Err(InterpretError::CompileError),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn destructuring_declarations() {
+        let src = r#"
+fun pair() { return [1, "two"]; }
+var a, b = pair();
+print a;
+print b;
+
+fun divmod(x, y) { return [(x - x % y) / y, x % y, "extra"]; }
+fun local() {
+    var q, r = divmod(17, 5);
+    fun show() { return str(q) + " r " + str(r); }
+    return show;
+}
+print local()();
+{
+    var first, second, third = [3, 2, 1];
+    var unused = 0;
+    print first + second * third;
+}"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "1\ntwo\n3 r 2\n5\n");
+
+        for (src, expected) in [
+            (
+                "var a, b = [1];",
+                "Expected at least 2 items to destructure but got 1.",
+            ),
+            (
+                "{ var a, b = [1]; }",
+                "Expected at least 2 items to destructure but got 1.",
+            ),
+            (
+                r#"var p, q = {0: "m0", 1: "m1"};"#,
+                "Can only destructure lists, not map.",
+            ),
+            (
+                "{ var p, q = nil; }",
+                "Can only destructure lists, not nil.",
+            ),
+        ] {
+            let mut vm = VM::new();
+            let err = interpret(&mut vm, src).unwrap_err().to_string();
+            assert!(
+                err.starts_with(&format!("Runtime error: {expected}")),
+                "{err}"
+            );
+        }
+
+        // like `var a = a;`, the initializer can't read the locals it declares
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("var a = 1;\n{ var a, b = [a, 2]; }", &mut mem);
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.errors(),
+            ["[line 2:15] Error at 'a': Can't read local variable in its own initializer."]
+        );
+
+        for src in ["var a, b;", "var a, = [1];", "{ var a, a = [1, 2]; }"] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src),
+                Err(InterpretError::CompileError),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn for_loop_empty_clauses() {
+        let src = r#"
+fun firstOver(limit) {
+    var i = 0;
+    for (;;) {
+        i = i + 1;
+        if (i > limit) return i;
+    }
+}
+var result = firstOver(4);
+
+var j = 0;
+var count = 0;
+for (j = 10; j > 0;) {
+    j = j - 2;
+    count = count + 1;
+}"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [
+            ("result", Value::Number(5.0)),
+            ("j", Value::Number(0.0)),
+            ("count", Value::Number(5.0)),
+        ] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(expected));
+        }
+    }
+
+    #[test]
+    fn globals() {
+        let src = r#"
+var x = 10;
+var y = x;
+x = 5;
+var z;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [
+            ("x", Value::Number(5.0)),
+            ("y", Value::Number(10.0)),
+            ("z", Value::Nil),
+        ] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(expected));
+        }
+    }
+
+    #[test]
+    fn undefined_global() {
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, "print nope;");
+        assert!(matches!(err, Err(InterpretError::RuntimeError { .. })));
+
+        // assigning doesn't implicitly define the variable
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, "nope = 1;");
+        assert!(matches!(err, Err(InterpretError::RuntimeError { .. })));
+        let nope = vm.get_string("nope").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(nope), None);
+
+        // `later` has a slot from the moment `f` is compiled, but it's still
+        // undefined until its `var` runs
+        let mut vm = VM::new();
+        interpret(&mut vm, "fun f() { return later; }").unwrap();
+        assert_eq!(
+            eval(&mut vm, "f()").unwrap_err().to_string(),
+            "Runtime error: Undefined variable: later\n[line 1] in f()\n[line 1] in script"
+        );
+        assert_eq!(
+            eval(&mut vm, "later = 1;").unwrap_err().to_string(),
+            "Runtime error: Undefined variable: later\n[line 1] in script"
+        );
+        assert_eq!(eval(&mut vm, "var later = 2; f()"), Ok(Value::Number(2.0)));
+    }
+
+    #[test]
+    fn break_and_continue() {
+        let src = r#"
+        var i = 0;
+        while (true) {
+            var next = i + 1;
+            if (next > 5) break;
+            i = next;
+        }
+
+        var sum = 0;
+        for (var j = 0; j < 10; j = j + 1) {
+            var odd = j % 2;
+            if (odd == 1) continue;
+            sum = sum + j;
+        }
+
+        var closures = 0;
+        var k = 0;
+        while (k < 3) {
+            var captured = k;
+            fun get() {
+                return captured;
+            }
+            k = k + 1;
+            if (k == 2) continue;
+            closures = closures + get();
+        }
+
+        var outer = 0;
+        for (var a = 0; a < 3; a = a + 1) {
+            for (var b = 0; b < 3; b = b + 1) {
+                if (b == 1) break;
+                outer = outer + 1;
+            }
+        }"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [("i", 5.0), ("sum", 20.0), ("closures", 2.0), ("outer", 3.0)] {
+            let name_str = vm.get_string(name).as_non_null_ptr();
+            let value = vm.mem.globals.get(name_str);
+            assert_eq!(value, Some(Value::Number(expected)), "{name}");
+        }
+        // the locals popped by `break`/`continue` don't leak onto the stack
+        assert_eq!(unsafe { vm.stack.top.offset_from(vm.stack.stack) }, 1);
+
+        for (src, expected) in [
+            (
+                "break;",
+                "[line 1:1] Error at 'break': Can't use 'break' outside of a loop.",
+            ),
+            (
+                "while (true) { fun f() { continue; } }",
+                "[line 1:26] Error at 'continue': Can't use 'continue' outside of a loop.",
+            ),
+        ] {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            assert!(!parser.compile());
+            assert_eq!(parser.errors().first().map(String::as_str), Some(expected));
+        }
+    }
+
+    #[test]
+    fn logical_short_circuit() {
+        let src = r#"
+var calls = 0;
+fun sideEffect() {
+    calls = calls + 1;
+    return "called";
+}
+
+var a = false and sideEffect();
+var b = true or sideEffect();
+var c = nil or "right";
+var d = 1 and 2;
+var e = true and sideEffect();
+var f = false or sideEffect();"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let calls = vm.get_string("calls").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(calls), Some(Value::Number(2.0)));
+
+        for (name, expected) in [
+            ("a", Value::Bool(false)),
+            ("b", Value::Bool(true)),
+            ("d", Value::Number(2.0)),
+        ] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(expected));
+        }
+        for (name, expected) in [("c", "right"), ("e", "called"), ("f", "called")] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name).unwrap().as_str(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn conditional_expression() {
+        let src = r#"
+var taken = 0;
+var skipped = 0;
+fun take(x) { taken = taken + 1; return x; }
+fun skip(x) { skipped = skipped + 1; return x; }
+
+var a = true ? take("yes") : skip("no");
+var b = nil ? skip("yes") : take("no");
+var c = false ? 1 : 2 > 1 ? "nested" : "wrong";
+var d = false or true ? 1 : 2;
+var e;
+e = 0 ? "zero is truthy" : "never";"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [
+            ("taken", Value::Number(2.0)),
+            ("skipped", Value::Number(0.0)),
+            ("d", Value::Number(1.0)),
+        ] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(expected));
+        }
+        for (name, expected) in [
+            ("a", "yes"),
+            ("b", "no"),
+            ("c", "nested"),
+            ("e", "zero is truthy"),
+        ] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name).unwrap().as_str(), Some(expected));
+        }
+
+        let mut vm = VM::new();
+        assert_eq!(
+            eval(&mut vm, "true ? 1 : 2 ? 3 : 4").unwrap(),
+            Value::Number(1.0)
+        );
+        assert_eq!(
+            eval(&mut vm, "false ? 1 : false ? 3 : 4").unwrap(),
+            Value::Number(4.0)
+        );
+
+        let mut mem = Mem::new();
+        let mut parser = Parser::new("var x = true ? 1;", &mut mem);
+        assert!(!parser.compile());
+        assert_eq!(
+            parser.errors().first().map(String::as_str),
+            Some(
+                "[line 1:17] Error at ';': Expect ':' after then branch of conditional expression."
+            )
+        );
+    }
+
+    #[test]
+    fn dup_opcode() {
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        vm.trace = true;
+
+        let mut chunk = Chunk::new();
+        chunk.write_constant(Value::Number(1.0), 1).unwrap();
+        chunk.write_constant(Value::Number(2.0), 1).unwrap();
+        chunk.write_op(Opcode::Dup, 1).unwrap();
+        chunk.write_op(Opcode::Return, 1).unwrap();
+        let mut function = vm.mem.alloc_obj(ObjFunction::new(std::ptr::null_mut()));
+        function.chunk = chunk;
+        vm.init(function);
+
+        assert_eq!(vm.run(), Ok(Value::Number(2.0)));
+        let trace = output.contents();
+        let stacks: Vec<&str> = trace
+            .lines()
+            .filter_map(|line| line.trim_start().strip_prefix("[ Closure"))
+            // drop the script's closure in slot 0
+            .map(|stack| stack.split_once("} ]").unwrap().1)
+            .collect();
+        assert_eq!(
+            stacks,
+            [
+                "",
+                "[ Number(1.0) ]",
+                "[ Number(1.0) ][ Number(2.0) ]",
+                "[ Number(1.0) ][ Number(2.0) ][ Number(2.0) ]",
+            ]
+        );
+    }
+
+    #[test]
+    fn compound_assignment() {
+        let src = r#"
+var g = 10;
+g += 5;
+g -= 3;
+g *= 2;
+g /= 4;
+var s = "a";
+s += "b";
+
+var local;
+{
+    var l = 1;
+    l += 2;
+    l *= l;
+    local = l;
+}
+
+fun counter() {
+    var count = 0;
+    fun inc() { count += 1; return count; }
+    inc();
+    return inc();
+}
+var upvalue = counter();
+
+class Box {}
+var box = Box();
+box.value = 1;
+var fetched = 0;
+fun getBox() { fetched += 1; return box; }
+getBox().value += 41;
+var chained = getBox().value -= 2;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [
+            ("g", Value::Number(6.0)),
+            ("local", Value::Number(9.0)),
+            ("upvalue", Value::Number(2.0)),
+            ("fetched", Value::Number(2.0)),
+            ("chained", Value::Number(40.0)),
+        ] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(expected));
+        }
+        let s = vm.get_string("s").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(s).unwrap().as_str(), Some("ab"));
+        assert_eq!(eval(&mut vm, "box.value"), Ok(Value::Number(40.0)));
+
+        for src in ["1 += 2;", "var a = 1; var b = 2; a + b -= 3;"] {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            assert!(!parser.compile());
+            assert!(
+                parser.errors()[0].ends_with("Invalid assignment target."),
+                "{:?}",
+                parser.errors()
+            );
+        }
+    }
+
+    #[test]
+    fn lists_are_shared_not_copied() {
+        let src = r#"
+var a = [1, 2];
+var b = a;
+b[0] = "via b";
+
+fun set(list) { list[1] = "via arg"; }
+set(a);
+
+class Box { init(items) { this.items = items; } }
+var box = Box(a);
+box.items[0] = "via field";
+var map = {"items": b};
+
+fun get() { return a; }
+var c = get();
+print a;
+print b;
+print c;
+print map["items"];
+print a == b;
+print [1] == [1];"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "[via field, via arg]\n".repeat(4) + "true\nfalse\n"
+        );
+    }
+
+    #[test]
+    fn lists() {
+        let src = r#"
+var a = [1, 2, 3];
+var first = a[0];
+a[1] = 9;
+var assigned = a[2] = "three";
+var nested = [[1, 2], [], [a],];
+var inner = nested[0][1];
+nested[2][0][0] = "shared";
+print a;
+print nested[1];
+print [nil, true, "s"];"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "[shared, 9, three]\n[]\n[nil, true, s]\n"
+        );
+
+        for (name, expected) in [("first", 1.0), ("inner", 2.0)] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(Value::Number(expected)));
+        }
+        let assigned = vm.get_string("assigned").as_non_null_ptr();
+        assert_eq!(
+            vm.mem.globals.get(assigned).unwrap().as_str(),
+            Some("three")
+        );
+
+        for (src, expected) in [
+            (
+                "var a = [1, 2];\nprint a[2];",
+                "Runtime error: List index 2 out of bounds for length 2.\n[line 2] in script",
+            ),
+            (
+                "var a = [1, 2];\na[-1] = 0;",
+                "Runtime error: List index -1 out of bounds for length 2.\n[line 2] in script",
+            ),
+            (
+                "[1][0.5];",
+                "Runtime error: List index must be an integer.\n[line 1] in script",
+            ),
+            (
+                r#"[1]["0"];"#,
+                "Runtime error: List index must be an integer.\n[line 1] in script",
+            ),
+            (
+                r#""str"[0];"#,
+                "Runtime error: Can only index into lists and maps.\n[line 1] in script",
+            ),
+        ] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src).unwrap_err().to_string(),
+                expected,
+                "{src}"
+            );
+        }
+
+        for src in ["var a = [1, 2;", "var a = [1]; a[0 = 1;"] {
+            let mut vm = VM::new();
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::CompileError));
+        }
+    }
+
+    #[test]
+    fn self_referencing_list() {
+        let src = r#"
+var a = [1];
+a[0] = a;
+print a;
+var b = [a, 2];
+a[0] = b;
+print b;
+var c = [3];
+print [c, c];"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        // only a list already being printed is elided, not every repeat
+        assert_eq!(output.contents(), "[[...]]\n[[[...]], 2]\n[[3], [3]]\n");
+        // the trace's `Debug` output is guarded the same way
+        let debug = format!("{:?}", eval(&mut vm, "a").unwrap());
+        assert!(debug.contains("[...]"), "{debug}");
+    }
+
+    #[test]
+    fn float_list_indices() {
+        let mut vm = VM::new();
+        eval(&mut vm, "var a = [10, 20, 30];").unwrap();
+        for (src, expected) in [
+            ("a[2.0]", 30.0),
+            ("a[5 / 2.5]", 30.0),
+            ("a[-0]", 10.0),
+            ("a[1.0] = 21", 21.0),
+            ("a[1]", 21.0),
+        ] {
+            assert_eq!(eval(&mut vm, src), Ok(Value::Number(expected)), "{src}");
+        }
+
+        for (src, expected) in [
+            ("a[1.5];", "List index must be an integer."),
+            ("a[0.5] = 1;", "List index must be an integer."),
+            ("a[0 / 0];", "List index must be an integer."),
+            ("a[1 / 0];", "List index must be an integer."),
+            ("a[-1];", "List index -1 out of bounds for length 3."),
+            ("a[-2.0] = 1;", "List index -2 out of bounds for length 3."),
+            ("a[-1 / 0];", "List index must be an integer."),
+        ] {
+            let err = interpret(&mut vm, src).unwrap_err().to_string();
+            assert!(
+                err.starts_with(&format!("Runtime error: {expected}")),
+                "{src}: {err}"
+            );
+        }
+        // failed assignments leave the list alone
+        assert_eq!(eval(&mut vm, "a").unwrap().to_string(), "[10, 21, 30]");
+    }
+
+    #[test]
+    fn maps() {
+        let src = r#"
+var m = {"a": 1, 2: "two", "nested": {},};
+m["b"] = m["a"] + 1;
+m["a"] = "one";
+var missing = m["nope"];
+var two = m[2.0];
+m["nested"]["deep"] = [m];
+print m["nested"]["deep"][0]["b"];
+print {"x": nil, -0: true};
+print {};"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "2\n{x: nil, 0: true}\n{}\n");
+
+        let missing = vm.get_string("missing").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(missing), Some(Value::Nil));
+        let two = vm.get_string("two").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(two).unwrap().as_str(), Some("two"));
+
+        for src in [
+            r#"var m = {}; m[[]] = 1;"#,
+            "print {nil: 1};",
+            "print {}[0 / 0];",
+        ] {
+            let mut vm = VM::new();
+            let err = interpret(&mut vm, src).unwrap_err().to_string();
+            assert!(
+                err.starts_with("Runtime error: Map keys must be strings or numbers."),
+                "{src}: {err}"
+            );
+        }
+
+        for src in ["var m = {1: 2;", "var m = {1 2};", "{1: 2};"] {
+            let mut vm = VM::new();
+            assert_eq!(interpret(&mut vm, src), Err(InterpretError::CompileError));
+        }
+    }
+
+    #[test]
+    fn self_referencing_map() {
+        let src = r#"
+var m = {"n": 1};
+m["self"] = m;
+print m;
+var l = [m];
+m["list"] = l;
+print l;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "{n: 1, self: {...}}\n[{n: 1, self: {...}, list: [...]}]\n"
+        );
+        let debug = format!("{:?}", eval(&mut vm, "m").unwrap());
+        assert!(debug.contains("{...}"), "{debug}");
+    }
+
+    #[test]
+    fn map_and_globals_order_is_stable() {
+        // maps print in insertion order, overwriting a key keeps its place
+        let src = r#"
+var m = {"zebra": 1, 10: "ten", "apple": 2};
+for (var i = 0; i < 20; i += 1) m[str(i % 7) + "k"] = i;
+m["zebra"] = "last write";
+m[-3] = nil;
+print m;"#;
+        let expected = "{zebra: last write, 10: ten, apple: 2, 0k: 14, 1k: 15, 2k: 16, 3k: 17, \
+                        4k: 18, 5k: 19, 6k: 13, -3: nil}\n";
+        for _ in 0..3 {
+            let output = SharedBuf::default();
+            let mut vm = VM::with_output(Box::new(output.clone()));
+            interpret(&mut vm, src).unwrap();
+            assert_eq!(output.contents(), expected);
+
+            // and `:globals` lists names alphabetically, whatever order they
+            // were defined in
+            let mut vm = VM::new();
+            interpret(&mut vm, "var zz = 1; var b = 2; var aa = 3; var c = 4;").unwrap();
+            let listing = format_globals(&vm);
+            let names: Vec<&str> = listing
+                .lines()
+                .map(|line| line.split_once(" = ").unwrap().0)
+                .filter(|name| ["zz", "b", "aa", "c"].contains(name))
+                .collect();
+            assert_eq!(names, ["aa", "b", "c", "zz"]);
+        }
+    }
+
+    #[test]
+    fn locals() {
+        let src = r#"
+            var global = 420;
+            { var x = "HELLO"; x = "NICE"; global = x; }
+    "#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("global").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("NICE"));
+    }
+
+    #[test]
+    fn shadowed_locals() {
+        let src = r#"
+var inner;
+var outer;
+{
+    var a = 1;
+    {
+        var a = 2;
+        inner = a;
+    }
+    outer = a;
+}"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let inner = vm.get_string("inner").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(inner), Some(Value::Number(2.0)));
+        let outer = vm.get_string("outer").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(outer), Some(Value::Number(1.0)));
+        let a = vm.get_string("a").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(a), None);
+    }
+
+    #[test]
+    fn string() {
+        let src = r#"var noob = "hello" + " sir" + " sir";"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob);
+        assert_eq!(top.unwrap().as_str(), Some("hello sir sir"));
+    }
+
+    #[test]
+    fn string_display() {
+        let src = r#"var noob = "hello" + " sir"; var num = 1.5 + 2.5;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let noob = vm.get_string("noob").as_non_null_ptr();
+        let top = vm.mem.globals.get(noob).unwrap();
+        assert_eq!(format!("{top}"), "hello sir");
+
+        let num = vm.get_string("num").as_non_null_ptr();
+        let top = vm.mem.globals.get(num).unwrap();
+        assert_eq!(format!("{top}"), "4");
+    }
+
+    #[test]
+    fn add_mismatched_types() {
+        let src = r#"var noob = "hello" + 1;"#;
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src);
+        assert!(matches!(err, Err(InterpretError::RuntimeError { .. })));
+    }
+
+    #[test]
+    fn negate() {
+        let src = r#"var num = -5; var double = --5;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let num = vm.get_string("num").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(num), Some(Value::Number(-5.0)));
+        let double = vm.get_string("double").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(double), Some(Value::Number(5.0)));
+
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, "var b = -true;");
+        assert!(matches!(err, Err(InterpretError::RuntimeError { .. })));
+    }
+
+    #[test]
+    fn truthiness() {
+        let src = r#"
+var a = !nil;
+var b = !false;
+var c = !0;
+var d = !"";
+var e = "falsey";
+if (nil) { e = "truthy"; }"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [("a", true), ("b", true), ("c", false), ("d", false)] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(Value::Bool(expected)));
+        }
+        let e = vm.get_string("e").as_non_null_ptr();
+        assert_eq!(vm.mem.globals.get(e).unwrap().as_str(), Some("falsey"));
+
+        assert_eq!(format!("{}", Value::Bool(true)), "true");
+        assert_eq!(format!("{}", Value::Nil), "nil");
+    }
+
+    #[test]
+    fn not_operator() {
+        let mut vm = VM::new();
+        for (src, expected) in [
+            ("!true", false),
+            ("!nil", true),
+            // 0 is truthy
+            ("!0", false),
+            ("!!nil", false),
+            ("!!0", true),
+            ("!!!true", false),
+            // binds tighter than `==`, so this is `(!nil) == false`
+            ("!nil == false", false),
+            ("!(nil == false)", true),
+            ("!clock", false),
+            ("var t = true; !t", false),
+            ("var n = nil; !!n", false),
+        ] {
+            assert_eq!(eval(&mut vm, src), Ok(Value::Bool(expected)), "{src}");
+        }
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        let src = r#"
+print 12 & 10;
+print 12 | 10;
+print 12 ^ 10;
+print 1 << 4;
+print 256 >> 3;
+print -16 >> 2;
+print 6 & 3 == 2;
+print 1 | 6 & 5 ^ 2;
+print 1 << 2 + 1;
+print 2 < 1 << 2;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "8\n14\n6\n16\n32\n-4\ntrue\n7\n8\ntrue\n"
+        );
+
+        for (src, expected) in [
+            ("1.5 & 1;", "Operands must be integers."),
+            ("1 | (1 / 0);", "Operands must be integers."),
+            ("1 ^ true;", "Operands must be integers."),
+            (r#""a" << 1;"#, "Operands must be integers."),
+            ("1 << 64;", "Shift amount must be between 0 and 63."),
+            ("1 >> -1;", "Shift amount must be between 0 and 63."),
+        ] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src).unwrap_err().to_string(),
+                format!("Runtime error: {expected}\n[line 1] in script"),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn opcode_counts() {
+        let src = "
+fun f() {}
+var i = 0;
+while (i < 10) {
+    i = i + 1;
+    if (i > 7) f();
+}";
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+        assert!(vm.opcode_counts().is_empty());
+
+        let mut vm = VM::new();
+        vm.profile = true;
+        interpret(&mut vm, src).unwrap();
+        let counts = vm.opcode_counts();
+        assert_eq!(counts[&Opcode::Loop], 10);
+        // the loop's condition and its `if`
+        assert_eq!(counts[&Opcode::JumpIfFalse], 11 + 10);
+        assert_eq!(counts[&Opcode::Call], 3);
+        // once for the script and once for each call
+        assert_eq!(counts[&Opcode::Return], 1 + 3);
+        assert!(!counts.contains_key(&Opcode::Divide));
+
+        // counts add up over runs
+        interpret(&mut vm, "print 1;").unwrap();
+        assert_eq!(vm.opcode_counts()[&Opcode::Return], 5);
+
+        // `:reset` starts counting again
+        vm.clear();
+        assert!(vm.profile);
+        assert!(vm.opcode_counts().is_empty());
+    }
+
+    #[test]
+    fn divide_by_zero_modes() {
+        let src = "var zero = 0;\nprint 1 / 0;\nprint 1 % zero;\nprint -1 / -0;\nprint 7 % 2 / 2;";
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        assert_eq!(vm.divide_by_zero, DivByZero::Ieee);
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "Infinity\nNaN\nInfinity\n0.5\n");
+
+        for (src, expected) in [
+            ("print 1 / 0;", "[line 1] in script"),
+            ("var a = 1;\nprint a % 0;", "[line 2] in script"),
+            ("fun f(x) {\n    x /= -0;\n}\nf(4);", "[line 2] in f()"),
+        ] {
+            let mut vm = VM::new();
+            vm.divide_by_zero = DivByZero::Error;
+            let err = interpret(&mut vm, src).unwrap_err().to_string();
+            assert!(
+                err.starts_with(&format!("Runtime error: Division by zero.\n{expected}")),
+                "{src}: {err}"
+            );
+        }
+
+        let mut vm = VM::new();
+        vm.divide_by_zero = DivByZero::Error;
+        assert_eq!(eval(&mut vm, "10 / 4 + 10 % 4"), Ok(Value::Number(4.5)));
+        let err = interpret(&mut vm, "print \"a\" / 0;")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("Runtime error: Operands must be numbers."),
+            "{err}"
+        );
+
+        // the option outlives `:reset`
+        vm.clear();
+        assert_eq!(vm.divide_by_zero, DivByZero::Error);
+    }
+
+    #[test]
+    fn power_operator() {
+        let src = r#"
+var two = 2;
+print two ** 10;
+print two ** 0.5;
+print -two ** 2;
+print (-two) ** 2;
+print two ** 3 ** 2;
+print two ** -1;
+print 2 * 3 ** 2;
+print 2 ** 10;
+print -2 ** 2;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "1024\n1.4142135623730951\n-4\n4\n512\n0.5\n18\n1024\n-4\n"
+        );
+
+        for src in [r#""a" ** 2;"#, "var n = nil; 2 ** n;"] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src).unwrap_err().to_string(),
+                "Runtime error: Operands must be numbers.\n[line 1] in script",
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn equality() {
+        let src = r#"
+var a = 1 == 1;
+var b = nil == false;
+var c = "a" == "a";
+var d = "a" + "b" == "ab";
+var e = 1 != 2;
+var f = 1 == "1";
+var g = nil == nil;
+var h = 1 < 2 == true;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [
+            ("a", true),
+            ("b", false),
+            ("c", true),
+            ("d", true),
+            ("e", true),
+            ("f", false),
+            ("g", true),
+            ("h", true),
+        ] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(Value::Bool(expected)));
+        }
+    }
+
+    #[test]
+    fn comparison() {
+        let src = r#"
+var a = 3 < 5;
+var b = 5 >= 5;
+var c = 5 > 5;
+var d = 4 <= 5;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        for (name, expected) in [("a", true), ("b", true), ("c", false), ("d", true)] {
+            let name = vm.get_string(name).as_non_null_ptr();
+            assert_eq!(vm.mem.globals.get(name), Some(Value::Bool(expected)));
+        }
+
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, r#"var e = "a" < 1;"#);
+        assert_eq!(
+            err,
+            Err(InterpretError::RuntimeError {
+                line: 1,
+                message: "Operands must be numbers.".to_string(),
+                frames: vec![TraceFrame {
+                    function: None,
+                    line: 1,
+                    file: None
+                }],
+            })
+        );
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "Runtime error: Operands must be numbers.\n[line 1] in script"
+        );
+    }
+
+    #[test]
+    fn runtime_error_trace() {
+        let src = r#"
+var a = 1;
+print -"foo";"#;
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: Operand must be a number.\n[line 3] in script"
+        );
+
+        let src = r#"
+fun inner() {
+    return nil + 1;
+}
+
+fun outer() {
+    inner();
+}
+
+outer();"#;
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, src).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Runtime error: Operands must be two numbers or two strings.
+[line 3] in inner()
+[line 7] in outer()
+[line 10] in script"
+        );
+    }
+
+    #[test]
+    fn runtime_error_fields() {
+        let src = r#"fun divide(a) {
+    return a / "two";
+}
+divide(1);"#;
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, src),
+            Err(InterpretError::RuntimeError {
+                line: 2,
+                message: "Operands must be numbers.".to_string(),
+                frames: vec![
+                    TraceFrame {
+                        function: Some("divide".to_string()),
+                        line: 2,
+                        file: None
+                    },
+                    TraceFrame {
+                        function: None,
+                        line: 4,
+                        file: None
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn interned_strings() {
+        let src = r#"
+var a = "hello";
+var b = "hello";
+var c = "hel" + "lo";"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+
+        let values = ["a", "b", "c"].map(|name| {
+            let name = vm.get_string(name).as_non_null_ptr();
+            match vm.mem.globals.get(name) {
+                Some(Value::Obj(obj)) => obj.as_ptr(),
+                other => panic!("Expected string object, got {other:?}"),
+            }
+        });
+        assert_eq!(values[0], values[1]);
+        assert_eq!(values[0], values[2]);
+        assert_eq!(vm.get_string("hello").as_ptr().cast(), values[0]);
+    }
+
+    #[test]
+    fn print() {
+        let src = r#"print 1 + 2;"#;
+        let mut vm = VM::new();
+        interpret(&mut vm, src).unwrap();
+    }
+
+    #[test]
+    fn const_declarations() {
+        let src = r#"
+const greeting = "hi";
+fun shout() {
+    const suffix = "!";
+    fun inner() { return greeting + suffix; }
+    return inner();
+}
+print greeting;
+print shout();
+{
+    const greeting = "shadowed";
+    print greeting;
+}"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "hi\nhi!\nshadowed\n");
+
+        for (src, expected) in [
+            (
+                "{ const a = 1;\n  a = 2; }",
+                "[line 2:3] Error at 'a': Cannot assign to const 'a'.",
+            ),
+            (
+                "const a = 1; a += 1;",
+                "[line 1:14] Error at 'a': Cannot assign to const 'a'.",
+            ),
+            (
+                "fun f() { const a = 1; fun g() { a = 2; } }",
+                "[line 1:34] Error at 'a': Cannot assign to const 'a'.",
+            ),
+            (
+                "{ const a = 1; var a = 2; }",
+                "[line 1:20] Error at 'a': Already a variable with this name in this scope.",
+            ),
+            (
+                "const a = 1; const a = 2;",
+                "[line 1:20] Error at 'a': Cannot redeclare const 'a'.",
+            ),
+            (
+                "const a = 1; var a = 2;",
+                "[line 1:18] Error at 'a': Cannot redeclare const 'a'.",
+            ),
+            (
+                "const a;",
+                "[line 1:8] Error at ';': Expect '=' after constant name.",
+            ),
+            (
+                "var y = 0; fun g() { y = 5; } const y = 1;",
+                "[line 1:37] Error at 'y': Cannot redeclare 'y' as const.",
+            ),
+            (
+                "fun h() {} const h = 1;",
+                "[line 1:18] Error at 'h': Cannot redeclare 'h' as const.",
+            ),
+        ] {
+            let mut mem = Mem::new();
+            let mut parser = Parser::new(src, &mut mem);
+            assert!(!parser.compile(), "{src}");
+            assert_eq!(parser.errors(), [expected], "{src}");
+        }
+
+        // a const global stays const in later scripts on the same VM
+        assert_eq!(
+            interpret(&mut vm, "greeting = \"bye\";"),
+            Err(InterpretError::CompileError)
+        );
+        // unless the script declaring it failed to compile
+        assert_eq!(
+            interpret(&mut vm, "const late = 1; oops"),
+            Err(InterpretError::CompileError)
+        );
+        interpret(&mut vm, "var late = 1; late = 2;").unwrap();
+        // nor can an earlier script's var become const
+        assert_eq!(
+            interpret(&mut vm, "const late = 3;"),
+            Err(InterpretError::CompileError)
+        );
+
+        // assignments compiled before the `const` are caught when they run
+        let mut vm = VM::new();
+        let err = interpret(&mut vm, "fun f() { x = 2; } const x = 1; f();")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("Runtime error: Cannot assign to const 'x'."),
+            "{err}"
+        );
+        assert_eq!(eval(&mut vm, "x"), Ok(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn nan_and_infinity() {
+        let src = r#"
+print Infinity;
+print -Infinity;
+print NaN;
+print 1 / 0;
+print -1 / 0;
+print 0 / 0;
+print NaN == NaN;
+print NaN != NaN;
+var n = NaN;
+print n == n;
+print Infinity == 1 / 0;
+print Infinity > 1000000;
+print str(-Infinity);
+print typeof(NaN);"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "Infinity\n-Infinity\nNaN\nInfinity\n-Infinity\nNaN\nfalse\ntrue\nfalse\ntrue\ntrue\n-Infinity\nnumber\n"
+        );
+
+        // they're built-in constants
+        for src in ["NaN = 1;", "var Infinity = 2;"] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src),
+                Err(InterpretError::CompileError),
+                "{src}"
+            );
+        }
+    }
+
+    #[test]
+    fn str_and_num_natives() {
+        let src = r#"
+print str(42) + "!";
+print str(true);
+print str(nil) + str(1.5) + str("s");
+print str([1, "a"]);
+print typeof(str(42));
+print num("3.14");
+print num(" -0x10 ");
+print num("1_000") + 1;
+print num("abc");
+print num("1e5");
+print num("");
+print num("1 // comment");
+print num(str(7)) == 7;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(
+            output.contents(),
+            "42!\ntrue\nnil1.5s\n[1, a]\nstring\n3.14\n-16\n1001\nnil\nnil\nnil\nnil\ntrue\n"
+        );
+
+        let mut vm = VM::new();
+        assert_eq!(
+            interpret(&mut vm, "num(1);").unwrap_err().to_string(),
+            "Runtime error: Expected a string but got number.\n[line 1] in script"
+        );
+
+        // Natives are defined once per VM, a script's own `str` stays put
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, "var str = \"mine\";").unwrap();
+        interpret(&mut vm, "print str;").unwrap();
+        assert_eq!(output.contents(), "mine\n");
+    }
+
+    #[test]
+    fn field_natives() {
+        let src = r#"
+class Point {}
+var p = Point();
+var axis = "x";
+setField(p, axis, 1);
+print p.x;
+print setField(p, "y" + "", 2) + getField(p, "y");
+p.z = 3;
+print getField(p, "z");
+print getField(p, "w");
+for (var i = 0; i < 3; i = i + 1) setField(p, "f" + typeof(i), i);
+print p.fnumber;"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "1\n4\n3\nnil\n2\n");
+
+        for (src, expected) in [
+            (
+                "getField(1, \"x\");",
+                "Expected an instance but got number.",
+            ),
+            (
+                "setField(nil, \"x\", 1);",
+                "Expected an instance but got nil.",
+            ),
+            (
+                "class A {} getField(A(), 1);",
+                "Expected a string field name but got number.",
+            ),
+            (
+                "class A {} getField(A());",
+                "Expected 2 arguments but got 1.",
+            ),
+        ] {
+            let mut vm = VM::new();
+            assert_eq!(
+                interpret(&mut vm, src).unwrap_err().to_string(),
+                format!("Runtime error: {expected}\n[line 1] in script"),
+                "{src}"
+            );
+        }
+
+        // A global named like a native keeps shadowing it in later runs
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, "fun getField(instance, name) { return name; }").unwrap();
+        interpret(&mut vm, "class A {} print getField(A(), \"x\");").unwrap();
+        assert_eq!(output.contents(), "x\n");
+    }
+
+    #[test]
+    fn read_line_native() {
+        let src = r#"
+var first = readLine();
+print first;
+print len(first);
+print readLine();
+print readLine();"#;
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        vm.input = Some(Box::new(std::io::Cursor::new("hello there\r\nlast")));
+        interpret(&mut vm, src).unwrap();
+        assert_eq!(output.contents(), "hello there\n11\nlast\nnil\n");
+    }
+
+    #[test]
+    fn time_and_random_natives() {
+        let src = r#"
+var rolls = "";
+for (var i = 0; i < 5; i += 1) {
+    var r = random();
+    if (r < 0 or r >= 1) print "out of range";
+    var n = randomInt(6);
+    if (n < 0 or n >= 6 or n % 1 != 0) print "bad roll";
+    rolls = rolls + str(r) + " " + str(n) + ", ";
+}
+var before = clock();
+sleep(5);
+print clock() - before >= 0.005;
+print before >= 0;"#;
+        let random_output = |seed| {
+            let output = SharedBuf::default();
+            let mut vm = VM::with_output(Box::new(output.clone()));
+            vm.seed_rng(seed);
+            interpret(&mut vm, src).unwrap();
+            (vm, output.contents())
+        };
+        let (mut vm, first) = random_output(42);
+        assert_eq!(first, "true\ntrue\n");
+        let rolls = |vm: &mut VM| eval(vm, "rolls").unwrap().to_string();
+        let first_rolls = rolls(&mut vm);
+        let (mut vm, _) = random_output(42);
+        assert_eq!(rolls(&mut vm), first_rolls);
+        let (mut vm, _) = random_output(7);
+        assert_ne!(rolls(&mut vm), first_rolls);
+        // like any native, a script can take over the name for good
+        interpret(&mut vm, "var random = 4;").unwrap();
+        assert_eq!(eval(&mut vm, "random"), Ok(Value::Number(4.0)));
+
+        for (src, expected) in [
+            ("random(1);", "Expected 0 arguments but got 1."),
+            (
+                "randomInt(0);",
+                "Expected a positive whole number but got 0.",
+            ),
+            (
+                "randomInt(2.5);",
+                "Expected a positive whole number but got 2.5.",
+            ),
+            (
+                "sleep(-1);",
+                "Expected a non-negative number of milliseconds but got -1.",
+            ),
+            (
+                "sleep(nil);",
+                "Expected a non-negative number of milliseconds but got nil.",
+            ),
+            (
+                "var ms = 1000000; sleep(ms * ms * ms * ms * ms);",
+                "Can't sleep for 1000000000000000000000000000000 milliseconds, it's too long.",
+            ),
+            (
+                "sleep(Infinity);",
+                "Can't sleep for inf milliseconds, it's too long.",
+            ),
+        ] {
+            let mut vm = VM::new();
+            let err = interpret(&mut vm, src).unwrap_err().to_string();
+            assert!(
+                err.starts_with(&format!("Runtime error: {expected}")),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn trace_execution() {
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        vm.trace = true;
+        interpret(&mut vm, "print 1 + 2;").unwrap();
+        let trace = output.contents();
+        assert!(trace.starts_with("          [ Closure"), "{trace}");
+        assert!(
+            trace.contains("0000    1 OP_CONSTANT      Number(3.0)\n"),
+            "{trace}"
+        );
+        assert!(
+            trace.contains("][ Number(3.0) ]\n0002    | OP_PRINT\n3\n"),
+            "{trace}"
+        );
+
+        let output = SharedBuf::default();
+        let mut vm = VM::with_output(Box::new(output.clone()));
+        interpret(&mut vm, "print 1 + 2;").unwrap();
+        assert_eq!(output.contents(), "3\n");
+    }
+
+    #[test]
+    fn modulo() {
+        // `%` truncates like C's fmod so the result takes the dividend's sign,
+        // `-7` is `7` negated at runtime so it isn't folded
+        let listing = dump_bytecode("print 7 % 3; print -7 % 3;").unwrap();
+        assert_eq!(
+            listing,
+            r#"== script ==
+0000    1 OP_CONSTANT      Number(1.0)
+0002    | OP_PRINT
+0003    | OP_CONSTANT      Number(7.0)
+0005    | OP_NEGATE
+0006    | OP_CONSTANT      Number(3.0)
+0008    | OP_MODULO
+0009    | OP_PRINT
+0010    | OP_NIL
+0011    | OP_RETURN
+"#
+        );
+
+        let mut vm = VM::new();
+        assert_eq!(eval(&mut vm, "7 % 3"), Ok(Value::Number(1.0)));
+        assert_eq!(eval(&mut vm, "-7 % 3"), Ok(Value::Number(-1.0)));
+        assert_eq!(eval(&mut vm, "7 % -3"), Ok(Value::Number(1.0)));
+        assert_eq!(eval(&mut vm, "5.5 % 2"), Ok(Value::Number(1.5)));
+        assert_eq!(eval(&mut vm, "1 + 7 % 4 * 2"), Ok(Value::Number(7.0)));
+        match eval(&mut vm, "var zero = 0; 7 % zero") {
+            Ok(Value::Number(num)) => assert!(num.is_nan()),
+            other => panic!("Expected NaN, got {other:?}"),
+        }
+        match eval(&mut vm, "7 % 0") {
+            Ok(Value::Number(num)) => assert!(num.is_nan()),
+            other => panic!("Expected NaN, got {other:?}"),
+        }
+        assert_eq!(
+            eval(&mut vm, r#"7 % "3""#).unwrap_err().to_string(),
+            "Runtime error: Operands must be numbers.\n[line 1] in script"
+        );
+    }
 }
@@ -0,0 +1,107 @@
+mod common;
+
+use std::io::Cursor;
+
+use common::SharedBuf;
+use loxide::{eval, interpret, run_repl, InterpretError, InterpretResult, TraceFrame, Value, VM};
+
+#[test]
+fn eval_from_another_crate() {
+    let mut vm = VM::new();
+    assert_eq!(
+        eval(&mut vm, "fun add(a, b) { return a + b; } add(1, 2)"),
+        Ok(Value::Number(3.0))
+    );
+    assert_eq!(
+        interpret(&mut vm, "print nope;"),
+        Err(InterpretError::RuntimeError {
+            line: 1,
+            message: "Undefined variable: nope".to_string(),
+            frames: vec![TraceFrame {
+                function: None,
+                line: 1,
+                file: None
+            }],
+        })
+    );
+}
+
+#[test]
+fn native_from_another_crate() {
+    fn answer(_vm: &mut VM, _values: &[Value]) -> InterpretResult<Value> {
+        Ok(Value::Number(42.0))
+    }
+
+    let mut vm = VM::new();
+    vm.define_native("answer", answer);
+    assert_eq!(eval(&mut vm, "answer() * 2"), Ok(Value::Number(84.0)));
+}
+
+#[test]
+fn native_reports_runtime_error() {
+    fn positive(vm: &mut VM, values: &[Value]) -> InterpretResult<Value> {
+        match values {
+            [Value::Number(n)] if *n > 0.0 => Ok(Value::Number(*n)),
+            _ => Err(vm.runtime_error("Expected a positive number.".into())),
+        }
+    }
+
+    let mut vm = VM::new();
+    vm.define_native("positive", positive);
+    assert_eq!(eval(&mut vm, "positive(2)"), Ok(Value::Number(2.0)));
+    assert_eq!(
+        interpret(&mut vm, "fun f() {\n  positive(-1);\n}\nf();"),
+        Err(InterpretError::RuntimeError {
+            line: 2,
+            message: "Expected a positive number.".to_string(),
+            frames: vec![
+                TraceFrame {
+                    function: Some("f".to_string()),
+                    line: 2,
+                    file: None
+                },
+                TraceFrame {
+                    function: None,
+                    line: 4,
+                    file: None
+                },
+            ],
+        })
+    );
+    // the error reset the stack, so the VM still works
+    assert_eq!(eval(&mut vm, "positive(3)"), Ok(Value::Number(3.0)));
+}
+
+#[test]
+fn repl_echoes_expressions_but_not_statements() {
+    // each line and what it should echo, if anything
+    let lines = [
+        ("var x = 1;", None),
+        ("x = 5", Some("5")),
+        ("x += 2", Some("7")),
+        ("x;", None),
+        ("x = 10;", None),
+        ("class Point {}", None),
+        ("var p = Point();", None),
+        ("p.y = x * 2", Some("20")),
+        ("var list = [1, 2];", None),
+        ("list[0] = \"first\"", Some("first")),
+        ("list", Some("[first, 2]")),
+        ("var a = 3; a * a", Some("9")),
+        ("{ x = 11; }", None),
+        ("if (x > 0) x = 12;", None),
+        ("fun f() { return x; }", None),
+        ("f()", Some("12")),
+    ];
+
+    let output = SharedBuf::default();
+    let mut vm = VM::with_output(Box::new(output.clone()));
+    let input: String = lines.iter().map(|(line, _)| format!("{line}\n")).collect();
+    run_repl(&mut vm, Cursor::new(input));
+
+    let expected: String = lines
+        .iter()
+        .filter_map(|(_, echo)| echo.map(|echo| format!("{echo}\n")))
+        .collect();
+    assert_eq!(output.contents(), expected);
+}
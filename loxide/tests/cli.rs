@@ -0,0 +1,124 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+fn script(name: &str, src: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("loxide-{}-{name}.lox", std::process::id()));
+    std::fs::write(&path, src).unwrap();
+    path
+}
+
+#[test]
+fn time_flag_reports_durations() {
+    let path = script("time", "var a = 1; print a + 2;");
+    let output = Command::new(env!("CARGO_BIN_EXE_loxide"))
+        .arg("--time")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Compile time: "), "{stderr}");
+    assert!(stderr.contains("Run time: "), "{stderr}");
+}
+
+#[test]
+fn time_flag_reports_durations_on_error() {
+    let path = script("time-error", "print 1; print -nil;");
+    let output = Command::new(env!("CARGO_BIN_EXE_loxide"))
+        .arg("--time")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Operand must be a number."), "{stderr}");
+    assert!(stderr.contains("Compile time: "), "{stderr}");
+    assert!(stderr.contains("Run time: "), "{stderr}");
+}
+
+#[test]
+fn repl_read_line_reads_from_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_loxide"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"var name = readLine();\nworld\nprint \"hello \" + name;\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "hello world\n");
+}
+
+#[test]
+fn check_flag_compiles_without_running() {
+    let path = script("check-clean", "print \"ran\" + \"!\";");
+    let output = Command::new(env!("CARGO_BIN_EXE_loxide"))
+        .arg("--check")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn check_flag_reports_every_compile_error() {
+    let path = script("check-broken", "print \"ran\";\nvar = 1;\nprint 1 +;");
+    let output = Command::new(env!("CARGO_BIN_EXE_loxide"))
+        .arg("--check")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 2:5] Error at '=': Expect variable name.\n    var = 1;\n        ^\n\
+         [line 3:10] Error at ';': Expect expression\n    print 1 +;\n             ^\n"
+    );
+}
+
+#[test]
+fn compile_errors_underline_the_token() {
+    let src = "fun f(a, b) {\n\treturn a b;\n}\nprint f(1, 2) \"multi\nline\";\nprint 1 +";
+    let path = script("underline", src);
+    let output = Command::new(env!("CARGO_BIN_EXE_loxide"))
+        .arg("--check")
+        .arg(&path)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "[line 2:11] Error at 'b': Expect ';' after return value.
+    \treturn a b;
+    \t         ^
+[line 4:15] Error at '\"multi\nline\"': Expect ';' after value.
+    print f(1, 2) \"multi
+                  ^~~~~~
+[line 6:10] Error at end: Expect expression
+"
+    );
+}
@@ -0,0 +1,24 @@
+//! Helpers shared by the integration tests, and by the unit tests through a
+//! `#[path]` module in `lib.rs`
+
+use std::{cell::RefCell, io::Write, rc::Rc};
+
+/// Output sink that can still be read after handing it to a VM
+#[derive(Clone, Default)]
+pub struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuf {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.borrow().clone()).unwrap()
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+//! Runs every `tests/scripts/*.lox` and compares what it printed with the
+//! `.expected` file next to it. A script that fails to compile expects its
+//! compile errors, one per line, and one that hits a runtime error expects
+//! whatever it printed followed by the error report.
+
+mod common;
+
+use std::path::Path;
+
+use common::SharedBuf;
+use loxide::{compile, VM};
+
+/// Compiles the script once, into the VM's own heap, so its errors and its
+/// run both come from the same compilation
+fn run(src: &str) -> String {
+    let output = SharedBuf::default();
+    let mut vm = VM::with_output(Box::new(output.clone()));
+    let function = match compile(src, &mut vm.mem) {
+        Ok(function) => function,
+        Err(err) => return err.errors.join("\n") + "\n",
+    };
+    vm.init(function);
+
+    let result = vm.run();
+    let mut printed = output.contents();
+    if let Err(err) = result {
+        printed += &format!("{err}\n");
+    }
+    printed
+}
+
+#[test]
+fn scripts_match_expected_output() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut scripts: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "lox"))
+        .collect();
+    scripts.sort();
+    assert!(!scripts.is_empty(), "no scripts in {}", dir.display());
+
+    let mut failures = vec![];
+    for script in &scripts {
+        let src = std::fs::read_to_string(script).unwrap();
+        let expected_path = script.with_extension("expected");
+        let expected = std::fs::read_to_string(&expected_path)
+            .unwrap_or_else(|err| panic!("{}: {err}", expected_path.display()));
+
+        let actual = run(&src);
+        if actual != expected {
+            failures.push(format!(
+                "{}\n--- expected\n{expected}--- actual\n{actual}",
+                script.display()
+            ));
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} scripts failed:\n\n{}",
+        failures.len(),
+        scripts.len(),
+        failures.join("\n")
+    );
+}